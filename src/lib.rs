@@ -1,657 +1,8841 @@
 use std::{
-    collections::{HashMap, HashSet},
-    fs::{self},
-    path::{Path, PathBuf},
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io::{BufWriter, Read, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
     time::{Duration, Instant},
 };
 
-use cssparser::{Parser, ParserInput, SourcePosition, Token};
-use glob::glob;
-use grimoire_css_lib::{GrimoireCssError, Spell};
-use regex::Regex;
-use serde::Serialize;
-use serde_json::to_string_pretty;
+use cssparser::{
+    serialize_identifier, serialize_string, BasicParseErrorKind, ParseError, Parser, ParserInput,
+    SourcePosition, Token,
+};
+use flate2::read::GzDecoder;
+use glob::{glob, Pattern};
+use grimoire_css_lib::{
+    start_in_memory, ConfigInMemory, ConfigInMemoryEntry, GrimoireCssError, Spell,
+};
+use rayon::prelude::*;
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use serde_json::{to_string, to_string_pretty};
 
-#[derive(Debug, Serialize)]
-struct Transmuted {
+/// Version of the output schema, bumped whenever the shape of the serialized
+/// [`Transmuted`] changes.
+pub const SCHEMA_VERSION: u32 = 16;
+
+/// The message of the [`GrimoireCssError::InvalidPath`] returned when the
+/// path patterns match no files.
+pub const NO_FILES_MATCHED: &str = "No files found matching the provided patterns.";
+
+/// Name and version of the tool, recorded as the `generator` of the output.
+pub const GENERATOR: &str = concat!("grimoire_css_transmutator ", env!("CARGO_PKG_VERSION"));
+
+/// The result of a transmutation: every scroll produced from the input CSS.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transmuted {
+    /// The [`SCHEMA_VERSION`] the output was written with.
+    pub version: u32,
+    /// The tool that produced the output, see [`GENERATOR`].
+    pub generator: String,
     pub scrolls: Vec<TransmutedClass>,
+    /// Scrolls scoped to media types split out of `scrolls`, keyed by media type.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub split_media: BTreeMap<String, Vec<TransmutedClass>>,
+    /// Spells of each scroll grouped by breakpoint alias (`base` for unscoped
+    /// spells), with the area prefix removed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub responsive: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    /// Scrolls nested under their area (`base` for unscoped spells), with the
+    /// area prefix removed from their spells. Filled instead of `scrolls` by
+    /// [`Transmuted::group_by_area`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub areas: BTreeMap<String, Vec<TransmutedClass>>,
+    /// CSS custom properties (`--name: value`) declared in the input, which
+    /// are collected here instead of becoming spells.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom_properties: BTreeMap<String, String>,
+    /// `@keyframes` rules (including vendor-prefixed ones) by animation name,
+    /// each mapping its keyframe selectors (`from`, `50%`, `to`) to the spell
+    /// components declared for them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub keyframes: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    /// Selectorless at-rules such as `@font-face` and `@page`, in source order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub at_rules: Vec<TransmutedAtRule>,
+    /// Classes that already are Grimoire spells and were left out of `scrolls`,
+    /// e.g. to exclude them from a second pass.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped_existing_spells: Vec<String>,
+    /// Input files that were left out and why: files that failed to parse,
+    /// with [`ParseOptions::continue_on_error`], and files that are no
+    /// stylesheet, with [`NonCssPolicy::Skip`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_files: Vec<(PathBuf, String)>,
+    /// Scrolls whose name is not a valid Grimoire CSS key, renamed or skipped
+    /// following [`ParseOptions::invalid_names`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<NameWarning>,
+    /// How often each kind of token the parser does not understand was
+    /// ignored, e.g. `@supports` or `delim '&'`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub diagnostics: BTreeMap<String, usize>,
+    /// The separator between a spell's area and the rest of the spell, only
+    /// written when it is not [`DEFAULT_AREA_SEPARATOR`].
+    #[serde(
+        default = "default_area_separator",
+        skip_serializing_if = "is_default_area_separator"
+    )]
+    pub area_separator: String,
+    /// How the focuses of the spells are written, only written when it is not
+    /// the default Grimoire CSS syntax.
+    #[serde(default, skip_serializing_if = "FocusEncoder::is_default")]
+    pub focus_encoder: FocusEncoder,
+    /// Whether the oneliners are grouped by category, see
+    /// [`ParseOptions::pretty_spells`]; only written when they are.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pretty_spells: bool,
+    /// Human-readable notices collected while parsing (e.g. skipped classes).
+    #[serde(skip)]
+    pub notices: Vec<String>,
+    /// Where each spell was declared, keyed by scroll name and then spell.
+    #[serde(skip)]
+    pub source_map: BTreeMap<String, BTreeMap<String, SourceSpan>>,
+    /// Counts describing the run.
+    #[serde(skip)]
+    pub stats: TransmutationStats,
+    /// How many transmuted declarations each CSS property had, across all
+    /// rules and files.
+    #[serde(skip)]
+    pub property_histogram: BTreeMap<String, usize>,
+    /// How long reading and parsing took; `serialize` and `total` are only
+    /// filled by the functions that also serialize.
+    #[serde(skip)]
+    pub timings: Timings,
 }
 
-#[derive(Debug, Serialize)]
-struct TransmutedClass {
+/// How long the phases of a transmutation took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Expanding the path patterns and reading the files (zero for content).
+    pub read: Duration,
+    /// Parsing the CSS and building the result.
+    pub parse: Duration,
+    /// Serializing the result.
+    pub serialize: Duration,
+    /// The whole run, including anything between the phases.
+    pub total: Duration,
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "read {:.2?}, parse {:.2?}, serialize {:.2?}, total {:.2?}",
+            self.read, self.parse, self.serialize, self.total
+        )
+    }
+}
+
+/// Counts describing a transmutation run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransmutationStats {
+    /// Style rules (selector blocks) parsed.
+    pub rules: usize,
+    /// Classes selected by those rules, counting every item of a selector list.
+    pub selectors: usize,
+    /// Classes skipped because they already are Grimoire spells.
+    pub skipped_spells: usize,
+    /// Unique scrolls in the result.
+    pub scrolls: usize,
+    /// Spells generated across all rules, before deduplication.
+    pub spells_generated: usize,
+    /// Unique spells across all scrolls.
+    pub spells: usize,
+    /// Input files whose results were taken from a [`ParseCache`].
+    pub cached_files: usize,
+}
+
+impl TransmutationStats {
+    /// Average number of spells per scroll.
+    pub fn average_spells_per_scroll(&self) -> f64 {
+        if self.scrolls == 0 {
+            0.0
+        } else {
+            self.spells as f64 / self.scrolls as f64
+        }
+    }
+
+    /// Share of generated spells removed as duplicates, between 0 and 1.
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.spells_generated == 0 {
+            0.0
+        } else {
+            1.0 - self.spells as f64 / self.spells_generated as f64
+        }
+    }
+
+    /// Adds the counts collected by a nested parse.
+    fn absorb(&mut self, other: &TransmutationStats) {
+        self.rules += other.rules;
+        self.selectors += other.selectors;
+        self.skipped_spells += other.skipped_spells;
+        self.spells_generated += other.spells_generated;
+        self.cached_files += other.cached_files;
+    }
+}
+
+impl fmt::Display for TransmutationStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rules, {} selectors, {} skipped as spells, {} scrolls, {} spells \
+             ({:.2} per scroll, {:.0}% deduplicated)",
+            self.rules,
+            self.selectors,
+            self.skipped_spells,
+            self.scrolls,
+            self.spells,
+            self.average_spells_per_scroll(),
+            self.dedup_ratio() * 100.0
+        )?;
+
+        if self.cached_files > 0 {
+            write!(f, ", {} file(s) from cache", self.cached_files)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The byte range of the declaration that produced a spell.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SourceSpan {
+    /// The input file, or `None` for content passed directly.
+    pub file: Option<String>,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl From<&Range<usize>> for SourceSpan {
+    fn from(span: &Range<usize>) -> Self {
+        Self {
+            file: None,
+            start: span.start,
+            end: span.end,
+        }
+    }
+}
+
+/// A single CSS class transmuted into Grimoire CSS spells.
+///
+/// A collapsed scroll (see [`Transmuted::collapse_single`]) serializes its only
+/// spell as a scalar `spell` field instead of the `spells` array.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(from = "SerializedClass")]
+pub struct TransmutedClass {
     pub name: String,
     pub spells: Vec<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub oneliner: Option<String>,
+    pub collapsed: bool,
+    /// The CSS selector(s) this scroll was produced from, when requested.
+    pub source_selector: Option<String>,
+    /// The cascade layer (`@layer`) the scroll was declared in, dot-separated
+    /// when nested (e.g. `base.reset`).
+    pub layer: Option<String>,
+    /// Where each spell came from, when [`ParseOptions::spell_sources`] is set.
+    pub spell_sources: Vec<SpellSource>,
+    /// The other scrolls this one shared a selector list with (`.a, .b`),
+    /// sorted, when [`ParseOptions::shared_with`] is set.
+    pub shared_with: Vec<String>,
+    /// How many rules produced exactly the same spells for this scroll, for
+    /// its most repeated set of spells; 1 when no rule was repeated.
+    pub duplicate_count: usize,
 }
 
-type TransmutedMap = HashMap<String, HashSet<String>>;
+/// The declaration and selector a spell was produced from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpellSource {
+    pub spell: String,
+    /// The `component=target` pair built from the declaration.
+    pub component: String,
+    /// The selector of the rule, when the rule had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+}
 
-/// Represents the state during CSS parsing.
-#[derive(Debug, Default)]
-struct ParserState {
-    pub raw_classes_spells_map: HashMap<String, Vec<String>>,
-    pub current_class: String,
-    pub started_media_pos: Option<SourcePosition>,
-    pub focus: Vec<String>,
-    pub component_and_component_target_map: HashSet<String>,
-    pub effects: Vec<String>,
-    pub class_started: bool,
-    pub focus_delim: String,
-    pub effect_started: bool,
-    pub colons: Vec<String>,
-    pub area: Option<String>,
+impl Serialize for TransmutedClass {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("TransmutedClass", 3)?;
+        state.serialize_field("name", &self.name)?;
+
+        match self.spells.as_slice() {
+            [spell] if self.collapsed => state.serialize_field("spell", spell)?,
+            spells => state.serialize_field("spells", spells)?,
+        }
+
+        if let Some(oneliner) = &self.oneliner {
+            state.serialize_field("oneliner", oneliner)?;
+        }
+
+        if let Some(source_selector) = &self.source_selector {
+            state.serialize_field("source_selector", source_selector)?;
+        }
+
+        if let Some(layer) = &self.layer {
+            state.serialize_field("layer", layer)?;
+        }
+
+        if !self.spell_sources.is_empty() {
+            state.serialize_field("spell_sources", &self.spell_sources)?;
+        }
+
+        if !self.shared_with.is_empty() {
+            state.serialize_field("shared_with", &self.shared_with)?;
+        }
+
+        if self.duplicate_count > 1 {
+            state.serialize_field("duplicate_count", &self.duplicate_count)?;
+        }
+
+        state.end()
+    }
 }
 
-/// Reads and cleans multiple CSS files (paths mode).
-fn read_and_clean_files(paths: &[PathBuf]) -> Result<String, GrimoireCssError> {
-    let comment_regex = Regex::new(r"(?s)/\*.*?\*/").unwrap();
+/// The serialized shape of a [`TransmutedClass`], either collapsed or not.
+#[derive(Deserialize)]
+struct SerializedClass {
+    name: String,
+    #[serde(default)]
+    spells: Vec<String>,
+    spell: Option<String>,
+    oneliner: Option<String>,
+    source_selector: Option<String>,
+    layer: Option<String>,
+    #[serde(default)]
+    spell_sources: Vec<SpellSource>,
+    #[serde(default)]
+    shared_with: Vec<String>,
+    duplicate_count: Option<usize>,
+}
 
-    let total_size: usize = paths
-        .iter()
-        .filter_map(|path| fs::metadata(path).ok())
-        .map(|metadata| metadata.len() as usize)
-        .sum();
+impl From<SerializedClass> for TransmutedClass {
+    fn from(class: SerializedClass) -> Self {
+        let collapsed = class.spell.is_some();
+        let mut spells = class.spells;
+        spells.extend(class.spell);
+
+        Self {
+            name: class.name,
+            spells,
+            oneliner: class.oneliner,
+            collapsed,
+            source_selector: class.source_selector,
+            layer: class.layer,
+            spell_sources: class.spell_sources,
+            shared_with: class.shared_with,
+            duplicate_count: class.duplicate_count.unwrap_or(1),
+        }
+    }
+}
+
+/// The parts of a Grimoire CSS configuration that decide which class names
+/// already are spells: its scrolls and its shared spells.
+///
+/// Both serialize sorted, so the [`ParseOptions`] a parse cache is keyed by
+/// serialize the same every run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct GrimoireConfig {
+    #[serde(serialize_with = "serialize_sorted_scrolls")]
+    pub scrolls: Option<HashMap<String, Vec<String>>>,
+    #[serde(serialize_with = "serialize_sorted_set")]
+    pub shared_spells: HashSet<String>,
+}
 
-    // Allocate with the estimated capacity
-    let mut all_contents = String::with_capacity(total_size);
+fn serialize_sorted_scrolls<S: Serializer>(
+    scrolls: &Option<HashMap<String, Vec<String>>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    scrolls
+        .as_ref()
+        .map(|scrolls| scrolls.iter().collect::<BTreeMap<_, _>>())
+        .serialize(serializer)
+}
 
-    for path in paths {
-        let content = fs::read_to_string(path).map_err(|e| {
-            GrimoireCssError::Io(std::io::Error::new(
-                e.kind(),
-                format!("Failed to read '{}': {}", path.display(), e),
-            ))
-        })?;
+fn serialize_sorted_set<S: Serializer>(
+    set: &HashSet<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    set.iter().collect::<BTreeSet<_>>().serialize(serializer)
+}
 
-        // Process and append in one go to minimize intermediate allocations
-        all_contents.push_str(&comment_regex.replace_all(&content, "").replace('"', "'"));
+/// The subset of `grimoire.config.json` read by [`GrimoireConfig`].
+#[derive(Deserialize)]
+struct RawGrimoireConfig {
+    #[serde(default)]
+    scrolls: Vec<RawConfigScroll>,
+    #[serde(default)]
+    shared: Vec<RawConfigShared>,
+}
+
+#[derive(Deserialize)]
+struct RawConfigScroll {
+    name: String,
+    #[serde(default)]
+    spells: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RawConfigShared {
+    #[serde(default)]
+    styles: Vec<String>,
+}
+
+impl GrimoireConfig {
+    /// Reads a Grimoire CSS config file (e.g. `grimoire/config/grimoire.config.json`).
+    pub fn load(path: &Path) -> Result<Self, GrimoireCssError> {
+        let content = fs::read_to_string(path).map_err(GrimoireCssError::Io)?;
+        Self::from_json(&content)
     }
 
-    // Release excess capacity if significant
-    if all_contents.capacity() > all_contents.len() * 2 {
-        all_contents.shrink_to_fit();
+    /// Parses the content of a Grimoire CSS config file. Shared styles that
+    /// point at CSS files are not spells and are left out.
+    pub fn from_json(content: &str) -> Result<Self, GrimoireCssError> {
+        let raw: RawGrimoireConfig =
+            serde_json::from_str(content).map_err(GrimoireCssError::Serde)?;
+
+        let scrolls = (!raw.scrolls.is_empty()).then(|| {
+            raw.scrolls
+                .into_iter()
+                .map(|scroll| (scroll.name, scroll.spells))
+                .collect()
+        });
+        let shared_spells = raw
+            .shared
+            .into_iter()
+            .flat_map(|shared| shared.styles)
+            .filter(|style| !style.ends_with(".css"))
+            .collect();
+
+        Ok(Self {
+            scrolls,
+            shared_spells,
+        })
     }
+}
+
+/// Reports progress while the input files are read: called once per file with
+/// the number of files read so far, the total number and the file just read.
+pub type Progress<'a> = &'a mut dyn FnMut(usize, usize, &Path);
 
-    Ok(all_contents)
+/// What a paths-mode run reports to and keeps state in, none by default.
+#[derive(Default)]
+pub struct RunHooks<'a> {
+    /// Called as each file is read.
+    pub progress: Option<Progress<'a>>,
+    /// Unchanged files are not parsed again, see [`ParseCache`].
+    pub cache: Option<&'a mut ParseCache>,
 }
 
-/// Removes the last character of a string.
-fn remove_last_char(s: &str) -> &str {
-    s.char_indices()
-        .next_back()
-        .map(|(i, _)| &s[..i])
-        .unwrap_or(s)
+/// Default for both [`RunLimits`] size limits: 64 MiB.
+pub const DEFAULT_MAX_INPUT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Where the CLI keeps its [`ParseCache`], relative to the working directory.
+pub const DEFAULT_CACHE_PATH: &str = "grimoire/.gcsst-cache.json";
+
+/// The parse results of individual files from earlier runs, keyed by path,
+/// so paths-mode runs only parse the files whose content changed.
+///
+/// A cache written by another version of the tool or with other
+/// [`ParseOptions`] is discarded on first use.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ParseCache {
+    generator: String,
+    options: String,
+    files: HashMap<String, CachedFile>,
 }
 
-/// Generates a map of spells based on parser state.
-fn generate_spells_map(state: &ParserState) -> TransmutedMap {
-    let mut spells_map = HashMap::new();
+/// A file's content hash with what parsing it produced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedFile {
+    hash: u64,
+    result: FileResult,
+}
 
-    for (class, prefixes) in &state.raw_classes_spells_map {
-        let mut spells = HashSet::new();
+impl ParseCache {
+    /// Reads a cache written by [`ParseCache::save`], starting empty when the
+    /// file is missing or unreadable.
+    pub fn load(path: &Path) -> Self {
+        fs::read(path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
 
-        for prefix in prefixes {
-            for component in &state.component_and_component_target_map {
-                let spell = if prefix.is_empty() {
-                    component.clone()
-                } else {
-                    format!("{prefix}{component}")
-                };
-                spells.insert(spell);
-            }
+    /// Writes the cache to `path` with [`write_atomically`].
+    pub fn save(&self, path: &Path) -> Result<(), GrimoireCssError> {
+        write_atomically(path, |file| {
+            serde_json::to_writer(file, self).map_err(GrimoireCssError::Serde)
+        })
+    }
+
+    /// Drops every entry if the cache was written by another version or
+    /// with other options than `options`.
+    fn validate(&mut self, options: &ParseOptions) {
+        let fingerprint = serde_json::to_string(options).unwrap_or_default();
+        if self.generator != GENERATOR || self.options != fingerprint {
+            *self = Self {
+                generator: GENERATOR.to_string(),
+                options: fingerprint,
+                files: HashMap::new(),
+            };
         }
-        spells_map.insert(class.clone(), spells);
     }
+}
 
-    spells_map
+/// Creates the file at `path` and its directories and lets `write` fill it.
+/// The content goes to a temporary file next to it that is renamed over the
+/// target once complete, so readers never see a half-written file and a
+/// failed write leaves an existing one untouched.
+pub fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), GrimoireCssError>,
+) -> Result<(), GrimoireCssError> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(GrimoireCssError::Io)?;
+    }
+
+    let temp_path = temp_path(path);
+    let written = File::create(&temp_path)
+        .map_err(GrimoireCssError::Io)
+        .and_then(|file| {
+            let mut file = BufWriter::new(file);
+            write(&mut file)?;
+            file.into_inner()
+                .map_err(|e| GrimoireCssError::Io(e.into_error()))?
+                .sync_all()
+                .map_err(GrimoireCssError::Io)
+        })
+        .and_then(|()| fs::rename(&temp_path, path).map_err(GrimoireCssError::Io));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
 }
 
-/// Merges two HashMaps, concatenating values for duplicate keys.
-fn merge_maps(map1: &mut TransmutedMap, map2: TransmutedMap) {
-    for (key, value) in map2 {
-        if let Some(existing_value) = map1.get_mut(&key) {
-            existing_value.extend(value);
-        } else {
-            map1.insert(key, value);
+/// A hidden file next to `path` to write its new content to, numbered per
+/// call so threads writing the same file never share one.
+fn temp_path(path: &Path) -> PathBuf {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let call = CALLS.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{name}.{}.{call}.tmp", std::process::id()))
+}
+
+/// The 64-bit FNV-1a hash of a file's content, stable across builds unlike
+/// the standard library's hashers.
+fn content_hash(content: &str) -> u64 {
+    content.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Guards for a paths-mode run against mis-scoped globs and slow inputs, and
+/// where it looks for files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunLimits {
+    /// Abort the whole run (glob expansion, reading and parsing) with an error
+    /// once this budget is exceeded.
+    pub timeout: Option<Duration>,
+    /// Largest size in bytes of a single input file.
+    pub max_file_size: u64,
+    /// Largest size in bytes of all input files together.
+    pub max_total_size: u64,
+    /// What to do with matched files that are obviously no stylesheet.
+    pub non_css: NonCssPolicy,
+    /// Directory relative path patterns are resolved against, instead of the
+    /// working directory.
+    pub base_dir: Option<PathBuf>,
+    /// Also read the local stylesheets pulled in through `@import`,
+    /// recursively and relative to the importing file. Remote URLs are
+    /// skipped, every file is read once (which also guards against import
+    /// cycles) and media queries or layers on the import are not carried
+    /// over.
+    pub follow_imports: bool,
+}
+
+impl Default for RunLimits {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            max_file_size: DEFAULT_MAX_INPUT_SIZE,
+            max_total_size: DEFAULT_MAX_INPUT_SIZE,
+            non_css: NonCssPolicy::default(),
+            base_dir: None,
+            follow_imports: false,
         }
     }
 }
 
-/// Processes CSS input and generates raw spells.
-fn process_css_into_raw_spells(
-    css_input: &str,
-    parser_state: &mut ParserState,
-) -> Result<TransmutedMap, GrimoireCssError> {
-    let mut result: TransmutedMap = HashMap::new();
-    let mut parser_input = ParserInput::new(css_input);
-    let mut parser = Parser::new(&mut parser_input);
+/// What to do with a matched file that is obviously no stylesheet (or, when
+/// extracting from HTML, no HTML document): binary content, invalid UTF-8 or
+/// the extension of another web asset (see [`NON_CSS_EXTENSIONS`]).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonCssPolicy {
+    /// Fail the run, naming the file.
+    #[default]
+    Error,
+    /// Leave the file out, listing it in [`Transmuted::failed_files`].
+    Skip,
+}
 
-    while let Ok(token) = parser.next() {
-        match token {
-            Token::Ident(cow_rc_str) => {
-                if parser_state.class_started && parser_state.current_class.is_empty() {
-                    parser_state.current_class.push_str(cow_rc_str);
-                    parser_state.class_started = false;
-                } else if !parser_state.focus_delim.is_empty() {
-                    let prefix = if parser_state.focus.is_empty() {
-                        ""
-                    } else {
-                        "_"
-                    };
-                    parser_state.focus.push(format!(
-                        "{}{}_{}",
-                        prefix, &parser_state.focus_delim, &cow_rc_str
-                    ));
-                    parser_state.focus_delim.clear();
-                } else if parser_state.effect_started {
-                    if parser_state.colons.len() > 2 {
-                        parser_state.colons = vec![":".to_string(), ":".to_string()]
-                    }
-                    let focus_item = format!("{}{}", parser_state.colons.join(""), cow_rc_str);
-                    parser_state.focus.push(focus_item.clone());
-                    parser_state.effects.push(cow_rc_str.to_string());
-                    parser_state.effect_started = false;
-                    parser_state.colons.clear();
+impl FromStr for NonCssPolicy {
+    type Err = GrimoireCssError;
 
-                    if parser_state.current_class.is_empty() {
-                        parser_state.current_class.push_str(&focus_item);
-                    }
-                } else if !parser_state.current_class.is_empty() {
-                    parser_state.focus.push(format!("_{cow_rc_str}"));
-                } else {
-                    // This is a tag selector
-                    parser_state.current_class.push_str(cow_rc_str);
-                }
-            }
-            Token::AtKeyword(cow_rc_str) => {
-                if cow_rc_str.as_ref() == "media" {
-                    parser_state.started_media_pos = Some(parser.position());
-                }
-            }
-            Token::Delim(d) => match d.to_string().as_str() {
-                "." => {
-                    parser_state.class_started = true;
-                    if !parser_state.current_class.is_empty() && parser_state.focus_delim.is_empty()
-                    {
-                        let focus_str = parser_state.focus.join("").trim().replace(" ", "_");
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "skip" => Ok(Self::Skip),
+            _ => Err(GrimoireCssError::InvalidInput(format!(
+                "Unknown non-CSS file policy '{value}', expected error or skip"
+            ))),
+        }
+    }
+}
 
-                        let base_raw_spell = if focus_str.is_empty() {
-                            String::new()
-                        } else {
-                            format!("{{{focus_str}}}")
-                        };
+/// Extensions of files a broad glob picks up that are never stylesheets.
+pub const NON_CSS_EXTENSIONS: &[&str] = &[
+    "js", "mjs", "cjs", "ts", "tsx", "jsx", "json", "map", "html", "htm", "svg", "png", "jpg",
+    "jpeg", "gif", "webp", "avif", "ico", "woff", "woff2", "ttf", "otf", "eot", "wasm", "zip",
+];
 
-                        parser_state
-                            .raw_classes_spells_map
-                            .entry(parser_state.current_class.to_owned())
-                            .or_default()
-                            .push(base_raw_spell.clone());
+impl RunLimits {
+    /// Starts the clock for `timeout`, if any.
+    fn deadline(&self) -> Option<Deadline> {
+        self.timeout
+            .map(|budget| Deadline::new(Instant::now(), budget))
+    }
 
-                        parser_state.focus.clear();
-                        parser_state.effects.clear();
-                        parser_state.current_class.clear();
-                        parser_state.focus_delim.clear();
-                    }
-                }
-                ":" | "::" | ">" | "+" | "~" => parser_state.focus_delim = d.to_string(),
-                "*" => {
-                    if parser_state.focus.is_empty() {
-                        parser_state.focus.push(d.to_string());
+    /// `base_dir`, or the working directory.
+    fn base_dir(&self) -> Result<PathBuf, GrimoireCssError> {
+        match &self.base_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => working_dir(),
+        }
+    }
+}
 
-                        if parser_state.current_class.is_empty() {
-                            parser_state.current_class.push('*');
-                        }
-                    } else {
-                        parser_state.focus_delim = d.to_string();
-                    }
-                }
-                _ => {}
-            },
-            Token::Colon => {
-                parser_state.effect_started = true;
-                parser_state.colons.push(":".to_string());
-            }
-            Token::Comma => {
-                if !parser_state.focus.is_empty() {
-                    if !parser_state.focus_delim.is_empty() {
-                        parser_state.focus.push(parser_state.focus_delim.clone());
+/// The working directory, failing with a hint when it cannot be read (e.g.
+/// it was deleted or a sandbox hides it).
+fn working_dir() -> Result<PathBuf, GrimoireCssError> {
+    std::env::current_dir().map_err(|e| {
+        GrimoireCssError::Io(std::io::Error::new(
+            e.kind(),
+            format!("Cannot read the working directory ({e}); pass a base directory instead"),
+        ))
+    })
+}
 
-                        parser_state.focus_delim.clear();
-                    }
+/// A selectorless at-rule (see [`SELECTORLESS_AT_RULES`]) with the spell
+/// components of its declarations.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransmutedAtRule {
+    /// The at-rule name without `@`, lowercased, e.g. `font-face`.
+    pub name: String,
+    /// What follows the name, e.g. `:first` for `@page :first`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prelude: Option<String>,
+    pub spells: Vec<String>,
+}
 
-                    parser_state.focus.push(",".to_string());
-                } else {
-                    let focus_str = parser_state.focus.join("").trim().replace(" ", "_");
+/// A scroll whose name needed attention, see [`InvalidNamePolicy`] and
+/// [`ConflictPolicy`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NameWarning {
+    /// The scroll name as taken from the selector.
+    pub scroll: String,
+    /// The selector(s) the scroll came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selector: Option<String>,
+    /// What was done with the scroll.
+    pub message: String,
+}
+
+/// What to do with scrolls whose name has characters that are not valid in a
+/// Grimoire CSS key (e.g. `/` in `w-1/2`, `%` or non-ASCII letters).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum InvalidNamePolicy {
+    /// Keep the name as is.
+    #[default]
+    Keep,
+    /// Replace every invalid character with `_`, merging the scroll into
+    /// another one that ends up with the same name, and add a warning.
+    Sanitize,
+    /// Leave the scroll out of the output and add a warning.
+    Skip,
+}
+
+impl FromStr for InvalidNamePolicy {
+    type Err = GrimoireCssError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "keep" => Ok(Self::Keep),
+            "sanitize" => Ok(Self::Sanitize),
+            "skip" => Ok(Self::Skip),
+            _ => Err(GrimoireCssError::InvalidInput(format!(
+                "Unknown invalid name policy '{value}', expected keep, sanitize or skip"
+            ))),
+        }
+    }
+}
+
+/// What to do when differently spelled selectors (e.g. `.btn.primary` and
+/// `.btn\.primary`) become the same scroll with different spells.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum ConflictPolicy {
+    /// Give the scroll the union of their spells.
+    #[default]
+    Merge,
+    /// Merge them like [`ConflictPolicy::Merge`] and add a warning.
+    Warn,
+    /// Fail the transmutation.
+    Error,
+    /// Keep the first selector's scroll and give the others a numeric
+    /// suffix (`btn.primary-2`), adding a warning.
+    Rename,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = GrimoireCssError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "merge" => Ok(Self::Merge),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            "rename" => Ok(Self::Rename),
+            _ => Err(GrimoireCssError::InvalidInput(format!(
+                "Unknown conflict policy '{value}', expected merge, warn, error or rename"
+            ))),
+        }
+    }
+}
+
+/// At-rules that hold declarations without a selector, so they are collected
+/// into [`Transmuted::at_rules`] instead of becoming scrolls.
+pub const SELECTORLESS_AT_RULES: &[&str] = &["font-face", "page", "viewport", "-ms-viewport"];
+
+/// The analysis of one file, or why it could not be parsed, see [`run_analysis`].
+pub type FileAnalysis = (PathBuf, Result<AnalysisReport, GrimoireCssError>);
+
+/// What a lint pass over CSS found, see [`analyze_content`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalysisReport {
+    /// Rules with a selector, including dropped ones.
+    pub rules: usize,
+    /// Selectors transmuted into scrolls.
+    pub selectors: usize,
+    /// Classes that already are spells, in order of appearance.
+    pub skipped_existing_spells: Vec<String>,
+    /// How often each kind of construct was dropped, as in
+    /// [`Transmuted::diagnostics`].
+    pub diagnostics: BTreeMap<String, usize>,
+    /// The parser notices, one per issue worth a look.
+    pub notices: Vec<String>,
+}
+
+impl AnalysisReport {
+    /// Whether the CSS transmutes without dropping or skipping anything.
+    pub fn is_clean(&self) -> bool {
+        self.skipped_existing_spells.is_empty()
+            && self.diagnostics.is_empty()
+            && self.notices.is_empty()
+    }
+}
+
+/// Inline styles and class names found in HTML, see [`extract_from_html`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HtmlExtraction {
+    /// One entry per element with a non-empty `style` attribute, in document order.
+    pub inline_styles: Vec<InlineStyle>,
+    /// Every class name used in a `class` attribute, sorted and deduplicated.
+    pub classes: Vec<String>,
+}
+
+/// The spells of a single element's `style` attribute.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InlineStyle {
+    /// The element's tag name, lowercased.
+    pub element: String,
+    /// The classes already on the element, in attribute order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub classes: Vec<String>,
+    pub spells: Vec<String>,
+}
+
+impl HtmlExtraction {
+    /// Serializes the extraction in the given format, see [`Transmuted::serialize_as`].
+    pub fn serialize_as(
+        &self,
+        format: OutputFormat,
+        minify: bool,
+    ) -> Result<String, GrimoireCssError> {
+        match format {
+            OutputFormat::Json if minify => to_string(self).map_err(GrimoireCssError::Serde),
+            OutputFormat::Json => to_string_pretty(self).map_err(GrimoireCssError::Serde),
+            OutputFormat::Yaml => serde_norway::to_string(self)
+                .map_err(|e| GrimoireCssError::InvalidInput(format!("Failed to write YAML: {e}"))),
+            OutputFormat::Toml => toml::to_string_pretty(self)
+                .map_err(|e| GrimoireCssError::InvalidInput(format!("Failed to write TOML: {e}"))),
+        }
+    }
+}
+
+/// Options for how declarations are parsed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ParseOptions {
+    /// Keep every declaration of a property repeated within a block (e.g.
+    /// fallback `background` layers) instead of only the one that wins.
+    pub keep_duplicate_properties: bool,
+    /// Non-empty separator put between a spell's area and the rest of the
+    /// spell, [`DEFAULT_AREA_SEPARATOR`] by default.
+    pub area_separator: String,
+    /// Lowercase property names, so `COLOR: red` and `color: red` give the
+    /// same spell.
+    pub lowercase_properties: bool,
+    /// Lowercase values too, except for quoted strings and `url()` contents.
+    /// Off by default as custom identifiers (e.g. animation names) are
+    /// case-sensitive.
+    pub lowercase_values: bool,
+    /// How the spells of a scroll are ordered in its oneliner.
+    pub oneliner_order: OnelinerOrder,
+    /// Group the oneliner by property category, with `" | "` between the
+    /// groups, for readability in templates. Overrides `oneliner_order`.
+    pub pretty_spells: bool,
+    /// Property names (matched case-insensitively) whose declarations are
+    /// left out of the scrolls, e.g. to handle `content` by hand.
+    pub skip_properties: Vec<String>,
+    /// Strip the `-webkit-`, `-moz-`, `-ms-` and `-o-` prefixes from property
+    /// names, so prefixed declarations collapse into the standard one.
+    pub strip_vendor_prefixes: bool,
+    /// What to do with scroll names that are not valid Grimoire CSS keys.
+    pub invalid_names: InvalidNamePolicy,
+    /// Record the declaration and selector each spell came from in
+    /// [`TransmutedClass::spell_sources`], for debugging odd spells.
+    pub spell_sources: bool,
+    /// Record which scrolls were declared together in a selector list in
+    /// [`TransmutedClass::shared_with`], e.g. to turn them into one component.
+    pub shared_with: bool,
+    /// What to do when differently spelled selectors become the same scroll.
+    pub on_conflict: ConflictPolicy,
+    /// Leave out input files that fail to parse, listing them in
+    /// [`Transmuted::failed_files`], instead of failing the whole run.
+    pub continue_on_error: bool,
+    /// Stop parsing once this many selectors have been transmuted, for a
+    /// quick partial run over a large input. The rule that reaches the limit
+    /// is kept whole, so a selector list may overshoot it.
+    pub limit: Option<usize>,
+    /// Fail on anything the parser would otherwise drop: the tokens and
+    /// at-rules counted in [`Transmuted::diagnostics`], dangling colons and
+    /// classes skipped because they already are spells.
+    pub strict: bool,
+    /// How focuses (the selector part of a spell, e.g. `{_>_a:hover}`) are
+    /// written, Grimoire CSS syntax by default.
+    pub focus_encoder: FocusEncoder,
+    /// Name the scrolls of tag selectors (`body`) and the universal selector
+    /// `*` with [`ELEMENT_PREFIX`] (`element:body`), so they stand apart from
+    /// class scrolls. They keep the bare tag name otherwise.
+    pub include_elements: bool,
+    /// The user's Grimoire CSS config: classes that already are spells under
+    /// it (e.g. one of its scrolls) are skipped like built-in spells.
+    pub grimoire_config: Option<GrimoireConfig>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            keep_duplicate_properties: false,
+            area_separator: default_area_separator(),
+            lowercase_properties: false,
+            lowercase_values: false,
+            oneliner_order: OnelinerOrder::default(),
+            pretty_spells: false,
+            skip_properties: Vec::new(),
+            strip_vendor_prefixes: false,
+            invalid_names: InvalidNamePolicy::default(),
+            spell_sources: false,
+            shared_with: false,
+            on_conflict: ConflictPolicy::default(),
+            continue_on_error: false,
+            limit: None,
+            strict: false,
+            focus_encoder: FocusEncoder::default(),
+            include_elements: false,
+            grimoire_config: None,
+        }
+    }
+}
+
+impl ParseOptions {
+    /// The components of a block that become spells: skipped properties are
+    /// left out, the rest normalized and, unless duplicates are kept, only
+    /// the winning declaration of each property remains.
+    fn transmutable(&self, components: Vec<(String, Range<usize>)>) -> Vec<(String, Range<usize>)> {
+        let components: Vec<_> = components
+            .into_iter()
+            .filter(|(component, _)| !self.skips(component))
+            .map(|(component, span)| (self.normalize_component(component), span))
+            .collect();
+
+        if self.keep_duplicate_properties {
+            components
+        } else {
+            winning_declarations(components)
+        }
+    }
+
+    /// Whether a `component=target` spell component declares one of the
+    /// [`skip_properties`](Self::skip_properties).
+    fn skips(&self, component: &str) -> bool {
+        let property = component.split_once('=').map_or(component, |(p, _)| p);
+
+        self.skip_properties
+            .iter()
+            .any(|skipped| skipped.eq_ignore_ascii_case(property))
+    }
+
+    /// Applies the lowercasing and vendor prefix options to a
+    /// `component=target` spell component.
+    fn normalize_component(&self, component: String) -> String {
+        if !self.lowercase_properties && !self.lowercase_values && !self.strip_vendor_prefixes {
+            return component;
+        }
+
+        let Some((property, value)) = component.split_once('=') else {
+            return component;
+        };
+        let property = if self.lowercase_properties {
+            property.to_ascii_lowercase()
+        } else {
+            property.to_string()
+        };
+        let property = if self.strip_vendor_prefixes {
+            strip_vendor_prefix(&property).to_string()
+        } else {
+            property
+        };
+        let value = if self.lowercase_values {
+            lowercase_value(value)
+        } else {
+            value.to_string()
+        };
+
+        format!("{property}={value}")
+    }
+}
+
+/// Everything that shapes a transmutation, built up from the defaults with
+/// one setter per field, named after it:
+///
+/// ```
+/// use grimoire_css_transmutator_lib::{OutputFormat, ParseOptions, TransmuteOptions};
+///
+/// let options = TransmuteOptions::new()
+///     .include_oneliner(true)
+///     .format(OutputFormat::Yaml)
+///     .parse(ParseOptions { lowercase_properties: true, ..Default::default() });
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransmuteOptions {
+    /// Add a oneliner to each scroll.
+    pub include_oneliner: bool,
+    /// Add the selectors each scroll came from.
+    pub include_source: bool,
+    /// How declarations are parsed.
+    pub parse: ParseOptions,
+    /// The text format of serialized output.
+    pub format: OutputFormat,
+    /// Write serialized output without whitespace.
+    pub minify: bool,
+    /// Paths mode: leave out the files matching any of these globs.
+    pub exclude: Vec<String>,
+    /// Paths mode: guards for the run and where to look for files.
+    pub limits: RunLimits,
+}
+
+impl TransmuteOptions {
+    /// The default options: JSON without oneliners or sources.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets [`include_oneliner`](Self::include_oneliner).
+    pub fn include_oneliner(mut self, include_oneliner: bool) -> Self {
+        self.include_oneliner = include_oneliner;
+        self
+    }
+
+    /// Sets [`include_source`](Self::include_source).
+    pub fn include_source(mut self, include_source: bool) -> Self {
+        self.include_source = include_source;
+        self
+    }
+
+    /// Sets [`parse`](Self::parse).
+    pub fn parse(mut self, parse: ParseOptions) -> Self {
+        self.parse = parse;
+        self
+    }
+
+    /// Sets [`format`](Self::format).
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets [`minify`](Self::minify).
+    pub fn minify(mut self, minify: bool) -> Self {
+        self.minify = minify;
+        self
+    }
+
+    /// Sets [`exclude`](Self::exclude).
+    pub fn exclude(mut self, exclude: Vec<String>) -> Self {
+        self.exclude = exclude;
+        self
+    }
+
+    /// Sets [`limits`](Self::limits).
+    pub fn limits(mut self, limits: RunLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+/// The text format a [`Transmuted`] result is written in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Yaml,
+    /// Scrolls as `[[scrolls]]` tables, ready to paste into a TOML config.
+    Toml,
+}
+
+impl OutputFormat {
+    /// The file extension for output in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Yaml => "yaml",
+            Self::Toml => "toml",
+        }
+    }
+}
+
+/// The syntax a focus is written in. Defaults to Grimoire CSS's:
+/// `{_>_.title:hover::before}` for `.a > .title:hover::before`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FocusEncoder {
+    /// Put before and after the whole focus.
+    pub open: String,
+    pub close: String,
+    /// Put before the name of a pseudo-class and of a pseudo-element.
+    pub pseudo_class: String,
+    pub pseudo_element: String,
+    /// Put before and after an attribute selector.
+    pub attribute_open: String,
+    pub attribute_close: String,
+    /// What a space becomes, which is all the descendant combinator is;
+    /// other combinators get one on each side.
+    pub space: String,
+}
+
+impl Default for FocusEncoder {
+    fn default() -> Self {
+        Self {
+            open: "{".to_string(),
+            close: "}".to_string(),
+            pseudo_class: ":".to_string(),
+            pseudo_element: "::".to_string(),
+            attribute_open: "[".to_string(),
+            attribute_close: "]".to_string(),
+            space: "_".to_string(),
+        }
+    }
+}
+
+impl FocusEncoder {
+    /// Wraps the encoded parts of a selector into a focus.
+    pub fn focus(&self, parts: &str) -> String {
+        format!("{}{parts}{}", self.open, self.close)
+    }
+
+    /// `:hover`, or `:not(.a)` given `not(.a)`.
+    pub fn pseudo_class(&self, name: &str) -> String {
+        format!("{}{name}", self.pseudo_class)
+    }
+
+    /// `::before`.
+    pub fn pseudo_element(&self, name: &str) -> String {
+        format!("{}{name}", self.pseudo_element)
+    }
+
+    /// `_` for the descendant combinator (a space), `_>_` for `>`, `+` and
+    /// `~`, nothing for none.
+    pub fn combinator(&self, combinator: &str) -> String {
+        match combinator {
+            "" => String::new(),
+            DESCENDANT_COMBINATOR => self.space.clone(),
+            combinator => format!("{}{combinator}{}", self.space, self.space),
+        }
+    }
+
+    /// `[type=button]` given the selector without its brackets.
+    pub fn attribute(&self, attribute: &str) -> String {
+        format!("{}{attribute}{}", self.attribute_open, self.attribute_close)
+    }
+
+    /// Whether an encoded part is a pseudo-class without a combinator before
+    /// it, as opposed to a pseudo-element.
+    fn is_pseudo_class(&self, part: &str) -> bool {
+        part.starts_with(&self.pseudo_class) && !part.starts_with(&self.pseudo_element)
+    }
+
+    /// Whether `text` starts with a focus.
+    fn opens_focus(&self, text: &str) -> bool {
+        !self.open.is_empty() && text.starts_with(&self.open)
+    }
+
+    /// Splits the focus off a spell without an area, `(Some(":hover"),
+    /// "color=red")` for `{:hover}color=red`.
+    fn split_focus<'a>(&self, spell: &'a str) -> (Option<&'a str>, &'a str) {
+        if !self.opens_focus(spell) || self.close.is_empty() {
+            return (None, spell);
+        }
+
+        let focused = &spell[self.open.len()..];
+        match focused.split_once(self.close.as_str()) {
+            Some((focus, rest)) => (Some(focus), rest),
+            None => (None, focused),
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The order of the spells in a scroll's oneliner.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum OnelinerOrder {
+    /// Sorted alphabetically, the same order as `spells`.
+    #[default]
+    Alphabetical,
+    /// In the order the declarations appear in the input.
+    Source,
+    /// Grouped by property category (layout, box, border, typography,
+    /// visual, motion, then anything else), alphabetically within a group.
+    Category,
+}
+
+/// Property name prefixes of each [`OnelinerOrder::Category`] group, in order.
+const PROPERTY_CATEGORIES: &[&[&str]] = &[
+    &[
+        "display",
+        "position",
+        "inset",
+        "top",
+        "right",
+        "bottom",
+        "left",
+        "z-index",
+        "float",
+        "clear",
+        "flex",
+        "grid",
+        "gap",
+        "row-gap",
+        "column-gap",
+        "order",
+        "align-",
+        "justify-",
+        "place-",
+    ],
+    &[
+        "box-sizing",
+        "width",
+        "height",
+        "min-",
+        "max-",
+        "aspect-ratio",
+        "margin",
+        "padding",
+        "overflow",
+    ],
+    &["border", "outline"],
+    &[
+        "font",
+        "line-height",
+        "letter-spacing",
+        "word-",
+        "white-space",
+        "text-",
+        "vertical-align",
+    ],
+    &[
+        "color",
+        "background",
+        "opacity",
+        "box-shadow",
+        "filter",
+        "visibility",
+        "cursor",
+    ],
+    &["transition", "animation", "transform"],
+];
+
+impl OnelinerOrder {
+    /// Joins `spells` into a oneliner in this order; `position` gives the
+    /// source position of a spell for [`OnelinerOrder::Source`].
+    fn oneliner(
+        self,
+        spells: &[String],
+        area_separator: &str,
+        encoder: &FocusEncoder,
+        position: impl Fn(&str) -> Option<usize>,
+    ) -> String {
+        let mut ordered: Vec<&String> = spells.iter().collect();
+        match self {
+            Self::Alphabetical => ordered.sort_unstable(),
+            Self::Source => ordered.sort_by(|a, b| {
+                let position = |spell: &str| position(spell).unwrap_or(usize::MAX);
+                position(a).cmp(&position(b)).then_with(|| a.cmp(b))
+            }),
+            Self::Category => ordered.sort_by_cached_key(|spell| {
+                (
+                    property_category(spell_property(spell, area_separator, encoder)),
+                    *spell,
+                )
+            }),
+        }
+
+        ordered
+            .iter()
+            .map(|spell| spell.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Separator between the category groups of a oneliner with
+/// [`ParseOptions::pretty_spells`].
+const ONELINER_GROUP_SEPARATOR: &str = " | ";
+
+/// Joins `spells` into a oneliner grouped by property category, in the
+/// order of [`OnelinerOrder::Category`], e.g.
+/// `display=flex gap=4px | margin=0 | color=red`.
+fn pretty_oneliner(spells: &[String], area_separator: &str, encoder: &FocusEncoder) -> String {
+    let mut groups: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+    for spell in spells {
+        let category = property_category(spell_property(spell, area_separator, encoder));
+        groups.entry(category).or_default().push(spell);
+    }
+
+    groups
+        .into_values()
+        .map(|mut group| {
+            group.sort_unstable();
+            group.join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join(ONELINER_GROUP_SEPARATOR)
+}
+
+impl FromStr for OnelinerOrder {
+    type Err = GrimoireCssError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "alphabetical" => Ok(Self::Alphabetical),
+            "source" => Ok(Self::Source),
+            "category" => Ok(Self::Category),
+            _ => Err(GrimoireCssError::InvalidInput(format!(
+                "Unknown oneliner order '{value}', expected alphabetical, source or category"
+            ))),
+        }
+    }
+}
+
+/// Returns the property of a spell, without its area and focus
+/// (`color` in `md__{:hover}color=red`).
+fn spell_property<'a>(spell: &'a str, area_separator: &str, encoder: &FocusEncoder) -> &'a str {
+    let spell = match spell_area(spell, area_separator, encoder) {
+        Some(area) => &spell[area.len() + area_separator.len()..],
+        None => spell,
+    };
+    let (_, spell) = encoder.split_focus(spell);
+
+    spell
+        .split_once('=')
+        .map_or(spell, |(property, _)| property)
+}
+
+/// Vendor prefixes recognized on property names and at-keywords.
+const VENDOR_PREFIXES: &[&str] = &["-webkit-", "-moz-", "-ms-", "-o-"];
+
+/// Removes a recognized (lowercase) vendor prefix, `-webkit-transition`
+/// becomes `transition`.
+fn strip_vendor_prefix(name: &str) -> &str {
+    VENDOR_PREFIXES
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
+
+/// The index of the [`PROPERTY_CATEGORIES`] group a property belongs to,
+/// ignoring vendor prefixes; unknown properties come last.
+fn property_category(property: &str) -> usize {
+    let property = strip_vendor_prefix(property);
+
+    PROPERTY_CATEGORIES
+        .iter()
+        .position(|prefixes| prefixes.iter().any(|prefix| property.starts_with(prefix)))
+        .unwrap_or(PROPERTY_CATEGORIES.len())
+}
+
+impl FromStr for OutputFormat {
+    type Err = GrimoireCssError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "yaml" | "yml" => Ok(Self::Yaml),
+            "toml" => Ok(Self::Toml),
+            _ => Err(GrimoireCssError::InvalidInput(format!(
+                "Unknown output format '{value}', expected json, yaml or toml"
+            ))),
+        }
+    }
+}
+
+impl Transmuted {
+    /// Moves every spell scoped to one of `media_types` (e.g. `print`) out of
+    /// `scrolls` and into the `split_media` section under that media type.
+    /// Scrolls left without spells are dropped.
+    pub fn split_media(&mut self, media_types: &[String]) {
+        if media_types.is_empty() {
+            return;
+        }
+
+        let mut split: BTreeMap<String, Vec<TransmutedClass>> = BTreeMap::new();
+
+        for scroll in &mut self.scrolls {
+            let mut kept = Vec::with_capacity(scroll.spells.len());
+            let mut moved: BTreeMap<&String, Vec<String>> = BTreeMap::new();
+
+            for spell in scroll.spells.drain(..) {
+                let media_type = spell_area(&spell, &self.area_separator, &self.focus_encoder)
+                    .and_then(|area| {
+                        area_media_types(area).find_map(|found| {
+                            media_types
+                                .iter()
+                                .find(|wanted| wanted.eq_ignore_ascii_case(found))
+                        })
+                    });
+
+                match media_type {
+                    Some(media_type) => moved.entry(media_type).or_default().push(spell),
+                    None => kept.push(spell),
+                }
+            }
+
+            for (media_type, spells) in moved {
+                let part = scroll.part(spells.clone(), |spell| {
+                    spells.iter().any(|moved| moved == spell).then_some(spell)
+                });
+                split.entry(media_type.clone()).or_default().push(part);
+            }
+
+            scroll.spells = kept;
+            scroll.refresh_oneliner();
+            let kept = &scroll.spells;
+            scroll
+                .spell_sources
+                .retain(|source| kept.contains(&source.spell));
+        }
+
+        self.scrolls.retain(|scroll| !scroll.spells.is_empty());
+
+        for (media_type, mut moved) in split {
+            moved.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+            self.split_media
+                .entry(media_type)
+                .or_default()
+                .append(&mut moved);
+        }
+    }
+
+    /// Builds every generated spell with Grimoire CSS and reports the ones
+    /// that do not parse, fail to build or build to empty CSS.
+    pub fn check_grimoire(&self) -> Vec<SpellIssue> {
+        let scrolls = self
+            .scrolls
+            .iter()
+            .chain(self.split_media.values().flatten());
+
+        let mut issues = Vec::new();
+
+        for scroll in scrolls {
+            for spell in &scroll.spells {
+                let reason = match is_spell(spell, None) {
+                    Ok(true) => match build_spell_css(spell) {
+                        Ok(css) if css.trim().is_empty() => "builds to empty CSS".to_string(),
+                        Ok(_) => continue,
+                        Err(err) => err.to_string(),
+                    },
+                    Ok(false) => "does not build to a spell".to_string(),
+                    Err(err) => err.to_string(),
+                };
+
+                issues.push(SpellIssue {
+                    scroll: scroll.name.clone(),
+                    spell: spell.clone(),
+                    reason,
+                });
+            }
+        }
+
+        issues
+    }
+
+    /// Fills the `responsive` section: for every scroll, its spells keyed by
+    /// breakpoint. `breakpoints` maps an alias (e.g. `md`) to a media condition
+    /// (e.g. `(min-width: 768px)`); spells without an area go under `base` and
+    /// spells in unaliased areas keep the area as their key.
+    ///
+    /// A leading `screen and`/`all and` is ignored when matching, and an
+    /// unaliased `(prefers-color-scheme: dark)` area is keyed `dark` (likewise
+    /// `light`), so theme spells separate cleanly.
+    pub fn group_breakpoints(&mut self, breakpoints: &[(String, String)]) {
+        let aliases: HashMap<String, &str> = breakpoints
+            .iter()
+            .map(|(alias, condition)| {
+                let area = canonical_media_condition(condition).replace(' ', "_");
+                (alias_key(&area).to_string(), alias.as_str())
+            })
+            .collect();
+
+        for scroll in &self.scrolls {
+            let variants = self.responsive.entry(scroll.name.clone()).or_default();
+
+            for spell in &scroll.spells {
+                let (key, spell) =
+                    match spell_area(spell, &self.area_separator, &self.focus_encoder) {
+                        Some(area) => (
+                            breakpoint_key(area, &aliases),
+                            &spell[area.len() + self.area_separator.len()..],
+                        ),
+                        None => ("base", spell.as_str()),
+                    };
+
+                variants
+                    .entry(key.to_string())
+                    .or_default()
+                    .push(spell.to_string());
+            }
+        }
+    }
+
+    /// Moves every scroll into the `areas` section, splitting it into one scroll
+    /// per area it has spells in. Unscoped spells go under `base`.
+    pub fn group_by_area(&mut self) {
+        let separator = self.area_separator.as_str();
+        let encoder = &self.focus_encoder;
+
+        for scroll in self.scrolls.drain(..) {
+            let mut by_area: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+
+            for spell in &scroll.spells {
+                let (area, spell) = split_base_area(spell, separator, encoder);
+                by_area.entry(area).or_default().push(spell.to_string());
+            }
+
+            for (area, spells) in by_area {
+                let class = scroll.part(spells, |spell| {
+                    let (spell_area, spell) = split_base_area(spell, separator, encoder);
+                    (spell_area == area).then_some(spell)
+                });
+                self.areas.entry(area.to_string()).or_default().push(class);
+            }
+        }
+    }
+
+    /// Marks every scroll with exactly one spell as collapsed, so it is emitted
+    /// as a scalar `spell` field (without `spells` or `oneliner`).
+    pub fn collapse_single(&mut self) {
+        for scroll in self
+            .scrolls
+            .iter_mut()
+            .chain(self.split_media.values_mut().flatten())
+            .chain(self.areas.values_mut().flatten())
+        {
+            if scroll.spells.len() == 1 {
+                scroll.collapsed = true;
+                scroll.oneliner = None;
+            }
+        }
+    }
+
+    /// Serializes the source map (scroll -> spell -> span) as pretty-printed JSON.
+    pub fn source_map_json(&self) -> Result<String, GrimoireCssError> {
+        to_string_pretty(&self.source_map).map_err(GrimoireCssError::Serde)
+    }
+
+    /// Reads output written by [`Transmuted::serialize_as`] back, refusing
+    /// output written with another [`SCHEMA_VERSION`].
+    pub fn deserialize_as(content: &str, format: OutputFormat) -> Result<Self, GrimoireCssError> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+
+        fn from_yaml<T: serde::de::DeserializeOwned>(content: &str) -> Result<T, GrimoireCssError> {
+            serde_norway::from_str(content)
+                .map_err(|e| GrimoireCssError::InvalidInput(format!("Failed to read YAML: {e}")))
+        }
+
+        fn from_toml<T: serde::de::DeserializeOwned>(content: &str) -> Result<T, GrimoireCssError> {
+            toml::from_str(content)
+                .map_err(|e| GrimoireCssError::InvalidInput(format!("Failed to read TOML: {e}")))
+        }
+
+        let version = match format {
+            OutputFormat::Json => {
+                serde_json::from_str::<Versioned>(content).map_err(GrimoireCssError::Serde)?
+            }
+            OutputFormat::Yaml => from_yaml::<Versioned>(content)?,
+            OutputFormat::Toml => from_toml::<Versioned>(content)?,
+        }
+        .version;
+        if version != SCHEMA_VERSION {
+            return Err(GrimoireCssError::InvalidInput(format!(
+                "The output was written with schema version {version}, but this version \
+                 reads and writes version {SCHEMA_VERSION}."
+            )));
+        }
+
+        match format {
+            OutputFormat::Json => serde_json::from_str(content).map_err(GrimoireCssError::Serde),
+            OutputFormat::Yaml => from_yaml(content),
+            OutputFormat::Toml => from_toml(content),
+        }
+    }
+
+    /// Merges a newer result into this one: scrolls of the same name get the
+    /// union of their spells, new scrolls are added after the existing ones.
+    /// The notices, source map, stats and timings, which describe a run
+    /// rather than the output, are taken from `newer`.
+    pub fn merge(&mut self, newer: Transmuted) {
+        self.pretty_spells = newer.pretty_spells;
+        let grouping = self
+            .pretty_spells
+            .then_some((self.area_separator.as_str(), &self.focus_encoder));
+
+        merge_scrolls(&mut self.scrolls, newer.scrolls, grouping);
+        for (media_type, scrolls) in newer.split_media {
+            let existing = self.split_media.entry(media_type).or_default();
+            merge_scrolls(existing, scrolls, grouping);
+        }
+        for (area, scrolls) in newer.areas {
+            merge_scrolls(self.areas.entry(area).or_default(), scrolls, grouping);
+        }
+        for (alias, classes) in newer.responsive {
+            let existing = self.responsive.entry(alias).or_default();
+            for (name, spells) in classes {
+                let existing = existing.entry(name).or_default();
+                for spell in spells {
+                    if !existing.contains(&spell) {
+                        existing.push(spell);
+                    }
+                }
+                existing.sort_unstable();
+            }
+        }
+
+        self.custom_properties.extend(newer.custom_properties);
+        self.keyframes.extend(newer.keyframes);
+        for at_rule in newer.at_rules {
+            if !self.at_rules.contains(&at_rule) {
+                self.at_rules.push(at_rule);
+            }
+        }
+        for class in newer.skipped_existing_spells {
+            if !self.skipped_existing_spells.contains(&class) {
+                self.skipped_existing_spells.push(class);
+            }
+        }
+        for failed in newer.failed_files {
+            if !self.failed_files.contains(&failed) {
+                self.failed_files.push(failed);
+            }
+        }
+        for warning in newer.warnings {
+            if !self.warnings.contains(&warning) {
+                self.warnings.push(warning);
+            }
+        }
+        for (kind, count) in newer.diagnostics {
+            *self.diagnostics.entry(kind).or_default() += count;
+        }
+        for (property, count) in newer.property_histogram {
+            *self.property_histogram.entry(property).or_default() += count;
+        }
+
+        self.generator = newer.generator;
+        self.notices = newer.notices;
+        self.source_map = newer.source_map;
+        self.stats = newer.stats;
+        self.timings = newer.timings;
+    }
+
+    /// Serializes the result as pretty-printed JSON.
+    pub fn to_json(&self) -> Result<String, GrimoireCssError> {
+        to_string_pretty(self).map_err(GrimoireCssError::Serde)
+    }
+
+    /// Serializes the result as compact JSON, without any whitespace.
+    pub fn to_json_compact(&self) -> Result<String, GrimoireCssError> {
+        to_string(self).map_err(GrimoireCssError::Serde)
+    }
+
+    /// Serializes the result as YAML.
+    pub fn to_yaml(&self) -> Result<String, GrimoireCssError> {
+        serde_norway::to_string(self)
+            .map_err(|e| GrimoireCssError::InvalidInput(format!("Failed to write YAML: {e}")))
+    }
+
+    /// Serializes the result as TOML, with each scroll a `[[scrolls]]` table.
+    pub fn to_toml(&self) -> Result<String, GrimoireCssError> {
+        toml::to_string_pretty(self)
+            .map_err(|e| GrimoireCssError::InvalidInput(format!("Failed to write TOML: {e}")))
+    }
+
+    /// Serializes the result in the given format. `minify` selects compact
+    /// JSON; YAML and TOML have no compact form and ignore it.
+    pub fn serialize_as(
+        &self,
+        format: OutputFormat,
+        minify: bool,
+    ) -> Result<String, GrimoireCssError> {
+        match format {
+            OutputFormat::Json if minify => self.to_json_compact(),
+            OutputFormat::Json => self.to_json(),
+            OutputFormat::Yaml => self.to_yaml(),
+            OutputFormat::Toml => self.to_toml(),
+        }
+    }
+
+    /// Serializes the result like [`Transmuted::serialize_as`], but straight
+    /// into `writer` instead of building the whole output in memory first.
+    pub fn write_as(
+        &self,
+        mut writer: impl Write,
+        format: OutputFormat,
+        minify: bool,
+    ) -> Result<(), GrimoireCssError> {
+        match format {
+            OutputFormat::Json if minify => {
+                serde_json::to_writer(&mut writer, self).map_err(GrimoireCssError::Serde)?
+            }
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, self).map_err(GrimoireCssError::Serde)?
+            }
+            OutputFormat::Yaml => serde_norway::to_writer(&mut writer, self).map_err(|e| {
+                GrimoireCssError::InvalidInput(format!("Failed to write YAML: {e}"))
+            })?,
+            // The TOML serializer only writes strings
+            OutputFormat::Toml => writer
+                .write_all(self.to_toml()?.as_bytes())
+                .map_err(GrimoireCssError::Io)?,
+        }
+
+        writer.flush().map_err(GrimoireCssError::Io)
+    }
+
+    /// Generates Rust source exposing the scrolls as a static slice and a `HashMap`,
+    /// so a build script can bake the migration into a binary.
+    pub fn to_rust_source(&self) -> String {
+        let mut source = String::from(
+            "// Generated by grimoire_css_transmutator. Do not edit.\n\n\
+             use std::collections::HashMap;\n\n\
+             pub static TRANSMUTED: &[(&str, &[&str])] = &[\n",
+        );
+
+        for class in &self.scrolls {
+            // `{:?}` on a str yields a valid Rust string literal
+            let spells: Vec<String> = class.spells.iter().map(|s| format!("{s:?}")).collect();
+            source.push_str(&format!(
+                "    ({:?}, &[{}]),\n",
+                class.name,
+                spells.join(", ")
+            ));
+        }
+
+        source.push_str(
+            "];\n\n\
+             pub fn transmuted() -> HashMap<&'static str, &'static [&'static str]> {\n\
+             \x20   TRANSMUTED.iter().copied().collect()\n\
+             }\n",
+        );
+
+        source
+    }
+}
+
+/// A generated spell that Grimoire CSS cannot build.
+#[derive(Debug, Serialize)]
+pub struct SpellIssue {
+    pub scroll: String,
+    pub spell: String,
+    pub reason: String,
+}
+
+/// Adds `newer` scrolls to `scrolls`, merging those with a name already there.
+/// With a `grouping` (area separator and focus encoder), merged oneliners are
+/// grouped by category like [`ParseOptions::pretty_spells`] does.
+fn merge_scrolls(
+    scrolls: &mut Vec<TransmutedClass>,
+    newer: Vec<TransmutedClass>,
+    grouping: Option<(&str, &FocusEncoder)>,
+) {
+    for scroll in newer {
+        match scrolls
+            .iter_mut()
+            .find(|existing| existing.name == scroll.name)
+        {
+            Some(existing) => existing.merge(scroll, grouping),
+            None => scrolls.push(scroll),
+        }
+    }
+}
+
+impl TransmutedClass {
+    /// Adds the spells of a newer scroll of the same name, keeping the
+    /// spells sorted and appending new ones to the oneliner, or regrouping
+    /// it with a `grouping` (see [`merge_scrolls`]).
+    fn merge(&mut self, newer: TransmutedClass, grouping: Option<(&str, &FocusEncoder)>) {
+        let added: Vec<&String> = newer
+            .spells
+            .iter()
+            .filter(|spell| !self.spells.contains(spell))
+            .collect();
+        if let Some(oneliner) = &mut self.oneliner {
+            let order: Vec<&str> = newer.oneliner.as_deref().map_or_else(
+                || added.iter().map(|spell| spell.as_str()).collect(),
+                |newer| {
+                    newer
+                        .split(' ')
+                        .filter(|spell| added.iter().any(|a| a == spell))
+                        .collect()
+                },
+            );
+            for spell in order {
+                oneliner.push(' ');
+                oneliner.push_str(spell);
+            }
+        }
+        let added: Vec<String> = added.into_iter().cloned().collect();
+        self.spells.extend(added);
+        self.spells.sort_unstable();
+        if let (Some(oneliner), Some((area_separator, encoder))) = (&mut self.oneliner, grouping) {
+            *oneliner = pretty_oneliner(&self.spells, area_separator, encoder);
+        }
+        self.collapsed = self.collapsed && self.spells.len() == 1;
+
+        if self.source_selector.is_none() {
+            self.source_selector = newer.source_selector;
+        }
+        if self.layer.is_none() {
+            self.layer = newer.layer;
+        }
+        for source in newer.spell_sources {
+            if !self.spell_sources.contains(&source) {
+                self.spell_sources.push(source);
+            }
+        }
+        for other in newer.shared_with {
+            if !self.shared_with.contains(&other) {
+                self.shared_with.push(other);
+            }
+        }
+        self.shared_with.sort_unstable();
+        self.duplicate_count = self.duplicate_count.max(newer.duplicate_count);
+    }
+
+    /// A scroll of the same class with only `spells`, taking the spells of
+    /// its oneliner and spell sources that `keep` maps to one of them.
+    fn part(&self, spells: Vec<String>, keep: impl Fn(&str) -> Option<&str>) -> Self {
+        let oneliner = self
+            .oneliner
+            .as_deref()
+            .map(|oneliner| oneliner_subset(oneliner, &keep));
+        let spell_sources = self
+            .spell_sources
+            .iter()
+            .filter_map(|source| {
+                keep(&source.spell).map(|spell| SpellSource {
+                    spell: spell.to_string(),
+                    ..source.clone()
+                })
+            })
+            .collect();
+
+        Self {
+            name: self.name.clone(),
+            spells,
+            oneliner,
+            collapsed: false,
+            source_selector: self.source_selector.clone(),
+            layer: self.layer.clone(),
+            spell_sources,
+            shared_with: self.shared_with.clone(),
+            duplicate_count: self.duplicate_count,
+        }
+    }
+
+    /// Rebuilds the oneliner (when enabled) after spells were removed,
+    /// keeping the order of the spells that remain.
+    fn refresh_oneliner(&mut self) {
+        if let Some(oneliner) = &self.oneliner {
+            self.oneliner = Some(oneliner_subset(oneliner, |spell| {
+                self.spells
+                    .iter()
+                    .any(|kept| kept == spell)
+                    .then_some(spell)
+            }));
+        }
+    }
+}
+
+/// Rebuilds a oneliner from the spells of `oneliner` that `keep` maps to a
+/// spell, in their order, dropping category groups left empty. Spells never
+/// contain spaces, see [`normalize_target`].
+fn oneliner_subset<'a>(oneliner: &'a str, keep: impl Fn(&'a str) -> Option<&'a str>) -> String {
+    oneliner
+        .split(ONELINER_GROUP_SEPARATOR)
+        .map(|group| {
+            group
+                .split(' ')
+                .filter_map(&keep)
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|group| !group.is_empty())
+        .collect::<Vec<_>>()
+        .join(ONELINER_GROUP_SEPARATOR)
+}
+
+type TransmutedMap = HashMap<String, HashSet<String>>;
+
+/// `focus_delim` value standing for the descendant (whitespace) combinator.
+const DESCENDANT_COMBINATOR: &str = " ";
+
+/// Default separator between a spell's area (media condition) and the rest
+/// of the spell, as Grimoire CSS expects it.
+pub const DEFAULT_AREA_SEPARATOR: &str = "__";
+
+fn default_area_separator() -> String {
+    DEFAULT_AREA_SEPARATOR.to_string()
+}
+
+fn is_default_area_separator(separator: &str) -> bool {
+    separator == DEFAULT_AREA_SEPARATOR
+}
+
+/// The part of an area compared against breakpoint aliases: a leading
+/// `screen and`/`all and` does not change which breakpoint is meant.
+fn alias_key(area: &str) -> &str {
+    ["screen_and_", "all_and_"]
+        .iter()
+        .find_map(|media_type| area.strip_prefix(media_type))
+        .unwrap_or(area)
+}
+
+/// The `responsive` key of an area: its alias, the color scheme for an
+/// unaliased `prefers-color-scheme` area, or the area itself.
+fn breakpoint_key<'a>(area: &'a str, aliases: &HashMap<String, &'a str>) -> &'a str {
+    let key = alias_key(area);
+
+    aliases.get(key).copied().unwrap_or_else(|| {
+        key.strip_prefix("(prefers-color-scheme:_")
+            .and_then(|scheme| scheme.strip_suffix(')'))
+            .unwrap_or(area)
+    })
+}
+
+/// Prefix of the scroll name of an element (tag or universal) selector, e.g.
+/// `element:body` or `element:*`, see [`ParseOptions::include_elements`].
+pub const ELEMENT_PREFIX: &str = "element:";
+
+/// Prefix of the area of spells scoped by an `@container` query, e.g.
+/// `container_sidebar_(min-width:_400px)__`.
+const CONTAINER_AREA_PREFIX: &str = "container_";
+
+/// Splits a spell into its area and the rest, using `base` for unscoped spells.
+fn split_base_area<'a>(
+    spell: &'a str,
+    separator: &str,
+    encoder: &FocusEncoder,
+) -> (&'a str, &'a str) {
+    match spell_area(spell, separator, encoder) {
+        Some(area) => (area, &spell[area.len() + separator.len()..]),
+        None => ("base", spell),
+    }
+}
+
+/// Returns the area prefix of a spell (e.g. `print` in `print__color=black`)
+/// given the area `separator` and how its focus is written.
+fn spell_area<'a>(spell: &'a str, separator: &str, encoder: &FocusEncoder) -> Option<&'a str> {
+    let (area, _) = spell.split_once(separator)?;
+
+    // A `=` outside of parentheses means the separator belongs to the
+    // component or target, not to an area prefix.
+    let mut depth = 0usize;
+    for c in area.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            '=' if depth == 0 => return None,
+            _ => {}
+        }
+    }
+
+    if area.is_empty() || encoder.opens_focus(area) {
+        None
+    } else {
+        Some(area)
+    }
+}
+
+/// Yields the media types (e.g. `screen`, `print`) named by an area.
+/// Negated queries (`not print`) name no type.
+fn area_media_types(area: &str) -> impl Iterator<Item = &str> {
+    area.split(',').filter_map(|query| {
+        let mut words = query
+            .split('_')
+            .filter(|w| !w.is_empty() && !w.eq_ignore_ascii_case("only"));
+
+        match words.next() {
+            Some(word) if word.eq_ignore_ascii_case("not") || word.starts_with('(') => None,
+            word => word,
+        }
+    })
+}
+
+/// Whether `class` builds to a Grimoire CSS spell, given the scrolls and
+/// shared spells of a Grimoire CSS config, if there is one.
+///
+/// `Spell::new` changed its signature between Grimoire CSS releases; only the
+/// release this crate builds with (see `Cargo.toml`) is supported.
+fn is_spell(class: &str, config: Option<&GrimoireConfig>) -> Result<bool, GrimoireCssError> {
+    let spell = match config {
+        Some(config) => Spell::new(class, &config.shared_spells, &config.scrolls)?,
+        None => Spell::new(class, &HashSet::new(), &None)?,
+    };
+    Ok(spell.is_some())
+}
+
+/// The CSS Grimoire CSS builds for the single `spell`.
+fn build_spell_css(spell: &str) -> Result<String, GrimoireCssError> {
+    // The in-memory builder collects spells from class attributes
+    let quote = if spell.contains('"') { '\'' } else { '"' };
+    let config = ConfigInMemory {
+        projects: vec![ConfigInMemoryEntry {
+            name: "check".to_string(),
+            content: vec![format!("<div class={quote}{spell}{quote}></div>")],
+            optimized: false,
+        }],
+        ..Default::default()
+    };
+
+    Ok(start_in_memory(&config)?
+        .into_iter()
+        .map(|compiled| compiled.content)
+        .collect())
+}
+
+/// What the parse recorded about one class besides its spells.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ClassRecord {
+    /// Position of the class in order of first appearance.
+    order: usize,
+    /// Byte range of the declaration that first produced each spell.
+    spell_spans: HashMap<String, Range<usize>>,
+    /// The `component=target` pair and selector that first produced each
+    /// spell, when [`ParseOptions::spell_sources`] is set.
+    spell_origins: HashMap<String, (String, Option<String>)>,
+    /// The classes this one shared a selector list with, when
+    /// [`ParseOptions::shared_with`] is set.
+    shared_with: BTreeSet<String>,
+    /// How many rules produced each distinct set of spells, keyed by the
+    /// sorted spells joined with spaces.
+    rule_repeats: HashMap<String, usize>,
+    /// The spellings the class was written with and the spells each one
+    /// produced, unless [`ParseOptions::on_conflict`] is `Merge`.
+    spellings: Vec<(String, BTreeSet<String>)>,
+    /// The distinct selectors that produced the class, in order of appearance.
+    selectors: Vec<String>,
+    /// The cascade layer the class was first declared in.
+    layer: Option<String>,
+}
+
+impl ClassRecord {
+    /// Adds the spells the class produced when written as `spelling`.
+    fn add_spelling(&mut self, spelling: String, spells: BTreeSet<String>) {
+        match self
+            .spellings
+            .iter_mut()
+            .find(|(known, _)| *known == spelling)
+        {
+            Some((_, known)) => known.extend(spells),
+            None => self.spellings.push((spelling, spells)),
+        }
+    }
+
+    /// Adds what was recorded for the same class elsewhere, keeping the
+    /// first span, origin and layer and its own position.
+    fn absorb(&mut self, other: ClassRecord) {
+        for (spell, span) in other.spell_spans {
+            self.spell_spans.entry(spell).or_insert(span);
+        }
+        for (spell, origin) in other.spell_origins {
+            self.spell_origins.entry(spell).or_insert(origin);
+        }
+        self.shared_with.extend(other.shared_with);
+        for (spells, count) in other.rule_repeats {
+            *self.rule_repeats.entry(spells).or_default() += count;
+        }
+        for (spelling, spells) in other.spellings {
+            self.add_spelling(spelling, spells);
+        }
+        for selector in other.selectors {
+            if !self.selectors.contains(&selector) {
+                self.selectors.push(selector);
+            }
+        }
+        if self.layer.is_none() {
+            self.layer = other.layer;
+        }
+    }
+}
+
+/// The [`ClassRecord`] of each class, keyed by class name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ClassRecords(HashMap<String, ClassRecord>);
+
+impl ClassRecords {
+    fn get(&self, class: &str) -> Option<&ClassRecord> {
+        self.0.get(class)
+    }
+
+    /// The record of `class`, added after the others when it is new.
+    fn entry(&mut self, class: &str) -> &mut ClassRecord {
+        let next = self.0.len();
+        self.0
+            .entry(class.to_string())
+            .or_insert_with(|| ClassRecord {
+                order: next,
+                ..Default::default()
+            })
+    }
+
+    /// Takes over the records of a nested parse, new classes after the
+    /// known ones in their nested order.
+    fn absorb(&mut self, nested: ClassRecords) {
+        let mut nested: Vec<_> = nested.0.into_iter().collect();
+        nested.sort_unstable_by_key(|(_, record)| record.order);
+        for (class, record) in nested {
+            self.entry(&class).absorb(record);
+        }
+    }
+
+    /// Moves the record of class `from` over to class `to`, or forgets it
+    /// when `to` is `None`.
+    fn rename(&mut self, from: &str, to: Option<&str>) {
+        let record = self.0.remove(from);
+        for other in self.0.values_mut() {
+            if other.shared_with.remove(from) {
+                other.shared_with.extend(to.map(str::to_string));
+            }
+        }
+        let (Some(to), Some(record)) = (to, record) else {
+            return;
+        };
+
+        match self.0.get_mut(to) {
+            Some(existing) => {
+                existing.order = existing.order.min(record.order);
+                existing.absorb(record);
+            }
+            None => {
+                self.0.insert(to.to_string(), record);
+            }
+        }
+        if let Some(renamed) = self.0.get_mut(to) {
+            renamed.shared_with.remove(to);
+        }
+    }
+
+    /// Moves every recorded span with `shift`.
+    fn shift_spans(&mut self, shift: impl Fn(&Range<usize>) -> Range<usize>) {
+        for span in self
+            .0
+            .values_mut()
+            .flat_map(|record| record.spell_spans.values_mut())
+        {
+            *span = shift(span);
+        }
+    }
+}
+
+/// Represents the state during CSS parsing.
+#[derive(Debug, Default)]
+struct ParserState {
+    pub raw_classes_spells_map: HashMap<String, Vec<String>>,
+    pub current_class: String,
+    /// The class part of the current selector as written, re-escaped (e.g.
+    /// `.btn.primary` or `.btn\.primary`), when it is built from classes.
+    pub class_spelling: String,
+    /// The spelling of each class of the current rule.
+    pub rule_spellings: HashMap<String, String>,
+    /// The raw spells of each class of the current rule before the area is
+    /// applied, for `@media` blocks nested in the rule.
+    pub rule_bases: HashMap<String, Vec<String>>,
+    pub started_media_pos: Option<SourcePosition>,
+    /// The pending at-rule at `started_media_pos` is `@container`, not `@media`.
+    pub container_query: bool,
+    /// Where the name of a pending `@layer` block starts.
+    pub started_layer_pos: Option<SourcePosition>,
+    /// The prelude of an at-rule the parser drops (`@import "x.css";`) is
+    /// being read; its tokens are not counted on their own.
+    pub dropped_at_rule: bool,
+    pub focus: Vec<String>,
+    pub component_and_component_target_map: HashSet<String>,
+    pub effects: Vec<String>,
+    pub class_started: bool,
+    pub focus_delim: String,
+    pub effect_started: bool,
+    /// Colons of the pending pseudo selector: one for a pseudo-class, two
+    /// for a pseudo-element.
+    pub colons: usize,
+    pub area: Option<String>,
+    /// The condition of the enclosing `@media` block as written, which
+    /// `@media` blocks nested in a rule add to; `None` inside `@container`.
+    pub media_condition: Option<String>,
+    /// The cascade layer the parsed rules belong to.
+    pub layer: Option<String>,
+    pub deadline: Option<Deadline>,
+    pub notices: Vec<String>,
+    /// Byte offset of the parsed input within the original source.
+    pub offset: usize,
+    /// Zero-based line and column of the parsed input within the original
+    /// source, so locations in nested blocks can be reported absolutely.
+    pub line_offset: u32,
+    pub column_offset: u32,
+    /// Only collect selector names, skipping declaration parsing.
+    pub names_only: bool,
+    /// Components of the current block with the byte range they came from.
+    pub component_spans: Vec<(String, Range<usize>)>,
+    /// What was recorded about each class besides its spells.
+    pub classes: ClassRecords,
+    /// Where the selector of the current rule starts.
+    pub selector_start: Option<SourcePosition>,
+    /// Custom property declarations (`--name: value`), first definition wins.
+    pub custom_properties: BTreeMap<String, String>,
+    /// `@keyframes` rules by animation name, the last definition wins.
+    pub keyframes: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    /// Selectorless at-rules in source order.
+    pub at_rules: Vec<TransmutedAtRule>,
+    /// Classes skipped because they already are spells, in order of appearance.
+    pub skipped_existing_spells: Vec<String>,
+    /// Input files that failed to parse, with the error.
+    pub failed_files: Vec<(PathBuf, String)>,
+    /// Scrolls renamed or skipped for an invalid name.
+    pub warnings: Vec<NameWarning>,
+    /// Ignored tokens, counted by kind.
+    pub dropped_tokens: BTreeMap<String, usize>,
+    /// Transmuted declarations, counted by property.
+    pub property_counts: BTreeMap<String, usize>,
+    pub options: ParseOptions,
+    /// Counts collected while parsing.
+    pub stats: TransmutationStats,
+    /// Selectors transmuted so far, including the enclosing blocks and the
+    /// files before this one, checked against [`ParseOptions::limit`].
+    pub selectors_flushed: usize,
+}
+
+impl ParserState {
+    /// The one-based line and column of the parser's position in the original
+    /// source.
+    fn source_location(&self, parser: &Parser<'_, '_>) -> (u32, u32) {
+        let location = parser.current_source_location();
+        let column = if location.line == 0 {
+            self.column_offset + location.column
+        } else {
+            location.column
+        };
+
+        (self.line_offset + location.line + 1, column)
+    }
+
+    /// Remembers where each spell of the current block was declared.
+    fn record_spell_spans(&mut self) {
+        for (class, prefixes) in &self.raw_classes_spells_map {
+            let spans = &mut self.classes.entry(class).spell_spans;
+
+            for prefix in prefixes {
+                for (component, span) in &self.component_spans {
+                    spans
+                        .entry(format!("{prefix}{component}"))
+                        .or_insert_with(|| span.clone());
+                }
+            }
+        }
+    }
+
+    /// Remembers the component and selector each spell of the current block
+    /// came from.
+    fn record_spell_origins(&mut self, selector: Option<&str>) {
+        let selector = selector.map(|s| s.split_whitespace().collect::<Vec<_>>().join(" "));
+
+        for (class, prefixes) in &self.raw_classes_spells_map {
+            let origins = &mut self.classes.entry(class).spell_origins;
+
+            for prefix in prefixes {
+                for (component, _) in &self.component_spans {
+                    origins
+                        .entry(format!("{prefix}{component}"))
+                        .or_insert_with(|| (component.clone(), selector.clone()));
+                }
+            }
+        }
+    }
+
+    /// Remembers the spelling of each class of the current rule along with
+    /// the spells it produced.
+    fn record_spellings(&mut self, spells_map: &TransmutedMap) {
+        for (class, spells) in spells_map {
+            let Some(spelling) = self.rule_spellings.get(class) else {
+                continue;
+            };
+            self.classes
+                .entry(class)
+                .add_spelling(spelling.clone(), spells.iter().cloned().collect());
+        }
+    }
+
+    /// Counts the spells each class of the current rule produced, so rules
+    /// repeated word for word can be reported.
+    fn record_rule_repeats(&mut self, spells_map: &TransmutedMap) {
+        for (class, spells) in spells_map {
+            let mut spells: Vec<&str> = spells.iter().map(String::as_str).collect();
+            spells.sort_unstable();
+            *self
+                .classes
+                .entry(class)
+                .rule_repeats
+                .entry(spells.join(" "))
+                .or_default() += 1;
+        }
+    }
+
+    /// Remembers that the classes of the current rule were declared together.
+    fn record_shared_with(&mut self) {
+        if self.raw_classes_spells_map.len() < 2 {
+            return;
+        }
+
+        for class in self.raw_classes_spells_map.keys() {
+            let others = self
+                .raw_classes_spells_map
+                .keys()
+                .filter(|other| *other != class)
+                .cloned();
+            self.classes.entry(class).shared_with.extend(others);
+        }
+    }
+
+    /// Whether [`ParseOptions::limit`] selectors have been transmuted.
+    fn limit_reached(&self) -> bool {
+        self.options
+            .limit
+            .is_some_and(|limit| self.selectors_flushed >= limit)
+    }
+
+    /// Clears everything collected for the current selector and its block.
+    fn reset_selector(&mut self) {
+        self.raw_classes_spells_map.clear();
+        self.current_class.clear();
+        self.class_spelling.clear();
+        self.rule_spellings.clear();
+        self.rule_bases.clear();
+        self.component_and_component_target_map.clear();
+        self.component_spans.clear();
+        self.effects.clear();
+        self.focus.clear();
+        self.class_started = false;
+        self.focus_delim.clear();
+        self.effect_started = false;
+        self.colons = 0;
+        self.selector_start = None;
+    }
+
+    /// The scroll name of the element selector `tag`, prefixed with
+    /// [`ELEMENT_PREFIX`] when [`ParseOptions::include_elements`] is set.
+    fn element_scroll(&self, tag: &str) -> String {
+        if self.options.include_elements {
+            format!("{ELEMENT_PREFIX}{tag}")
+        } else {
+            tag.to_string()
+        }
+    }
+
+    /// Whether the last focus item is a universal selector operand (`_>_*`),
+    /// which a glued class (`*.b`) continues.
+    fn universal_operand(&self) -> bool {
+        self.focus_delim.is_empty() && self.focus.last().is_some_and(|item| item.ends_with('*'))
+    }
+
+    /// Adds a pseudo selector (`hover`, `not(.a)`) to the focus, after the
+    /// pending combinator. Its colons decide between a pseudo-class and a
+    /// pseudo-element; more than two are read as two.
+    ///
+    /// A selector of pseudo-classes only (e.g. `:hover:focus`) is named after
+    /// the first of them in sorted order, like the focus lists them. The name
+    /// is spelled as in CSS, whatever the [`FocusEncoder`].
+    fn push_pseudo(&mut self, pseudo: &str) {
+        let colons = if self.colons > 2 {
+            self.notices.push(format!(
+                "Malformed pseudo selector '{}{pseudo}' read as '::{pseudo}'.",
+                ":".repeat(self.colons)
+            ));
+            2
+        } else {
+            self.colons.max(1)
+        };
+        let combinator = self.take_combinator();
+
+        self.effect_started = false;
+        self.colons = 0;
+
+        let encoder = &self.options.focus_encoder;
+        let (name, focus_item) = match colons {
+            2 => (
+                format!("::{pseudo}"),
+                format!("{combinator}{}", encoder.pseudo_element(pseudo)),
+            ),
+            _ => (
+                format!(":{pseudo}"),
+                format!("{combinator}{}", encoder.pseudo_class(pseudo)),
+            ),
+        };
+
+        let renames = colons == 1
+            && combinator.is_empty()
+            && self
+                .current_class
+                .strip_prefix(':')
+                .is_some_and(|first| self.focus.first() == Some(&encoder.pseudo_class(first)))
+            && self.focus.iter().all(|item| encoder.is_pseudo_class(item))
+            && name < self.current_class;
+
+        if self.current_class.is_empty() || renames {
+            self.current_class = name;
+        }
+
+        self.focus.push(focus_item);
+    }
+
+    /// The focus of the current selector as written in a spell. Each chain of
+    /// pseudo-classes is sorted, as `:hover:focus` and `:focus:hover` select
+    /// the same, while pseudo-elements and combinators stay in place.
+    fn focus_string(&self) -> String {
+        let encoder = &self.options.focus_encoder;
+        let mut items: Vec<&str> = self.focus.iter().map(String::as_str).collect();
+        for chain in items.split_mut(|item| !encoder.is_pseudo_class(item)) {
+            chain.sort_unstable();
+        }
+
+        items.join("").trim().replace(' ', &encoder.space)
+    }
+
+    /// Encodes the pending combinator (see [`FocusEncoder::combinator`]), or
+    /// returns an empty string if there is none.
+    fn take_combinator(&mut self) -> String {
+        let combinator = self.options.focus_encoder.combinator(&self.focus_delim);
+
+        self.focus_delim.clear();
+        combinator
+    }
+
+    /// Remembers the selector of the current block for each of its classes.
+    fn record_source_selector(&mut self, selector: &str) {
+        let selector = selector.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        for class in self.raw_classes_spells_map.keys() {
+            let selectors = &mut self.classes.entry(class).selectors;
+            if !selectors.contains(&selector) {
+                selectors.push(selector.clone());
+            }
+        }
+    }
+
+    /// Adds a raw spell prefix for the current class.
+    fn push_raw_spell(&mut self, base_raw_spell: String) {
+        let bases = self
+            .rule_bases
+            .entry(self.current_class.clone())
+            .or_default();
+        if !bases.contains(&base_raw_spell) {
+            bases.push(base_raw_spell.clone());
+        }
+
+        // Each query of an `@media a, b` list is an area of its own
+        let raw_spells: Vec<String> = match &self.area {
+            Some(area) => media_queries(area)
+                .map(|query| format!("{query}{}{base_raw_spell}", self.options.area_separator))
+                .collect(),
+            None => vec![base_raw_spell],
+        };
+
+        let spelling = if self.class_spelling.is_empty() {
+            self.current_class.clone()
+        } else {
+            std::mem::take(&mut self.class_spelling)
+        };
+        self.rule_spellings
+            .entry(self.current_class.clone())
+            .or_insert(spelling);
+
+        self.classes.entry(&self.current_class);
+
+        // A selector list naming the same selector twice needs its prefix once
+        let prefixes = self
+            .raw_classes_spells_map
+            .entry(self.current_class.clone())
+            .or_default();
+        for raw_spell in raw_spells {
+            if !prefixes.contains(&raw_spell) {
+                prefixes.push(raw_spell);
+            }
+        }
+    }
+
+    /// Makes `components` the declarations of the current rule.
+    fn set_components(&mut self, components: Vec<(String, Range<usize>)>) {
+        self.component_spans.clear();
+        self.component_and_component_target_map.clear();
+
+        for (component, span) in components {
+            let span = self.offset + span.start..self.offset + span.end;
+            self.component_spans.push((component.clone(), span));
+            self.component_and_component_target_map.insert(component);
+        }
+    }
+
+    /// Scopes the current rule to an `@media` block nested in it: its
+    /// classes get the area of `condition`, combined with that of the
+    /// enclosing `@media` block, if any, and the block's `components` become
+    /// the rule's declarations. Returns `false`, leaving the rule as is,
+    /// inside `@container`, whose area a media condition cannot add to.
+    fn enter_nested_media(
+        &mut self,
+        condition: &str,
+        components: Vec<(String, Range<usize>)>,
+    ) -> bool {
+        let condition = match (&self.area, &self.media_condition) {
+            (None, _) => condition.to_string(),
+            (Some(_), Some(outer)) => and_media_conditions(outer, condition),
+            // A container area has no media part to add to
+            (Some(_), None) => return false,
+        };
+        let area = media_area(&condition, false);
+        let areas: Vec<&str> = media_queries(&area).collect();
+
+        let separator = &self.options.area_separator;
+        self.raw_classes_spells_map = self
+            .rule_bases
+            .iter()
+            .map(|(class, bases)| {
+                let prefixes = areas
+                    .iter()
+                    .flat_map(|area| {
+                        bases
+                            .iter()
+                            .map(move |base| format!("{area}{separator}{base}"))
+                    })
+                    .collect();
+                (class.clone(), prefixes)
+            })
+            .collect();
+        self.set_components(components);
+        true
+    }
+
+    /// Remembers the layer of the classes of the current rule.
+    fn record_layer(&mut self) {
+        if let Some(layer) = &self.layer {
+            for class in self.raw_classes_spells_map.keys() {
+                self.classes
+                    .entry(class)
+                    .layer
+                    .get_or_insert_with(|| layer.clone());
+            }
+        }
+    }
+
+    /// Remembers the current class as one that already is a spell.
+    fn record_existing_spell(&mut self) {
+        if !self.skipped_existing_spells.contains(&self.current_class) {
+            self.skipped_existing_spells
+                .push(self.current_class.clone());
+        }
+    }
+
+    /// Collects a `--name: value` declaration, keeping the first value of a
+    /// property and noting redefinitions with a different value.
+    fn record_custom_property(&mut self, declaration: &str) {
+        let Some((name, value)) = declaration.split_once(':') else {
+            return;
+        };
+        let value = strip_comments(value);
+        let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        self.insert_custom_property(name.trim().to_string(), value);
+    }
+
+    /// Adds a custom property unless it is already known.
+    fn insert_custom_property(&mut self, name: String, value: String) {
+        match self.custom_properties.get(&name) {
+            Some(existing) if *existing != value => self.notices.push(format!(
+                "Custom property '{name}' is redefined as '{value}', keeping '{existing}'."
+            )),
+            Some(_) => {}
+            None => {
+                self.custom_properties.insert(name, value);
+            }
+        }
+    }
+
+    /// Counts a token the parser ignored, or fails in strict mode.
+    fn record_dropped(
+        &mut self,
+        kind: String,
+        parser: &Parser<'_, '_>,
+    ) -> Result<(), GrimoireCssError> {
+        self.check_strict(&kind, parser)?;
+        *self.dropped_tokens.entry(kind).or_default() += 1;
+        Ok(())
+    }
+
+    /// Fails with `dropped` and the current location in
+    /// [`ParseOptions::strict`] mode.
+    fn check_strict(&self, dropped: &str, parser: &Parser<'_, '_>) -> Result<(), GrimoireCssError> {
+        if !self.options.strict {
+            return Ok(());
+        }
+
+        let (line, column) = self.source_location(parser);
+        Err(GrimoireCssError::InvalidInput(format!(
+            "Strict mode: dropped {dropped} at line {line}, column {column}."
+        )))
+    }
+
+    /// Counts the properties of the current block's declarations.
+    fn record_properties(&mut self) {
+        for component in &self.component_and_component_target_map {
+            let property = component
+                .split_once('=')
+                .map_or(component.as_str(), |(p, _)| p);
+            *self
+                .property_counts
+                .entry(property.to_string())
+                .or_default() += 1;
+        }
+    }
+
+    /// Takes over what a nested (e.g. `@media`) parse collected.
+    fn absorb_nested(&mut self, mut nested: ParserState) {
+        self.notices.append(&mut nested.notices);
+        self.stats.absorb(&nested.stats);
+
+        for (kind, count) in std::mem::take(&mut nested.dropped_tokens) {
+            *self.dropped_tokens.entry(kind).or_default() += count;
+        }
+        for (property, count) in std::mem::take(&mut nested.property_counts) {
+            *self.property_counts.entry(property).or_default() += count;
+        }
+
+        for class in nested.skipped_existing_spells.drain(..) {
+            if !self.skipped_existing_spells.contains(&class) {
+                self.skipped_existing_spells.push(class);
+            }
+        }
+
+        for (name, value) in std::mem::take(&mut nested.custom_properties) {
+            self.insert_custom_property(name, value);
+        }
+
+        self.failed_files.append(&mut nested.failed_files);
+        self.keyframes.append(&mut nested.keyframes);
+        self.at_rules.append(&mut nested.at_rules);
+
+        self.classes.absorb(std::mem::take(&mut nested.classes));
+    }
+}
+
+/// What parsing a single file collected, with spans relative to the file, as
+/// kept in a [`ParseCache`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct FileResult {
+    spells: TransmutedMap,
+    notices: Vec<String>,
+    stats: TransmutationStats,
+    classes: ClassRecords,
+    custom_properties: BTreeMap<String, String>,
+    keyframes: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+    at_rules: Vec<TransmutedAtRule>,
+    skipped_existing_spells: Vec<String>,
+    dropped_tokens: BTreeMap<String, usize>,
+    property_counts: BTreeMap<String, usize>,
+}
+
+impl FileResult {
+    /// Captures the result of a file parsed at `offset` of the bundle.
+    fn new(spells: &TransmutedMap, state: &ParserState, offset: usize) -> Self {
+        let mut classes = state.classes.clone();
+        classes.shift_spans(|span| span.start - offset..span.end - offset);
+
+        Self {
+            spells: spells.clone(),
+            notices: state.notices.clone(),
+            stats: TransmutationStats {
+                cached_files: 0,
+                ..state.stats.clone()
+            },
+            classes,
+            custom_properties: state.custom_properties.clone(),
+            keyframes: state.keyframes.clone(),
+            at_rules: state.at_rules.clone(),
+            skipped_existing_spells: state.skipped_existing_spells.clone(),
+            dropped_tokens: state.dropped_tokens.clone(),
+            property_counts: state.property_counts.clone(),
+        }
+    }
+
+    /// Turns a cached result back into what parsing the file at `offset`
+    /// would have produced.
+    fn restore(mut self, offset: usize, options: &ParseOptions) -> (TransmutedMap, ParserState) {
+        self.classes
+            .shift_spans(|span| span.start + offset..span.end + offset);
+
+        let state = ParserState {
+            offset,
+            options: options.clone(),
+            notices: self.notices,
+            stats: TransmutationStats {
+                cached_files: 1,
+                ..self.stats
+            },
+            classes: self.classes,
+            custom_properties: self.custom_properties,
+            keyframes: self.keyframes,
+            at_rules: self.at_rules,
+            skipped_existing_spells: self.skipped_existing_spells,
+            dropped_tokens: self.dropped_tokens,
+            property_counts: self.property_counts,
+            ..Default::default()
+        };
+
+        (self.spells, state)
+    }
+}
+
+/// Wall-clock budget shared by every stage of a single transmutation run.
+#[derive(Debug, Clone, Copy)]
+struct Deadline {
+    started: Instant,
+    budget: Duration,
+}
+
+impl Deadline {
+    fn new(started: Instant, budget: Duration) -> Self {
+        Self { started, budget }
+    }
+
+    /// Fails with a [`std::io::ErrorKind::TimedOut`] error when the budget is
+    /// exhausted.
+    /// `progress` describes what has been produced so far.
+    fn check(&self, progress: impl FnOnce() -> String) -> Result<(), GrimoireCssError> {
+        if self.started.elapsed() > self.budget {
+            return Err(GrimoireCssError::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "Transmutation timed out after {:.2?} (budget {:.2?}); partial result discarded: {}.",
+                    self.started.elapsed(),
+                    self.budget,
+                    progress()
+                ),
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `err` is the error of an exceeded [`RunLimits::timeout`].
+fn is_timeout(err: &GrimoireCssError) -> bool {
+    matches!(err, GrimoireCssError::Io(err) if err.kind() == std::io::ErrorKind::TimedOut)
+}
+
+/// Measures how long a transmutation took. `Instant` panics on
+/// `wasm32-unknown-unknown`, so there it always reports zero.
+#[derive(Debug, Clone, Copy)]
+struct Stopwatch(#[cfg(not(target_arch = "wasm32"))] Instant);
+
+impl Stopwatch {
+    fn start() -> Self {
+        Self(
+            #[cfg(not(target_arch = "wasm32"))]
+            Instant::now(),
+        )
+    }
+
+    fn elapsed(&self) -> Duration {
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.0.elapsed();
+        #[cfg(target_arch = "wasm32")]
+        return Duration::ZERO;
+    }
+}
+
+/// CSS read from several files, each kept in a string of its own.
+///
+/// Each file is parsed on its own (see [`process_bundle`]). Offsets are those
+/// the files would have one after another, so spans from every file can be
+/// told apart and located with [`SourceBundle::locate`].
+#[derive(Debug, Default)]
+struct SourceBundle {
+    files: Vec<SourceFile>,
+    /// Matched files left out as no stylesheet, with the reason.
+    skipped: Vec<(PathBuf, String)>,
+}
+
+/// One file of a [`SourceBundle`].
+#[derive(Debug)]
+struct SourceFile {
+    /// Where the file starts in the bundle.
+    offset: usize,
+    path: PathBuf,
+    /// The cleaned content.
+    content: String,
+}
+
+impl SourceBundle {
+    /// Adds a file after the others.
+    fn push(&mut self, path: PathBuf, content: String) {
+        let offset = self
+            .files
+            .last()
+            .map_or(0, |file| file.offset + file.content.len());
+        self.files.push(SourceFile {
+            offset,
+            path,
+            content,
+        });
+    }
+
+    /// Maps a byte range of the bundle back to its file and file-local range.
+    fn locate(&self, span: &Range<usize>) -> SourceSpan {
+        let index = self.files.partition_point(|file| file.offset <= span.start);
+
+        match index.checked_sub(1).map(|i| &self.files[i]) {
+            Some(file) => SourceSpan {
+                file: Some(file.path.to_string_lossy().into_owned()),
+                start: span.start - file.offset,
+                end: span.end - file.offset,
+            },
+            None => SourceSpan::from(span),
+        }
+    }
+
+    /// The content of each file, for [`ensure_transmuted`].
+    fn contents(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|file| file.content.as_str())
+    }
+}
+
+/// Reads and cleans multiple CSS files (paths mode).
+///
+/// Fails before reading anything if a file or all files together exceed the
+/// size limits.
+fn read_and_clean_files(
+    paths: &[PathBuf],
+    kind: InputKind,
+    exclude: &[Pattern],
+    limits: &RunLimits,
+    deadline: Option<Deadline>,
+    mut progress: Option<Progress<'_>>,
+) -> Result<SourceBundle, GrimoireCssError> {
+    let mut total_size = 0u64;
+
+    for path in paths {
+        let Ok(metadata) = fs::metadata(path) else {
+            continue;
+        };
+
+        if metadata.len() > limits.max_file_size {
+            return Err(GrimoireCssError::InvalidInput(format!(
+                "'{}' is {} bytes, over the limit of {} bytes per file",
+                path.display(),
+                metadata.len(),
+                limits.max_file_size
+            )));
+        }
+        total_size += metadata.len();
+    }
+
+    if total_size > limits.max_total_size {
+        return Err(GrimoireCssError::InvalidInput(format!(
+            "The {} matched files total {total_size} bytes, over the limit of {} bytes",
+            paths.len(),
+            limits.max_total_size
+        )));
+    }
+
+    let mut bundle = SourceBundle::default();
+    // Decompressed sizes count too, which the metadata above cannot tell
+    let mut read_size = 0u64;
+
+    let follow_imports = limits.follow_imports && kind == InputKind::Css;
+    // Grows as imports are found, which go right after the importing file
+    let mut paths = paths.to_vec();
+    let mut queued = HashSet::new();
+    if follow_imports {
+        paths.retain(|path| queued.insert(canonical_path(path)));
+    }
+
+    let mut index = 0;
+    while index < paths.len() {
+        let path = paths[index].clone();
+        index += 1;
+        if let Some(deadline) = deadline {
+            deadline.check(|| format!("read {} of {} files", index - 1, paths.len()))?;
+        }
+
+        // The extension is checked first, so a misnamed asset is never read
+        let read = match kind.extension_mismatch(&path) {
+            Some(reason) => Err(reason),
+            None => match read_css_file(&path, limits.max_file_size) {
+                Ok(content) => match InputKind::content_mismatch(&content) {
+                    Some(reason) => Err(reason),
+                    None => Ok(content),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    Err("is not UTF-8 text".to_string())
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                    return Err(GrimoireCssError::InvalidInput(format!(
+                        "'{}' {e}",
+                        path.display()
+                    )))
+                }
+                Err(e) => {
+                    return Err(GrimoireCssError::Io(std::io::Error::new(
+                        e.kind(),
+                        format!("Failed to read '{}': {}", path.display(), e),
+                    )))
+                }
+            },
+        };
+
+        let content = match read {
+            Ok(content) => content,
+            Err(reason) => match limits.non_css {
+                NonCssPolicy::Error => {
+                    return Err(GrimoireCssError::InvalidInput(format!(
+                        "'{}' is not {}: it {reason}.",
+                        path.display(),
+                        kind.noun()
+                    )))
+                }
+                NonCssPolicy::Skip => {
+                    let reason = format!("Not {}: it {reason}.", kind.noun());
+                    bundle.skipped.push((path.clone(), reason));
+                    continue;
+                }
+            },
+        };
+
+        read_size += content.len() as u64;
+        if read_size > limits.max_total_size {
+            return Err(GrimoireCssError::InvalidInput(format!(
+                "The {} matched files read to over the limit of {} bytes",
+                paths.len(),
+                limits.max_total_size
+            )));
+        }
+
+        let content = match kind {
+            InputKind::Css => clean_source(&content),
+            // `/*` means nothing in markup, blanking it would hide elements
+            InputKind::Html => content,
+        };
+
+        if follow_imports {
+            let imports = imported_paths(&path, &content, exclude)?;
+            let imports = imports
+                .into_iter()
+                .filter(|imported| queued.insert(canonical_path(imported)));
+            paths.splice(index..index, imports.collect::<Vec<_>>());
+        }
+
+        log::debug!("[{index}/{}] {}", paths.len(), path.display());
+        if let Some(progress) = progress.as_mut() {
+            progress(index, paths.len(), &path);
+        }
+        bundle.push(path, content);
+    }
+
+    Ok(bundle)
+}
+
+/// What the files read in paths mode are expected to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputKind {
+    Css,
+    Html,
+}
+
+impl InputKind {
+    /// The expected kind of file, for messages.
+    fn noun(self) -> &'static str {
+        match self {
+            Self::Css => "a stylesheet",
+            Self::Html => "an HTML document",
+        }
+    }
+
+    /// The extensions a directory is searched for.
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Self::Css => &["css"],
+            Self::Html => &["html", "htm"],
+        }
+    }
+
+    /// Why a file is obviously not of this kind by its name, if it is: for
+    /// stylesheets, the extension of another web asset. Checked before the
+    /// file is read.
+    fn extension_mismatch(self, path: &Path) -> Option<String> {
+        if self != Self::Css {
+            return None;
+        }
+
+        let name = path.file_name()?.to_string_lossy().to_ascii_lowercase();
+        let name = name.strip_suffix(".gz").unwrap_or(&name);
+        let (_, extension) = name.rsplit_once('.')?;
+        NON_CSS_EXTENSIONS
+            .contains(&extension)
+            .then(|| format!("has a .{extension} extension"))
+    }
+
+    /// Why read content is obviously not of this kind, if it is: binary
+    /// content (a NUL character). Cheap enough to run on every file.
+    fn content_mismatch(content: &str) -> Option<String> {
+        content
+            .contains('\0')
+            .then(|| "has binary content".to_string())
+    }
+}
+
+/// The two bytes every gzip stream starts with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reads a CSS file, transparently decompressing it when it has a `.gz`
+/// extension or starts with the gzip magic bytes.
+///
+/// Plain files are only read once and checked for valid UTF-8, same as
+/// `fs::read_to_string`. A file over `max_size` bytes fails with
+/// [`std::io::ErrorKind::InvalidInput`], as does decompression past
+/// `max_size` bytes, so a small archive cannot expand without bound.
+fn read_css_file(path: &Path, max_size: u64) -> std::io::Result<String> {
+    let size = fs::metadata(path)?.len();
+    if size > max_size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("is {size} bytes, over the limit of {max_size} bytes per file"),
+        ));
+    }
+
+    let bytes = fs::read(path)?;
+    let is_gzip = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gz"))
+        || bytes.starts_with(&GZIP_MAGIC);
+
+    if !is_gzip {
+        return String::from_utf8(bytes)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+    }
+
+    let mut content = Vec::new();
+    GzDecoder::new(bytes.as_slice())
+        .take(max_size.saturating_add(1))
+        .read_to_end(&mut content)?;
+
+    if content.len() as u64 > max_size {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("decompresses to over the limit of {max_size} bytes per file"),
+        ));
+    }
+
+    String::from_utf8(content).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Removes the last character of a string.
+fn remove_last_char(s: &str) -> &str {
+    s.char_indices()
+        .next_back()
+        .map(|(i, _)| &s[..i])
+        .unwrap_or(s)
+}
+
+/// Marker appended to a spell component whose declaration is `!important`.
+const IMPORTANT_MARKER: &str = "!";
+
+/// Checks whether `css` starts with an unquoted `url(...)` token.
+fn starts_unquoted_url(css: &str) -> bool {
+    css.get(..4)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("url("))
+        && !css[4..].trim_start().starts_with(['"', '\''])
+}
+
+/// Prepares a stylesheet for the bundle: BOM and comments are blanked out.
+fn clean_source(css: &str) -> String {
+    strip_comments(&blank_bom(css))
+}
+
+/// Blanks out a leading UTF-8 byte order mark, which would otherwise be read
+/// as part of the first selector. Offsets into the result stay valid.
+fn blank_bom(css: &str) -> Cow<'_, str> {
+    match css.strip_prefix('\u{feff}') {
+        Some(rest) => Cow::Owned(" ".repeat('\u{feff}'.len_utf8()) + rest),
+        None => Cow::Borrowed(css),
+    }
+}
+
+/// Blanks out `/* ... */` comments, leaving string literals and unquoted
+/// `url()` values untouched. Comments are replaced by spaces of the same
+/// byte length so offsets into the result still point into the source.
+fn strip_comments(css: &str) -> String {
+    let mut result = String::with_capacity(css.len());
+    let mut chars = css.char_indices().peekable();
+    let mut quote: Option<char> = None;
+
+    while let Some((i, c)) = chars.next() {
+        match quote {
+            Some(q) => {
+                result.push(c);
+                if c == '\\' {
+                    if let Some((_, escaped)) = chars.next() {
+                        result.push(escaped);
+                    }
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                result.push(c);
+            }
+            None if starts_unquoted_url(&css[i..]) => {
+                // Comments are not recognized inside unquoted url() tokens
+                let end = css[i..].find(')').map_or(css.len(), |e| i + e + 1);
+                result.push_str(&css[i..end]);
+                while chars.peek().is_some_and(|(j, _)| *j < end) {
+                    chars.next();
+                }
+            }
+            None if css[i..].starts_with("/*") => {
+                let end = css[i + 2..].find("*/").map_or(css.len(), |e| i + 2 + e + 2);
+                result.extend(std::iter::repeat_n(' ', end - i));
+                while chars.peek().is_some_and(|(j, _)| *j < end) {
+                    chars.next();
+                }
+            }
+            None => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Splits a trailing `!important` flag off a declaration value.
+///
+/// The flag is matched the way CSS does: case-insensitively and with optional
+/// whitespace between `!` and `important` and before the end of the value, so
+/// `red !important`, `red ! important ` and `red!IMPORTANT` all give `red`.
+fn split_important(value: &str) -> (&str, bool) {
+    let trimmed = value.trim_end();
+    let flag_start = trimmed.len().saturating_sub("important".len());
+
+    if let Some(flag) = trimmed.get(flag_start..) {
+        if flag.eq_ignore_ascii_case("important") {
+            if let Some(rest) = trimmed[..flag_start].trim_end().strip_suffix('!') {
+                return (rest.trim_end(), true);
+            }
+        }
+    }
+
+    (trimmed, false)
+}
+
+/// Builds a `component=target` spell component from a raw declaration.
+fn build_component(raw_component: &str, raw_target: &str) -> String {
+    let component = strip_comments(raw_component);
+
+    format!(
+        "{}={}",
+        component.trim().replace(' ', "_"),
+        normalize_target(raw_target)
+    )
+}
+
+/// Normalizes a raw declaration value into a spell target.
+///
+/// Comments are dropped and `!important` becomes the important marker.
+/// Outside of quoted strings whitespace runs collapse to one separator, which
+/// is removed next to parentheses and slashes and kept as exactly one after a
+/// comma. Quoted strings keep their content but are re-serialized in double
+/// quotes with CSS escaping (`content: '→'` and `content: "\2192"` both give
+/// `content="→"`), so the spell stays well-formed. Remaining spaces become `_`, the
+/// way Grimoire CSS encodes them, so function calls like `calc()` and `var()`
+/// keep every space their arguments need (e.g. around `-` in `calc()`).
+fn normalize_target(raw_target: &str) -> String {
+    let target = strip_comments(raw_target);
+    let (target, important) = split_important(&target);
+
+    let mut normalized = String::with_capacity(target.len() + 1);
+    let mut chars = target.trim().chars();
+    let mut pending_space = false;
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            pending_space = true;
+            continue;
+        }
+
+        if pending_space && !matches!(c, ',' | '/' | ')') && !normalized.ends_with(['(', '/']) {
+            normalized.push(' ');
+        }
+        pending_space = c == ',';
+
+        if matches!(c, '"' | '\'') {
+            let mut string = String::from(c);
+            let mut terminated = false;
+            while let Some(next) = chars.next() {
+                string.push(next);
+                if next == '\\' {
+                    string.extend(chars.next());
+                } else if next == c {
+                    terminated = true;
+                    break;
+                }
+            }
+            if terminated {
+                normalized.push_str(&canonical_string(&string));
+            } else {
+                normalized.push_str(&string);
+            }
+        } else {
+            normalized.push(c);
+        }
+    }
+
+    if important {
+        normalized.push_str(IMPORTANT_MARKER);
+    }
+
+    normalized.replace(' ', "_")
+}
+
+/// Re-serializes a terminated quoted CSS string in double quotes, resolving
+/// escapes that are not needed and escaping the ones that are.
+fn canonical_string(raw: &str) -> String {
+    let mut input = ParserInput::new(raw);
+    let mut parser = Parser::new(&mut input);
+    let Ok(value) = parser.expect_string_cloned() else {
+        return raw.to_string();
+    };
+
+    let mut canonical = String::with_capacity(raw.len());
+    match serialize_string(&value, &mut canonical) {
+        Ok(()) if parser.is_exhausted() => canonical,
+        _ => raw.to_string(),
+    }
+}
+
+/// Lowercases a spell target outside of quoted strings and `url()` contents.
+fn lowercase_value(value: &str) -> String {
+    let mut lowercased = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    let mut quote = None;
+    let mut in_url = false;
+
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(open) => {
+                lowercased.push(c);
+                if c == '\\' {
+                    lowercased.extend(chars.next());
+                } else if c == open {
+                    quote = None;
+                }
+            }
+            None if in_url => {
+                lowercased.push(c);
+                in_url = c != ')';
+            }
+            None => {
+                lowercased.push(c.to_ascii_lowercase());
+                if matches!(c, '"' | '\'') {
+                    quote = Some(c);
+                } else if c == '(' && lowercased.ends_with("url(") {
+                    in_url = true;
+                }
+            }
+        }
+    }
+
+    lowercased
+}
+
+/// Splits a media query list at the commas outside of parentheses.
+fn media_queries(list: &str) -> impl Iterator<Item = &str> {
+    let mut depth = 0usize;
+
+    list.split(move |c| {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        c == ',' && depth == 0
+    })
+    .map(str::trim)
+    .filter(|query| !query.is_empty())
+}
+
+/// The media query list matching both `outer` and `inner`, e.g. for a block
+/// of one nested in the other: each query of `outer` joined by `and` with
+/// each query of `inner`.
+fn and_media_conditions(outer: &str, inner: &str) -> String {
+    media_queries(outer)
+        .flat_map(|outer| media_queries(inner).map(move |inner| format!("{outer} and {inner}")))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The area of a media (or, with `container`, container) condition: one
+/// query per query of a list, joined by `,` until `push_raw_spell` gives each
+/// its own spell.
+fn media_area(condition: &str, container: bool) -> String {
+    let condition = canonical_media_condition(condition);
+    media_queries(&condition)
+        .map(|query| {
+            let query = query.replace(" ", "_");
+            if container {
+                format!("{CONTAINER_AREA_PREFIX}{query}")
+            } else {
+                query
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Media types and keywords of a media query, lowercased by
+/// [`lowercase_media_keywords`].
+const MEDIA_KEYWORDS: &[&str] = &[
+    "all", "print", "screen", "speech", "and", "not", "only", "or",
+];
+
+/// Lowercases the ASCII case-insensitive words of a media or container
+/// condition: media types, keywords, feature names and function names.
+/// Container names, custom media (`--name`) and feature values keep their
+/// case.
+fn lowercase_media_keywords(condition: &str) -> String {
+    let mut lowercased = String::with_capacity(condition.len());
+    // Whether each open parenthesis is past the `:` of its feature
+    let mut in_value: Vec<bool> = Vec::new();
+    let mut rest = condition;
+
+    while let Some(c) = rest.chars().next() {
+        let word_len = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+            .unwrap_or(rest.len());
+
+        if word_len == 0 {
+            match c {
+                '(' => in_value.push(false),
+                ')' => {
+                    in_value.pop();
+                }
+                ':' => {
+                    if let Some(value) = in_value.last_mut() {
+                        *value = true;
+                    }
+                }
+                _ => {}
+            }
+            lowercased.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        let (word, after) = rest.split_at(word_len);
+        let is_name = !word.starts_with("--") && !word.starts_with(|c: char| c.is_ascii_digit());
+        let lowercase = is_name
+            && (after.starts_with('(')
+                || match in_value.last() {
+                    Some(in_value) => !in_value,
+                    None => MEDIA_KEYWORDS.iter().any(|k| word.eq_ignore_ascii_case(k)),
+                });
+
+        if lowercase {
+            lowercased.push_str(&word.to_ascii_lowercase());
+        } else {
+            lowercased.push_str(word);
+        }
+        rest = after;
+    }
+
+    lowercased
+}
+
+/// Canonicalizes a media condition so semantically identical conditions
+/// produce the same area: its case-insensitive words are lowercased (see
+/// [`lowercase_media_keywords`]), whitespace is collapsed, `feature: value`
+/// pairs are spaced uniformly and the features of each query are sorted after
+/// its type.
+fn canonical_media_condition(condition: &str) -> String {
+    let condition = lowercase_media_keywords(condition);
+    let mut collapsed = String::with_capacity(condition.len());
+
+    for word in condition.split_whitespace() {
+        let joins_previous = collapsed.ends_with(['(', ':', ','])
+            || word.starts_with([')', ':', ','])
+            || collapsed.is_empty();
+
+        if !joins_previous {
+            collapsed.push(' ');
+        }
+        collapsed.push_str(word);
+    }
+
+    let spaced = collapsed.replace(':', ": ");
+
+    spaced
+        .split(',')
+        .map(|query| {
+            let (mut parts, mut features): (Vec<&str>, Vec<&str>) = query
+                .split(" and ")
+                .map(str::trim)
+                .filter(|part| !part.is_empty())
+                .partition(|part| !part.starts_with('('));
+
+            features.sort_unstable();
+            parts.extend(features);
+            parts.join(" and ")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Removes insignificant whitespace from an `An+B` argument (`2n + 1` -> `2n+1`),
+/// keeping an optional `of <selector>` clause intact.
+fn normalize_an_plus_b(argument: &str) -> String {
+    let argument = argument.trim();
+    let (an_plus_b, selector) = match argument.to_ascii_lowercase().find(" of ") {
+        Some(i) => (&argument[..i], Some(argument[i + 4..].trim())),
+        None => (argument, None),
+    };
+
+    let mut normalized: String = an_plus_b.chars().filter(|c| !c.is_whitespace()).collect();
+    if let Some(selector) = selector {
+        normalized.push_str(" of ");
+        normalized.push_str(selector);
+    }
+
+    normalized
+}
+
+/// Generates a map of spells based on parser state.
+fn generate_spells_map(state: &ParserState) -> TransmutedMap {
+    let mut spells_map = HashMap::new();
+
+    for (class, prefixes) in &state.raw_classes_spells_map {
+        let mut spells = HashSet::new();
+
+        for prefix in prefixes {
+            for component in &state.component_and_component_target_map {
+                let spell = if prefix.is_empty() {
+                    component.clone()
+                } else {
+                    format!("{prefix}{component}")
+                };
+                spells.insert(spell);
+            }
+        }
+        spells_map.insert(class.clone(), spells);
+    }
+
+    spells_map
+}
+
+/// Merges two HashMaps, concatenating values for duplicate keys. Spells keep
+/// their area prefix, so base and area-scoped spells never collapse together.
+fn merge_maps(map1: &mut TransmutedMap, map2: TransmutedMap) {
+    for (key, value) in map2 {
+        if let Some(existing_value) = map1.get_mut(&key) {
+            existing_value.extend(value);
+        } else {
+            map1.insert(key, value);
+        }
+    }
+}
+
+/// Converts a cssparser failure into an error carrying the byte offset.
+fn parse_error(offset: usize, err: ParseError<'_, ()>) -> GrimoireCssError {
+    GrimoireCssError::InvalidInput(format!(
+        "Failed to parse CSS at byte offset {offset}: {:?}",
+        err.kind
+    ))
+}
+
+/// Re-escapes an identifier the tokenizer unescaped, for use in a selector
+/// (e.g. `y.z` back to `y\.z`).
+fn escape_identifier(ident: &str) -> String {
+    let mut escaped = String::with_capacity(ident.len());
+    // Writing into a String cannot fail
+    let _ = serialize_identifier(ident, &mut escaped);
+    escaped
+}
+
+/// The canonical contents of an attribute selector: `name`, or `name`, the
+/// operator and the value as a quoted string, followed by an ` i`/` s` case
+/// modifier if given. Differences in spacing, quoting and modifier case thus
+/// never produce different focuses. `None` when the contents are no plain
+/// attribute selector (e.g. one with a namespace).
+fn canonical_attribute_selector(input: &mut Parser<'_, '_>) -> Option<String> {
+    let mut canonical = match input.next().ok()? {
+        Token::Ident(name) => name.to_string(),
+        _ => return None,
+    };
+
+    let operator = match input.next() {
+        Err(_) => return Some(canonical),
+        Ok(Token::Delim('=')) => "=",
+        Ok(Token::IncludeMatch) => "~=",
+        Ok(Token::DashMatch) => "|=",
+        Ok(Token::PrefixMatch) => "^=",
+        Ok(Token::SuffixMatch) => "$=",
+        Ok(Token::SubstringMatch) => "*=",
+        Ok(_) => return None,
+    };
+    canonical.push_str(operator);
+
+    match input.next().ok()? {
+        Token::Ident(value) | Token::QuotedString(value) => {
+            serialize_string(value, &mut canonical).ok()?;
+        }
+        _ => return None,
+    }
+
+    match input.next() {
+        Err(_) => return Some(canonical),
+        Ok(Token::Ident(modifier))
+            if modifier.eq_ignore_ascii_case("i") || modifier.eq_ignore_ascii_case("s") =>
+        {
+            canonical.push(' ');
+            canonical.push_str(&modifier.to_ascii_lowercase());
+        }
+        Ok(_) => return None,
+    }
+
+    input.next().is_err().then_some(canonical)
+}
+
+/// Adds the selector and one-based `(line, column)` of the rule being
+/// processed to an error Grimoire CSS returned for it, keeping its kind.
+fn with_rule_location(
+    err: GrimoireCssError,
+    selector: &str,
+    (line, column): (u32, u32),
+) -> GrimoireCssError {
+    let located = |message: String| {
+        format!("{message} (in rule '{selector}' at line {line}, column {column})")
+    };
+
+    match err {
+        GrimoireCssError::InvalidInput(message) => GrimoireCssError::InvalidInput(located(message)),
+        GrimoireCssError::InvalidSpellFormat(message) => {
+            GrimoireCssError::InvalidSpellFormat(located(message))
+        }
+        GrimoireCssError::InvalidPath(message) => GrimoireCssError::InvalidPath(located(message)),
+        GrimoireCssError::RuntimeError(message) => GrimoireCssError::RuntimeError(located(message)),
+        GrimoireCssError::Io(err) => {
+            GrimoireCssError::Io(std::io::Error::new(err.kind(), located(err.to_string())))
+        }
+        GrimoireCssError::Serde(err) => GrimoireCssError::Serde(
+            <serde_json::Error as serde::de::Error>::custom(located(err.to_string())),
+        ),
+        err => err,
+    }
+}
+
+/// Consumes the rest of the block opened by the last token.
+fn skip_nested_block(parser: &mut Parser<'_, '_>, offset: usize) -> Result<(), GrimoireCssError> {
+    let block_offset = offset + parser.position().byte_index();
+
+    parser
+        .parse_nested_block(|input| {
+            while input.next().is_ok() {}
+            Ok::<(), ParseError<'_, ()>>(())
+        })
+        .map_err(|e| parse_error(block_offset, e))
+}
+
+/// Returns the contents of the curly block that started at `start`,
+/// failing when the input ended before the block was closed.
+fn closed_block_contents<'i>(
+    parser: &Parser<'i, '_>,
+    start: SourcePosition,
+    offset: usize,
+) -> Result<&'i str, GrimoireCssError> {
+    parser.slice_from(start).strip_suffix('}').ok_or_else(|| {
+        GrimoireCssError::InvalidInput(format!(
+            "Unbalanced braces: block opened at byte offset {} is never closed",
+            offset + start.byte_index() - 1
+        ))
+    })
+}
+
+/// Whether an at-keyword starts a `@keyframes` rule, vendor prefixes included.
+fn is_keyframes_keyword(keyword: &str) -> bool {
+    strip_vendor_prefix(&keyword.to_ascii_lowercase()) == "keyframes"
+}
+
+/// Parses a `@keyframes` rule after its at-keyword into
+/// [`ParserState::keyframes`], so its keyframe selectors never become scrolls.
+fn parse_keyframes(
+    parser: &mut Parser<'_, '_>,
+    parser_state: &mut ParserState,
+) -> Result<(), GrimoireCssError> {
+    let mut name = String::new();
+    loop {
+        match parser.next() {
+            Ok(Token::Ident(ident)) | Ok(Token::QuotedString(ident)) => name = ident.to_string(),
+            Ok(Token::CurlyBracketBlock) => break,
+            // A `@keyframes` without a block declares nothing
+            Ok(Token::Semicolon) | Err(_) => return Ok(()),
+            Ok(_) => {}
+        }
+    }
+
+    let block_offset = parser_state.offset + parser.position().byte_index();
+    let options = &parser_state.options;
+    let frames = parser
+        .parse_nested_block(|input| {
+            let mut frames: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            let mut selector_start = input.position();
+
+            while let Ok(token) = input.next() {
+                if !matches!(token, Token::CurlyBracketBlock) {
+                    continue;
+                }
+
+                let selector = remove_last_char(input.slice_from(selector_start)).to_string();
+                let components = input.parse_nested_block(|block| {
+                    Ok::<_, ParseError<'_, ()>>(parse_declarations(block))
+                })?;
+                let components: Vec<String> = winning_declarations(components)
+                    .into_iter()
+                    .map(|(component, _)| options.normalize_component(component))
+                    .collect();
+
+                for frame in selector.split(',') {
+                    let frame = frame.trim().to_ascii_lowercase();
+                    if !frame.is_empty() {
+                        frames.entry(frame).or_default().extend(components.clone());
+                    }
+                }
+
+                selector_start = input.position();
+            }
+
+            Ok::<_, ParseError<'_, ()>>(frames)
+        })
+        .map_err(|e| parse_error(block_offset, e))?;
+
+    if !name.is_empty() {
+        parser_state.keyframes.insert(name, frames);
+    }
+
+    Ok(())
+}
+
+/// Parses a selectorless at-rule after its at-keyword into
+/// [`ParserState::at_rules`].
+fn parse_selectorless_at_rule(
+    parser: &mut Parser<'_, '_>,
+    parser_state: &mut ParserState,
+    name: String,
+) -> Result<(), GrimoireCssError> {
+    let prelude_start = parser.position();
+    loop {
+        match parser.next() {
+            Ok(Token::CurlyBracketBlock) => break,
+            Ok(Token::Semicolon) | Err(_) => return Ok(()),
+            Ok(_) => {}
+        }
+    }
+
+    let prelude = remove_last_char(parser.slice_from(prelude_start)).trim();
+    let prelude = (!prelude.is_empty()).then(|| prelude.to_string());
+
+    let block_offset = parser_state.offset + parser.position().byte_index();
+    let components = parser
+        .parse_nested_block(|input| Ok::<_, ParseError<'_, ()>>(parse_declarations(input)))
+        .map_err(|e| parse_error(block_offset, e))?;
+
+    let spells = winning_declarations(components)
+        .into_iter()
+        .map(|(component, _)| parser_state.options.normalize_component(component))
+        .collect();
+
+    parser_state.at_rules.push(TransmutedAtRule {
+        name,
+        prelude,
+        spells,
+    });
+
+    Ok(())
+}
+
+/// `component=target` spell components, each with the byte range of the
+/// declaration it was built from.
+type Components = Vec<(String, Range<usize>)>;
+
+/// The contents of a rule's block.
+#[derive(Default)]
+struct RuleBlock {
+    declarations: Components,
+    /// The condition and declarations of each `@media` block nested in the
+    /// block (CSS nesting), with the conditions of nested ones joined by
+    /// `and`.
+    nested_media: Vec<(String, Components)>,
+    /// The other at-rules nested in the block, e.g. `@container`, which
+    /// are left out.
+    dropped_at_rules: Vec<String>,
+}
+
+/// Parses the declarations of a block into `component=target` spell components,
+/// each with the byte range of the declaration it was built from. Nested
+/// at-rules are left out, see [`parse_rule_block`].
+fn parse_declarations(input: &mut Parser<'_, '_>) -> Vec<(String, Range<usize>)> {
+    parse_rule_block(input).declarations
+}
+
+/// Parses the declarations of a block into `component=target` spell components,
+/// each with the byte range of the declaration it was built from.
+fn parse_rule_block(input: &mut Parser<'_, '_>) -> RuleBlock {
+    let mut block = RuleBlock::default();
+    let components = &mut block.declarations;
+    let mut start_decl_pos: SourcePosition = input.position();
+    // Only the first top-level colon separates property and value; colons
+    // inside functions and blocks are never seen at this level
+    let mut colon_pos: Option<SourcePosition> = None;
+
+    while let Ok(inner_token) = input.next() {
+        match inner_token {
+            Token::Colon => {
+                colon_pos.get_or_insert(input.position());
+            }
+            Token::AtKeyword(name) if colon_pos.is_none() => {
+                let name = format!("@{}", name.to_ascii_lowercase());
+                let prelude_start = input.position();
+                let mut opened = false;
+                while let Ok(token) = input.next() {
+                    match token {
+                        Token::CurlyBracketBlock => {
+                            opened = true;
+                            break;
+                        }
+                        Token::Semicolon => break,
+                        _ => {}
+                    }
+                }
+
+                if name != "@media" {
+                    if opened {
+                        let _ = input.parse_nested_block(|_| Ok::<_, ParseError<'_, ()>>(()));
+                    }
+                    block.dropped_at_rules.push(name);
+                } else if opened {
+                    let condition = remove_last_char(input.slice_from(prelude_start))
+                        .trim()
+                        .to_string();
+                    if let Ok(nested) = input.parse_nested_block(|nested| {
+                        Ok::<_, ParseError<'_, ()>>(parse_rule_block(nested))
+                    }) {
+                        block
+                            .nested_media
+                            .extend(nested.nested_media.into_iter().map(|(inner, components)| {
+                                (and_media_conditions(&condition, &inner), components)
+                            }));
+                        block.nested_media.push((condition, nested.declarations));
+                        block.dropped_at_rules.extend(nested.dropped_at_rules);
+                    }
+                }
+
+                start_decl_pos = input.position();
+                colon_pos = None;
+            }
+            Token::Semicolon => {
+                let declaration = remove_last_char(input.slice_from(start_decl_pos));
+                components.extend(declaration_component(
+                    declaration,
+                    start_decl_pos,
+                    colon_pos,
+                ));
+
+                start_decl_pos = input.position();
+                colon_pos = None;
+            }
+            _ => {}
+        }
+    }
+
+    // The last declaration of a block may omit its semicolon
+    components.extend(declaration_component(
+        input.slice_from(start_decl_pos),
+        start_decl_pos,
+        colon_pos,
+    ));
+
+    block
+}
+
+/// Builds the spell component of a single declaration (without its `;`) that
+/// starts at `start` and has its colon just before `colon`.
+fn declaration_component(
+    declaration: &str,
+    start: SourcePosition,
+    colon: Option<SourcePosition>,
+) -> Option<(String, Range<usize>)> {
+    let colon_offset = colon?.byte_index().checked_sub(start.byte_index())?;
+    if colon_offset == 0 {
+        return None;
+    }
+
+    // `get` rather than indexing, so a position off a char boundary (which
+    // the tokenizer never yields) drops the declaration instead of panicking
+    let component = remove_last_char(declaration.get(..colon_offset)?);
+    let target = declaration.get(colon_offset..)?;
+
+    let span_start = start.byte_index() + declaration.len() - declaration.trim_start().len();
+    let span_end = start.byte_index() + declaration.trim_end().len();
+
+    Some((build_component(component, target), span_start..span_end))
+}
+
+/// Keeps only the declaration that wins for each property of a block: the
+/// last one, unless an earlier one is `!important` and the later one is not.
+fn winning_declarations(components: Vec<(String, Range<usize>)>) -> Vec<(String, Range<usize>)> {
+    let property = |component: &str| {
+        component
+            .split_once('=')
+            .map_or(component, |(p, _)| p)
+            .to_string()
+    };
+    let mut winners: Vec<(String, Range<usize>)> = Vec::with_capacity(components.len());
+
+    for (component, span) in components {
+        let name = property(&component);
+        match winners.iter().position(|(c, _)| property(c) == name) {
+            Some(i) => {
+                if component.ends_with(IMPORTANT_MARKER)
+                    || !winners[i].0.ends_with(IMPORTANT_MARKER)
+                {
+                    winners.remove(i);
+                    winners.push((component, span));
+                }
+            }
+            None => winners.push((component, span)),
+        }
+    }
+
+    winners
+}
+
+/// Processes CSS input and generates raw spells.
+///
+/// Identifier escapes are resolved for scroll names, which are class names as
+/// they appear in markup (`.w-1\/2` becomes `w-1/2`, `.md\:flex` becomes
+/// `md:flex`), but re-escaped where an identifier ends up in a focus, which
+/// is a selector again (`.a .y\.z` keeps `{_.y\.z}`). The classes of a
+/// compound selector are joined with `.`, so `.btn.primary` and
+/// `.btn\.primary` share the scroll `btn.primary`.
+fn process_css_into_raw_spells(
+    css_input: &str,
+    parser_state: &mut ParserState,
+) -> Result<TransmutedMap, GrimoireCssError> {
+    let mut result: TransmutedMap = HashMap::new();
+    let mut parser_input = ParserInput::new(css_input);
+    let mut parser = Parser::new(&mut parser_input);
+    let mut last_was_whitespace = false;
+
+    loop {
+        if parser_state.limit_reached() {
+            return Ok(result);
+        }
+
+        let token_start = parser.position();
+        let token = match parser.next_including_whitespace() {
+            Ok(token) => token,
+            Err(err) => {
+                if !matches!(err.kind, BasicParseErrorKind::EndOfInput) {
+                    parser_state.notices.push(format!(
+                        "Stopped parsing at byte offset {}: {:?}",
+                        parser_state.offset + parser.position().byte_index(),
+                        err.kind
+                    ));
+                }
+                break;
+            }
+        };
+
+        let after_whitespace = std::mem::replace(
+            &mut last_was_whitespace,
+            matches!(token, Token::WhiteSpace(_)),
+        );
+
+        if parser_state.selector_start.is_none()
+            && parser_state.started_media_pos.is_none()
+            && parser_state.started_layer_pos.is_none()
+            && !matches!(
+                token,
+                Token::WhiteSpace(_)
+                    | Token::Semicolon
+                    | Token::CurlyBracketBlock
+                    | Token::AtKeyword(_)
+            )
+        {
+            parser_state.selector_start = Some(token_start);
+        }
+
+        // Whitespace between two parts of a selector is the descendant combinator
+        if after_whitespace
+            && !parser_state.current_class.is_empty()
+            && parser_state.focus_delim.is_empty()
+            && matches!(
+                token,
+                Token::Ident(_)
+                    | Token::IDHash(_)
+                    | Token::Colon
+                    | Token::SquareBracketBlock
+                    | Token::Delim('.' | '*')
+            )
+        {
+            parser_state.focus_delim = DESCENDANT_COMBINATOR.to_string();
+        }
+
+        match token {
+            Token::Ident(cow_rc_str) => {
+                if parser_state.class_started
+                    && (parser_state.current_class.is_empty()
+                        || parser_state.current_class.ends_with('.'))
+                {
+                    parser_state.current_class.push_str(cow_rc_str);
+                    if parser_state.class_spelling.is_empty() {
+                        parser_state.class_spelling.push('.');
+                    }
+                    let ident = escape_identifier(cow_rc_str);
+                    parser_state.class_spelling.push_str(&ident);
+                    parser_state.class_started = false;
+                } else if parser_state.effect_started {
+                    parser_state.push_pseudo(cow_rc_str);
+                    parser_state.effects.push(cow_rc_str.to_string());
+                } else if parser_state.class_started && parser_state.universal_operand() {
+                    let ident = escape_identifier(cow_rc_str);
+                    if let Some(operand) = parser_state.focus.last_mut() {
+                        operand.push_str(&format!(".{ident}"));
+                    }
+                    parser_state.class_started = false;
+                } else if !parser_state.focus_delim.is_empty() {
+                    // Keep the dot so `> .title` and `> title` stay distinct
+                    let dot = if parser_state.class_started { "." } else { "" };
+                    let combinator = parser_state.take_combinator();
+                    let ident = escape_identifier(cow_rc_str);
+                    parser_state.focus.push(format!("{combinator}{dot}{ident}"));
+                    parser_state.class_started = false;
+                } else if !parser_state.current_class.is_empty() {
+                    let space = &parser_state.options.focus_encoder.space;
+                    parser_state.focus.push(format!("{space}{cow_rc_str}"));
+                } else {
+                    // This is a tag selector
+                    parser_state.current_class = parser_state.element_scroll(cow_rc_str);
+                }
+            }
+            Token::IDHash(id) => {
+                if parser_state.current_class.is_empty() {
+                    // IDs become class-like keys, prefixed with `#`
+                    parser_state.current_class = format!("#{id}");
+                } else {
+                    let combinator = parser_state.take_combinator();
+                    parser_state.focus.push(format!("{combinator}#{id}"));
+                }
+            }
+            Token::AtKeyword(cow_rc_str)
+                if cow_rc_str.eq_ignore_ascii_case("media")
+                    || cow_rc_str.eq_ignore_ascii_case("container") =>
+            {
+                parser_state.container_query = cow_rc_str.eq_ignore_ascii_case("container");
+                parser_state.started_media_pos = Some(parser.position());
+            }
+            Token::AtKeyword(cow_rc_str) if cow_rc_str.eq_ignore_ascii_case("layer") => {
+                parser_state.started_layer_pos = Some(parser.position());
+            }
+            Token::AtKeyword(cow_rc_str) if is_keyframes_keyword(cow_rc_str) => {
+                parse_keyframes(&mut parser, parser_state)?;
+            }
+            Token::AtKeyword(cow_rc_str)
+                if SELECTORLESS_AT_RULES
+                    .iter()
+                    .any(|name| cow_rc_str.eq_ignore_ascii_case(name)) =>
+            {
+                let name = cow_rc_str.to_ascii_lowercase();
+                parse_selectorless_at_rule(&mut parser, parser_state, name)?;
+            }
+            // `@charset "utf-8";` only declares the encoding
+            Token::AtKeyword(cow_rc_str) if cow_rc_str.eq_ignore_ascii_case("charset") => {
+                while !matches!(parser.next(), Ok(Token::Semicolon) | Err(_)) {}
+            }
+            Token::Delim(d) => match d.to_string().as_str() {
+                "." => {
+                    parser_state.class_started = true;
+                    // `.btn.primary`: a dot glued to the class continues a compound selector
+                    let compound = !after_whitespace
+                        && !parser_state.current_class.is_empty()
+                        && !parser_state.current_class.ends_with('.')
+                        && parser_state.focus.is_empty()
+                        && parser_state.focus_delim.is_empty()
+                        && !parser_state.effect_started;
+
+                    if compound && parser_state.current_class == parser_state.element_scroll("*") {
+                        // `*.btn` selects the same as `.btn`
+                        parser_state.current_class.clear();
+                    } else if compound {
+                        if parser_state.class_spelling.is_empty() {
+                            parser_state.class_spelling = parser_state.current_class.clone();
+                        }
+                        parser_state.current_class.push('.');
+                        parser_state.class_spelling.push('.');
+                    } else if !after_whitespace && parser_state.universal_operand() {
+                        // `.a *.b`: the class is part of the universal operand
+                    } else if !parser_state.current_class.is_empty()
+                        && parser_state.focus_delim.is_empty()
+                    {
+                        let focus_str = parser_state.focus_string();
+
+                        let base_raw_spell = if focus_str.is_empty() {
+                            String::new()
+                        } else {
+                            parser_state.options.focus_encoder.focus(&focus_str)
+                        };
+
+                        parser_state.push_raw_spell(base_raw_spell);
+
+                        parser_state.focus.clear();
+                        parser_state.effects.clear();
+                        parser_state.current_class.clear();
+                        parser_state.focus_delim.clear();
+                    }
+                }
+                ">" | "+" | "~" => parser_state.focus_delim = d.to_string(),
+                "*" => {
+                    if parser_state.current_class.is_empty() {
+                        // The standalone universal selector is a scroll of its own
+                        parser_state.current_class = parser_state.element_scroll("*");
+                    } else {
+                        // The operand of a combinator, e.g. `.a > *` or `.a *`
+                        let combinator = parser_state.take_combinator();
+                        parser_state.focus.push(format!("{combinator}*"));
+                    }
+                }
+                _ => {
+                    let kind = format!("delim '{d}'");
+                    parser_state.record_dropped(kind, &parser)?;
+                }
+            },
+            Token::Colon => {
+                // `.a: :hover`: the colon before the whitespace leads nowhere
+                if after_whitespace && parser_state.colons > 0 {
+                    parser_state.check_strict("dangling ':'", &parser)?;
+                    parser_state.notices.push(format!(
+                        "Dropped dangling ':' before byte offset {}.",
+                        parser_state.offset + token_start.byte_index()
+                    ));
+                    parser_state.colons = 0;
+                }
+                parser_state.effect_started = true;
+                parser_state.colons += 1;
+            }
+            // Commas inside functional pseudo-classes are skipped with their
+            // block, so this one always ends an item of a selector list
+            Token::Comma => {
+                let focus_str = parser_state.focus_string();
+
+                let base_raw_spell = if focus_str.is_empty() {
+                    String::new()
+                } else {
+                    parser_state.options.focus_encoder.focus(&focus_str)
+                };
+
+                parser_state.push_raw_spell(base_raw_spell);
+
+                parser_state.focus.clear();
+                parser_state.effects.clear();
+                parser_state.current_class.clear();
+                parser_state.class_started = false;
+                parser_state.focus_delim.clear();
+                parser_state.effect_started = false;
+                parser_state.colons = 0;
+            }
+            Token::CloseCurlyBracket => {
+                return Err(GrimoireCssError::InvalidInput(format!(
+                    "Unbalanced braces: unexpected '}}' at byte offset {}",
+                    parser_state.offset + parser.position().byte_index() - 1
+                )));
+            }
+            Token::SquareBracketBlock => {
+                let start_pos = parser.position();
+                let block_offset = parser_state.offset + start_pos.byte_index();
+
+                let canonical = parser
+                    .parse_nested_block(|input| {
+                        let canonical = canonical_attribute_selector(input);
+                        while input.next().is_ok() {}
+                        Ok::<_, ParseError<'_, ()>>(canonical)
+                    })
+                    .map_err(|e| parse_error(block_offset, e))?;
+
+                // Anything but a plain attribute selector is kept as written
+                let encoder = &parser_state.options.focus_encoder;
+                let squared_focus = match canonical {
+                    Some(attribute) => encoder.attribute(&attribute),
+                    None => encoder.attribute(remove_last_char(parser.slice_from(start_pos))),
+                };
+
+                let combinator = parser_state.take_combinator();
+                parser_state
+                    .focus
+                    .push(format!("{combinator}{squared_focus}"));
+            }
+            Token::CurlyBracketBlock => {
+                if let Some(deadline) = parser_state.deadline {
+                    deadline.check(|| format!("{} scrolls transmuted", result.len()))?;
+                }
+                parser_state.dropped_at_rule = false;
+
+                if let Some(start_media_pos) = parser_state.started_media_pos {
+                    let condition = remove_last_char(parser.slice_from(start_media_pos));
+                    let area = media_area(condition, parser_state.container_query);
+                    let media_condition =
+                        (!parser_state.container_query).then(|| condition.trim().to_string());
+                    parser_state.started_media_pos = None;
+
+                    let layer = parser_state.layer.clone();
+                    let res = process_nested_rules(
+                        &mut parser,
+                        parser_state,
+                        Some(area),
+                        media_condition,
+                        layer,
+                    )?;
+                    merge_maps(&mut result, res);
+                } else if let Some(start_layer_pos) = parser_state.started_layer_pos.take() {
+                    let name = remove_last_char(parser.slice_from(start_layer_pos)).trim();
+
+                    // Anonymous layers have no name to add
+                    let layer = match &parser_state.layer {
+                        _ if name.is_empty() => parser_state.layer.clone(),
+                        Some(outer) => Some(format!("{outer}.{name}")),
+                        None => Some(name.to_string()),
+                    };
+
+                    let area = parser_state.area.clone();
+                    let media_condition = parser_state.media_condition.clone();
+                    let res = process_nested_rules(
+                        &mut parser,
+                        parser_state,
+                        area,
+                        media_condition,
+                        layer,
+                    )?;
+                    merge_maps(&mut result, res);
+                } else if !parser_state.focus_delim.is_empty() {
+                    // `.a > {`: the combinator has nothing to combine with
+                    let selector = parser_state
+                        .selector_start
+                        .map_or(parser_state.current_class.as_str(), |start| {
+                            parser.slice(start..token_start).trim()
+                        });
+                    parser_state.notices.push(format!(
+                        "Dropped '{selector}', it ends with the combinator '{}'.",
+                        parser_state.focus_delim
+                    ));
+                    let kind = "dangling combinator".to_string();
+                    parser_state.record_dropped(kind, &parser)?;
+                    parser_state.stats.rules += 1;
+                    skip_nested_block(&mut parser, parser_state.offset)?;
+                    parser_state.reset_selector();
+                } else {
+                    let is_spell = is_spell(
+                        &parser_state.current_class,
+                        parser_state.options.grimoire_config.as_ref(),
+                    )
+                    .map_err(|err| {
+                        let selector = parser_state
+                            .selector_start
+                            .map_or(parser_state.current_class.as_str(), |start| {
+                                parser.slice(start..token_start).trim()
+                            });
+                        with_rule_location(err, selector, parser_state.source_location(&parser))
+                    })?;
+                    parser_state.stats.rules += 1;
+
+                    if is_spell {
+                        let class =
+                            format!("class '{}' (already a spell)", parser_state.current_class);
+                        parser_state.check_strict(&class, &parser)?;
+                        parser_state.stats.skipped_spells += 1;
+                        parser_state.record_existing_spell();
+                        parser_state.notices.push(format!(
+                            "Class '{}' is already a spell, skipped.",
+                            parser_state.current_class
+                        ));
+                    } else {
+                        let focus_str = parser_state.focus_string();
+
+                        let base_raw_spell = if focus_str.is_empty() {
+                            String::new()
+                        } else {
+                            parser_state.options.focus_encoder.focus(&focus_str)
+                        };
+
+                        parser_state.push_raw_spell(base_raw_spell);
+
+                        let block_start = parser.position();
+                        let mut custom_only = false;
+                        let mut empty = false;
+                        let mut nested_media = Vec::new();
+                        if parser_state.names_only {
+                            skip_nested_block(&mut parser, parser_state.offset)?;
+                        } else {
+                            let block = parser
+                                .parse_nested_block(|input| {
+                                    Ok::<_, ParseError<'_, ()>>(parse_rule_block(input))
+                                })
+                                .map_err(|e| {
+                                    parse_error(parser_state.offset + block_start.byte_index(), e)
+                                })?;
+                            for name in block.dropped_at_rules {
+                                let selector = parser_state
+                                    .selector_start
+                                    .map_or(parser_state.current_class.as_str(), |start| {
+                                        parser.slice(start..token_start).trim()
+                                    });
+                                parser_state.notices.push(format!(
+                                    "Dropped '{name}' nested in '{selector}', only @media \
+                                     blocks can be nested in a rule."
+                                ));
+                                parser_state.record_dropped(name, &parser)?;
+                            }
+                            // Custom properties are collected on their own, not as spells
+                            let (custom, components): (Vec<_>, Vec<_>) = block
+                                .declarations
+                                .into_iter()
+                                .partition(|(component, _)| component.starts_with("--"));
+                            let components = parser_state.options.transmutable(components);
+                            nested_media = block
+                                .nested_media
+                                .into_iter()
+                                .map(|(condition, components)| {
+                                    let components = components
+                                        .into_iter()
+                                        .filter(|(component, _)| !component.starts_with("--"))
+                                        .collect();
+                                    (condition, parser_state.options.transmutable(components))
+                                })
+                                .filter(|(_, components)| !components.is_empty())
+                                .collect();
+                            let declares = !components.is_empty() || !nested_media.is_empty();
+                            custom_only = !custom.is_empty() && !declares;
+                            empty = custom.is_empty() && !declares;
+
+                            for (_, span) in custom {
+                                parser_state.record_custom_property(&css_input[span]);
+                            }
+                            parser_state.set_components(components);
+                        }
+                        closed_block_contents(&parser, block_start, parser_state.offset)?;
+
+                        // Nor is a rule without any declarations left, e.g. `.a {}`
+                        if empty {
+                            let selector = parser_state
+                                .selector_start
+                                .map_or(parser_state.current_class.as_str(), |start| {
+                                    parser.slice(start..token_start).trim()
+                                });
+                            parser_state.notices.push(format!(
+                                "Dropped '{selector}', its block has no declarations to transmute."
+                            ));
+                            let kind = "empty rule".to_string();
+                            parser_state.record_dropped(kind, &parser)?;
+                        }
+
+                        // A rule holding only custom properties (e.g. `:root`) is no scroll
+                        if !custom_only && !empty {
+                            let selector = parser_state
+                                .selector_start
+                                .map(|start| parser.slice(start..token_start));
+                            let classes = flush_rule(parser_state, &mut result, selector);
+                            parser_state.stats.selectors += classes;
+                            parser_state.selectors_flushed += classes;
+
+                            // `.a { @media (...) { ... } }` adds spells under that area
+                            for (condition, components) in nested_media {
+                                if parser_state.enter_nested_media(&condition, components) {
+                                    flush_rule(parser_state, &mut result, selector);
+                                    continue;
+                                }
+                                parser_state.notices.push(format!(
+                                    "Dropped '@media {condition}' nested in a rule inside \
+                                     @container, the two conditions make no single area."
+                                ));
+                                let kind = "@media in @container".to_string();
+                                parser_state.record_dropped(kind, &parser)?;
+                            }
+                        }
+                    }
+
+                    parser_state.reset_selector();
+                }
+            }
+            Token::Semicolon => {
+                // A stray top-level `;` ends whatever selector was pending;
+                // `@layer a, b;` only declares the layer order
+                if parser_state.started_layer_pos.is_none()
+                    && (!parser_state.current_class.is_empty()
+                        || !parser_state.raw_classes_spells_map.is_empty())
+                {
+                    parser_state.check_strict("selector without a block", &parser)?;
+                    parser_state.notices.push(format!(
+                        "Dropped selector without a block before byte offset {}.",
+                        parser_state.offset + parser.position().byte_index() - 1
+                    ));
+                }
+                parser_state.started_media_pos = None;
+                parser_state.started_layer_pos = None;
+                parser_state.dropped_at_rule = false;
+                parser_state.reset_selector();
+            }
+            // Functional pseudo-classes in the selector, e.g. `:not(.disabled)`
+            Token::Function(t) if parser_state.effect_started => {
+                let fn_name = t.to_string();
+
+                let start_pos = parser.position();
+
+                skip_nested_block(&mut parser, parser_state.offset)?;
+
+                let slice = parser.slice_from(start_pos);
+                // Pseudo-class names are ASCII case-insensitive
+                let an_plus_b = fn_name
+                    .get(..4)
+                    .is_some_and(|prefix| prefix.eq_ignore_ascii_case("nth-"));
+                let argument = if an_plus_b {
+                    normalize_an_plus_b(remove_last_char(slice))
+                } else {
+                    // Keep the selector argument intact, only tidy its whitespace
+                    remove_last_char(slice)
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                };
+
+                parser_state.push_pseudo(&format!("{fn_name}({argument})"));
+                parser_state.effects.push(fn_name);
+            }
+            // At-rule preludes (`@media (min-width: 600px)`) are read as a whole later
+            _ if parser_state.started_media_pos.is_some()
+                || parser_state.started_layer_pos.is_some()
+                || parser_state.dropped_at_rule => {}
+            other => {
+                parser_state.dropped_at_rule = matches!(other, Token::AtKeyword(_));
+                if let Some(kind) = dropped_token_kind(other) {
+                    parser_state.record_dropped(kind, &parser)?;
+                }
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Names the kind of a token the rule parser ignores, for the diagnostics;
+/// `None` for whitespace, comments and `<!--`/`-->`, which carry no meaning.
+fn dropped_token_kind(token: &Token<'_>) -> Option<String> {
+    let kind = match token {
+        Token::WhiteSpace(_) | Token::Comment(_) | Token::CDO | Token::CDC => return None,
+        Token::AtKeyword(name) => return Some(format!("@{}", name.to_ascii_lowercase())),
+        Token::Function(name) => return Some(format!("function {name}()")),
+        Token::Delim(c) => return Some(format!("delim '{c}'")),
+        Token::Ident(_) => "ident",
+        Token::Number { .. } => "number",
+        Token::Percentage { .. } => "percentage",
+        Token::Dimension { .. } => "dimension",
+        Token::Hash(_) | Token::IDHash(_) => "hash",
+        Token::QuotedString(_) | Token::BadString(_) => "string",
+        Token::UnquotedUrl(_) | Token::BadUrl(_) => "url",
+        Token::Colon => "colon",
+        Token::Semicolon => "semicolon",
+        Token::Comma => "comma",
+        Token::IncludeMatch
+        | Token::DashMatch
+        | Token::PrefixMatch
+        | Token::SuffixMatch
+        | Token::SubstringMatch => "attribute operator",
+        Token::ParenthesisBlock => "parenthesis block",
+        Token::SquareBracketBlock => "square bracket block",
+        Token::CurlyBracketBlock => "curly bracket block",
+        Token::CloseParenthesis | Token::CloseSquareBracket | Token::CloseCurlyBracket => {
+            "closing bracket"
+        }
+    };
+
+    Some(kind.to_string())
+}
+
+/// Generates the spells of the current rule from its raw spells and
+/// declarations, merges them into `result` and records where they came from.
+/// Returns how many classes the rule has.
+fn flush_rule(
+    parser_state: &mut ParserState,
+    result: &mut TransmutedMap,
+    selector: Option<&str>,
+) -> usize {
+    let spells_map = generate_spells_map(parser_state);
+    if parser_state.options.on_conflict != ConflictPolicy::Merge {
+        parser_state.record_spellings(&spells_map);
+    }
+    let classes = spells_map.len();
+    parser_state.stats.spells_generated += spells_map.values().map(HashSet::len).sum::<usize>();
+    parser_state.record_rule_repeats(&spells_map);
+    merge_maps(result, spells_map);
+    parser_state.record_properties();
+    parser_state.record_spell_spans();
+    parser_state.record_layer();
+    if let Some(selector) = selector {
+        parser_state.record_source_selector(selector);
+    }
+    if parser_state.options.spell_sources {
+        parser_state.record_spell_origins(selector);
+    }
+    if parser_state.options.shared_with {
+        parser_state.record_shared_with();
+    }
+
+    classes
+}
+
+/// Parses the rules inside the at-rule block opened by the last token with a
+/// fresh state scoped to `area` (of `media_condition`, see
+/// [`ParserState::media_condition`]) and `layer`, then clears the at-rule prelude.
+fn process_nested_rules(
+    parser: &mut Parser<'_, '_>,
+    parser_state: &mut ParserState,
+    area: Option<String>,
+    media_condition: Option<String>,
+    layer: Option<String>,
+) -> Result<TransmutedMap, GrimoireCssError> {
+    let start_nested_pos = parser.position();
+    let (line, column) = parser_state.source_location(parser);
+    skip_nested_block(parser, parser_state.offset)?;
+    let nested_css = closed_block_contents(parser, start_nested_pos, parser_state.offset)?;
+
+    let mut state = ParserState {
+        area,
+        media_condition,
+        layer,
+        deadline: parser_state.deadline,
+        offset: parser_state.offset + start_nested_pos.byte_index(),
+        line_offset: line - 1,
+        column_offset: column - 1,
+        names_only: parser_state.names_only,
+        options: parser_state.options.clone(),
+        selectors_flushed: parser_state.selectors_flushed,
+        ..Default::default()
+    };
+
+    let res = process_css_into_raw_spells(nested_css, &mut state)?;
+    parser_state.selectors_flushed = state.selectors_flushed;
+    parser_state.absorb_nested(state);
+    parser_state.reset_selector();
+
+    Ok(res)
+}
+
+/// Parses every file of the bundle independently and in parallel, so parser
+/// state never bleeds from one file into the next. Results are in file order.
+///
+/// With a `cache`, files whose content is unchanged since the cached run are
+/// not parsed again, and the cache is updated to hold exactly this run's files.
+/// With a [`ParseOptions::limit`], files are parsed one after another and the
+/// cache is left alone.
+fn parse_bundle_files(
+    bundle: &SourceBundle,
+    deadline: Option<Deadline>,
+    options: &ParseOptions,
+    mut cache: Option<&mut ParseCache>,
+) -> Result<Vec<(TransmutedMap, ParserState)>, GrimoireCssError> {
+    let files: Vec<_> = bundle
+        .files
+        .iter()
+        .map(|file| {
+            let path = file.path.to_string_lossy().into_owned();
+            (file.offset, file.content.as_str(), path)
+        })
+        .collect();
+
+    // A sample depends on every file before it, so it is neither parallel
+    // nor cached
+    if options.limit.is_some() {
+        let mut selectors_flushed = 0;
+        return files
+            .iter()
+            .map(|(offset, css, path)| {
+                let parsed = parse_file(css, *offset, path, deadline, options, selectors_flushed)?;
+                selectors_flushed = parsed.1.selectors_flushed;
+                Ok(parsed)
+            })
+            .collect();
+    }
+
+    if let Some(cache) = cache.as_deref_mut() {
+        cache.validate(options);
+    }
+    let hashes: Vec<Option<u64>> = files
+        .iter()
+        .map(|(_, css, _)| cache.is_some().then(|| content_hash(css)))
+        .collect();
+    let cached = cache.as_deref();
+
+    let parsed = files
+        .par_iter()
+        .zip(&hashes)
+        .map(|((offset, css, path), hash)| {
+            let hit = cached
+                .and_then(|cache| cache.files.get(path))
+                .filter(|file| Some(file.hash) == *hash);
+            if let Some(file) = hit {
+                return Ok(file.result.clone().restore(*offset, options));
+            }
+
+            parse_file(css, *offset, path, deadline, options, 0)
+        })
+        .collect::<Vec<_>>();
+
+    // Report the error of the earliest failing file, independent of scheduling
+    let parsed: Vec<_> = parsed.into_iter().collect::<Result<_, _>>()?;
+
+    if let Some(cache) = cache {
+        cache.files = files
+            .iter()
+            .zip(hashes)
+            .zip(&parsed)
+            // Failed files are parsed again next time
+            .filter(|(_, (_, state))| state.failed_files.is_empty())
+            .filter_map(|(((offset, _, path), hash), (map, state))| {
+                let result = FileResult::new(map, state, *offset);
+                Some((
+                    path.clone(),
+                    CachedFile {
+                        hash: hash?,
+                        result,
+                    },
+                ))
+            })
+            .collect();
+    }
+
+    Ok(parsed)
+}
+
+/// Parses one file of a bundle starting at `offset`, with
+/// `selectors_flushed` selectors already transmuted from the files before it.
+/// With [`ParseOptions::continue_on_error`], a file that fails to parse gives
+/// an empty result recording the failure.
+fn parse_file(
+    css: &str,
+    offset: usize,
+    path: &str,
+    deadline: Option<Deadline>,
+    options: &ParseOptions,
+    selectors_flushed: usize,
+) -> Result<(TransmutedMap, ParserState), GrimoireCssError> {
+    let mut state = ParserState {
+        deadline,
+        offset,
+        options: options.clone(),
+        selectors_flushed,
+        ..Default::default()
+    };
+
+    match process_css_into_raw_spells(css, &mut state) {
+        Ok(map) => Ok((map, state)),
+        // A timeout ends the whole run, whatever the option says
+        Err(err) if options.continue_on_error && !is_timeout(&err) => {
+            let failed = ParserState {
+                options: options.clone(),
+                failed_files: vec![(PathBuf::from(path), err.to_string())],
+                selectors_flushed,
+                ..Default::default()
+            };
+            Ok((TransmutedMap::new(), failed))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Parses every file of the bundle (see [`parse_bundle_files`]) and merges
+/// the results in file order.
+fn process_bundle(
+    bundle: &SourceBundle,
+    deadline: Option<Deadline>,
+    options: &ParseOptions,
+    cache: Option<&mut ParseCache>,
+) -> Result<(TransmutedMap, ParserState), GrimoireCssError> {
+    let mut result = TransmutedMap::new();
+    let mut parser_state = ParserState {
+        options: options.clone(),
+        failed_files: bundle.skipped.clone(),
+        ..Default::default()
+    };
+
+    for (map, state) in parse_bundle_files(bundle, deadline, options, cache)? {
+        merge_maps(&mut result, map);
+        parser_state.absorb_nested(state);
+    }
+
+    Ok((result, parser_state))
+}
+
+/// Fails when nothing was transmuted from `sources`, telling an
+/// empty input (nothing but whitespace and comments) apart from an input
+/// without any transmutable rules.
+fn ensure_transmuted<'a>(
+    mut sources: impl Iterator<Item = &'a str>,
+    processed_css: &TransmutedMap,
+) -> Result<(), GrimoireCssError> {
+    if !processed_css.is_empty() {
+        return Ok(());
+    }
+
+    let message = if sources.all(|source| clean_source(source).trim().is_empty()) {
+        "The input is empty, there is no CSS to transmute."
+    } else {
+        "There is nothing to transmute: the input has no rules that produce spells."
+    };
+
+    Err(GrimoireCssError::InvalidInput(message.into()))
+}
+
+/// Fails when every input file of `bundle` failed, either to parse (see
+/// [`ParseOptions::continue_on_error`]) or as no stylesheet (see
+/// [`NonCssPolicy::Skip`]), listing each with its reason.
+fn ensure_any_file_read(
+    bundle: &SourceBundle,
+    failed_files: &[(PathBuf, String)],
+) -> Result<(), GrimoireCssError> {
+    let files = bundle.files.len() + bundle.skipped.len();
+    if failed_files.len() < files {
+        return Ok(());
+    }
+
+    let reasons: Vec<String> = failed_files
+        .iter()
+        .map(|(path, reason)| format!("'{}': {reason}", path.display()))
+        .collect();
+    Err(GrimoireCssError::InvalidInput(format!(
+        "All {files} input files failed: {}",
+        reasons.join("; ")
+    )))
+}
+
+/// Whether a scroll name only has characters that are safe in a Grimoire CSS
+/// key: ASCII letters, digits, `-` and `_`, plus the `.`, `:`, `#` and `*`
+/// the transmutator itself puts into names (compound classes, pseudo-class,
+/// element and id scrolls).
+fn is_valid_scroll_name(name: &str) -> bool {
+    name.chars().all(is_scroll_name_char)
+}
+
+/// A character allowed in a scroll name, see [`is_valid_scroll_name`].
+fn is_scroll_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':' | '#' | '*')
+}
+
+/// Renames or drops the scrolls whose name is not valid, following
+/// [`ParseOptions::invalid_names`], and records a warning for each.
+fn apply_name_policy(
+    processed_css: TransmutedMap,
+    parser_state: &mut ParserState,
+) -> TransmutedMap {
+    let policy = parser_state.options.invalid_names;
+    if policy == InvalidNamePolicy::Keep {
+        return processed_css;
+    }
+
+    let mut result = TransmutedMap::with_capacity(processed_css.len());
+    // Sorted so warnings and merges do not depend on hash order
+    let mut processed_css: Vec<_> = processed_css.into_iter().collect();
+    processed_css.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    for (name, spells) in processed_css {
+        if name.is_empty() || is_valid_scroll_name(&name) {
+            result.entry(name).or_default().extend(spells);
+            continue;
+        }
+
+        let selector = parser_state
+            .classes
+            .get(&name)
+            .filter(|record| !record.selectors.is_empty())
+            .map(|record| record.selectors.join(", "));
+
+        if policy == InvalidNamePolicy::Sanitize {
+            let renamed: String = name
+                .chars()
+                .map(|c| if is_scroll_name_char(c) { c } else { '_' })
+                .collect();
+            parser_state.classes.rename(&name, Some(&renamed));
+            parser_state.warnings.push(NameWarning {
+                scroll: name,
+                selector,
+                message: format!("Renamed to '{renamed}'."),
+            });
+            result.entry(renamed).or_default().extend(spells);
+        } else {
+            parser_state.classes.rename(&name, None);
+            parser_state.warnings.push(NameWarning {
+                scroll: name,
+                selector,
+                message: "Skipped, the name is not a valid Grimoire CSS key.".into(),
+            });
+        }
+    }
+
+    result
+}
+
+/// Applies [`ParseOptions::on_conflict`] to scrolls that differently spelled
+/// selectors produced with different spells.
+fn apply_conflict_policy(
+    mut processed_css: TransmutedMap,
+    parser_state: &mut ParserState,
+) -> Result<TransmutedMap, GrimoireCssError> {
+    // Sorted so warnings and renames do not depend on hash order
+    let mut conflicts: Vec<_> = parser_state
+        .classes
+        .0
+        .iter_mut()
+        .map(|(name, record)| (name.clone(), std::mem::take(&mut record.spellings)))
+        .filter(|(_, spellings)| {
+            spellings
+                .iter()
+                .any(|(_, spells)| *spells != spellings[0].1)
+        })
+        .collect();
+    conflicts.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    for (name, spellings) in conflicts {
+        let written: Vec<&str> = spellings.iter().map(|(s, _)| s.as_str()).collect();
+
+        match parser_state.options.on_conflict {
+            ConflictPolicy::Merge => {}
+            ConflictPolicy::Warn => parser_state.warnings.push(NameWarning {
+                scroll: name,
+                selector: Some(written.join(", ")),
+                message: "Merged the different spells of differently spelled selectors.".into(),
+            }),
+            ConflictPolicy::Error => {
+                return Err(GrimoireCssError::InvalidInput(format!(
+                    "Selectors {} become the same scroll '{name}' with different spells.",
+                    written.join(" and ")
+                )))
+            }
+            ConflictPolicy::Rename => {
+                let first = written[0].to_string();
+                let mut suffix = 1;
+
+                for (spelling, spells) in spellings.iter().skip(1) {
+                    let renamed = loop {
+                        suffix += 1;
+                        let candidate = format!("{name}-{suffix}");
+                        if !processed_css.contains_key(&candidate) {
+                            break candidate;
+                        }
+                    };
+
+                    let record = parser_state.classes.get(&name).map(|record| ClassRecord {
+                        order: record.order,
+                        spell_spans: record
+                            .spell_spans
+                            .iter()
+                            .filter(|(spell, _)| spells.contains(*spell))
+                            .map(|(spell, span)| (spell.clone(), span.clone()))
+                            .collect(),
+                        ..Default::default()
+                    });
+                    if let Some(record) = record {
+                        parser_state.classes.0.insert(renamed.clone(), record);
+                    }
+                    parser_state.warnings.push(NameWarning {
+                        scroll: name.clone(),
+                        selector: Some(spelling.clone()),
+                        message: format!(
+                            "Renamed to '{renamed}', its spells differ from those of '{first}'."
+                        ),
+                    });
+                    processed_css.insert(renamed, spells.iter().cloned().collect());
+                }
+
+                // The first spelling keeps the spells no other one produced
+                if let Some(kept) = processed_css.get_mut(&name) {
+                    kept.retain(|spell| {
+                        spellings[0].1.contains(spell)
+                            || !spellings.iter().any(|(_, spells)| spells.contains(spell))
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(processed_css)
+}
+
+/// Builds the output structure from the merged spells map and what the
+/// parser collected along the way, resolving spans with `locate`.
+///
+/// With `include_source`, each scroll records the selectors it came from.
+fn build_transmuted(
+    processed_css: TransmutedMap,
+    mut parser_state: ParserState,
+    include_oneliner: bool,
+    include_source: bool,
+    locate: impl Fn(&Range<usize>) -> SourceSpan,
+) -> Result<Transmuted, GrimoireCssError> {
+    let processed_css = apply_conflict_policy(processed_css, &mut parser_state)?;
+    let processed_css = apply_name_policy(processed_css, &mut parser_state);
+
+    let mut transmuted = Transmuted {
+        version: SCHEMA_VERSION,
+        generator: GENERATOR.to_string(),
+        scrolls: Vec::with_capacity(processed_css.len()),
+        split_media: BTreeMap::new(),
+        responsive: BTreeMap::new(),
+        areas: BTreeMap::new(),
+        custom_properties: parser_state.custom_properties,
+        keyframes: parser_state.keyframes,
+        at_rules: parser_state.at_rules,
+        skipped_existing_spells: parser_state.skipped_existing_spells,
+        failed_files: parser_state.failed_files,
+        warnings: std::mem::take(&mut parser_state.warnings),
+        diagnostics: std::mem::take(&mut parser_state.dropped_tokens),
+        area_separator: parser_state.options.area_separator.clone(),
+        focus_encoder: parser_state.options.focus_encoder.clone(),
+        pretty_spells: parser_state.options.pretty_spells,
+        notices: Vec::new(),
+        source_map: BTreeMap::new(),
+        stats: parser_state.stats.clone(),
+        property_histogram: std::mem::take(&mut parser_state.property_counts),
+        timings: Timings::default(),
+    };
+
+    // Scrolls in source order, spells sorted, so the output is stable
+    let mut processed_css: Vec<_> = processed_css.into_iter().collect();
+    processed_css.sort_unstable_by(|(a, _), (b, _)| {
+        let position = |name| {
+            parser_state
+                .classes
+                .get(name)
+                .map_or(usize::MAX, |record| record.order)
+        };
+        position(a).cmp(&position(b)).then_with(|| a.cmp(b))
+    });
+
+    for (name, spells) in processed_css {
+        if !name.is_empty() {
+            let record = parser_state.classes.get(&name);
+            let mut spells_vec: Vec<String> = spells.into_iter().collect();
+            spells_vec.sort_unstable();
+
+            let oneliner = include_oneliner.then(|| {
+                if parser_state.options.pretty_spells {
+                    return pretty_oneliner(
+                        &spells_vec,
+                        &parser_state.options.area_separator,
+                        &parser_state.options.focus_encoder,
+                    );
+                }
+                let spans = record.map(|record| &record.spell_spans);
+                parser_state.options.oneliner_order.oneliner(
+                    &spells_vec,
+                    &parser_state.options.area_separator,
+                    &parser_state.options.focus_encoder,
+                    |spell| {
+                        spans
+                            .and_then(|spans| spans.get(spell))
+                            .map(|span| span.start)
+                    },
+                )
+            });
+
+            let source_selector = record
+                .filter(|record| include_source && !record.selectors.is_empty())
+                .map(|record| record.selectors.join(", "));
+
+            let layer = record.and_then(|record| record.layer.clone());
+
+            let shared_with = record
+                .map(|record| {
+                    let others = record.shared_with.iter();
+                    others.filter(|o| !o.is_empty()).cloned().collect()
+                })
+                .unwrap_or_default();
+
+            let duplicate_count = record
+                .and_then(|record| record.rule_repeats.values().max().copied())
+                .unwrap_or(1);
+
+            let origins = record.map(|record| &record.spell_origins);
+            let spell_sources = spells_vec
+                .iter()
+                .filter_map(|spell| {
+                    let (component, selector) = origins?.get(spell)?.clone();
+                    Some(SpellSource {
+                        spell: spell.clone(),
+                        component,
+                        selector,
+                    })
+                })
+                .collect();
+
+            transmuted.stats.scrolls += 1;
+            transmuted.stats.spells += spells_vec.len();
+            transmuted.scrolls.push(TransmutedClass {
+                name,
+                spells: spells_vec,
+                oneliner,
+                collapsed: false,
+                source_selector,
+                layer,
+                spell_sources,
+                shared_with,
+                duplicate_count,
+            });
+        }
+    }
+
+    // Logged here rather than where they arise, so notices of cached files
+    // are not lost
+    for notice in &parser_state.notices {
+        log::warn!("{notice}");
+    }
+    transmuted.notices = parser_state.notices;
+    transmuted.source_map = build_source_map(parser_state.classes, locate);
+
+    Ok(transmuted)
+}
+
+/// Resolves the recorded spell spans into a source map, skipping unnamed scrolls.
+fn build_source_map(
+    classes: ClassRecords,
+    locate: impl Fn(&Range<usize>) -> SourceSpan,
+) -> BTreeMap<String, BTreeMap<String, SourceSpan>> {
+    classes
+        .0
+        .into_iter()
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, record)| {
+            let spans = record
+                .spell_spans
+                .iter()
+                .map(|(spell, span)| (spell.clone(), locate(span)))
+                .collect();
+            (name, spans)
+        })
+        .collect()
+}
+
+/// Run the transmutation process on multiple CSS files and return the typed
+/// result, shaped by `options` (its `format` and `minify` do not apply).
+///
+/// Files matching any of [`TransmuteOptions::exclude`] are dropped after
+/// expansion and the run is guarded by [`TransmuteOptions::limits`].
+pub fn run_transmutation_to_struct_with(
+    args: Vec<String>,
+    options: &TransmuteOptions,
+    hooks: RunHooks<'_>,
+) -> Result<Transmuted, GrimoireCssError> {
+    let limits = &options.limits;
+    let deadline = limits.deadline();
+    let read_start = Stopwatch::start();
+    let all_css = read_inputs(
+        args,
+        InputKind::Css,
+        &options.exclude,
+        limits,
+        deadline,
+        hooks.progress,
+    )?;
+    let read = read_start.elapsed();
+
+    let parse_start = Stopwatch::start();
+    let (processed_css, parser_state) =
+        process_bundle(&all_css, deadline, &options.parse, hooks.cache)?;
+
+    ensure_any_file_read(&all_css, &parser_state.failed_files)?;
+    ensure_transmuted(all_css.contents(), &processed_css)?;
+
+    let mut transmuted = build_transmuted(
+        processed_css,
+        parser_state,
+        options.include_oneliner,
+        options.include_source,
+        |span| all_css.locate(span),
+    )?;
+    transmuted.timings.read = read;
+    transmuted.timings.parse = parse_start.elapsed();
+    log::debug!(
+        "Read in {read:.2?}, parsed in {:.2?}",
+        transmuted.timings.parse
+    );
+
+    Ok(transmuted)
+}
+
+/// Like [`run_transmutation_to_struct_with`], but keeps each input file's
+/// result separate instead of merging them. Files without any rules yield an
+/// empty result rather than an error. Files skipped by [`NonCssPolicy::Skip`]
+/// follow, each with an empty result listing it in
+/// [`Transmuted::failed_files`].
+pub fn run_transmutation_per_file_with(
+    args: Vec<String>,
+    options: &TransmuteOptions,
+    hooks: RunHooks<'_>,
+) -> Result<Vec<(PathBuf, Transmuted)>, GrimoireCssError> {
+    let limits = &options.limits;
+    let deadline = limits.deadline();
+    let all_css = read_inputs(
+        args,
+        InputKind::Css,
+        &options.exclude,
+        limits,
+        deadline,
+        hooks.progress,
+    )?;
+    let parsed = parse_bundle_files(&all_css, deadline, &options.parse, hooks.cache)?;
+    let failed_files: Vec<_> = parsed
+        .iter()
+        .flat_map(|(_, state)| state.failed_files.iter().cloned())
+        .chain(all_css.skipped.iter().cloned())
+        .collect();
+    ensure_any_file_read(&all_css, &failed_files)?;
+
+    let skipped = all_css.skipped.iter().map(|skipped| {
+        let parser_state = ParserState {
+            options: options.parse.clone(),
+            failed_files: vec![skipped.clone()],
+            ..Default::default()
+        };
+        (skipped.0.clone(), (TransmutedMap::new(), parser_state))
+    });
+
+    all_css
+        .files
+        .iter()
+        .map(|file| file.path.clone())
+        .zip(parsed)
+        .chain(skipped)
+        .map(|(path, (processed_css, parser_state))| {
+            let transmuted = build_transmuted(
+                processed_css,
+                parser_state,
+                options.include_oneliner,
+                options.include_source,
+                |span| all_css.locate(span),
+            )?;
+            Ok((path, transmuted))
+        })
+        .collect()
+}
+
+/// Expands the path patterns and reads every matched file (paths mode).
+fn read_inputs(
+    args: Vec<String>,
+    kind: InputKind,
+    exclude: &[String],
+    limits: &RunLimits,
+    deadline: Option<Deadline>,
+    progress: Option<Progress<'_>>,
+) -> Result<SourceBundle, GrimoireCssError> {
+    let cwd = limits.base_dir()?;
+
+    // Validate input
+    if args.is_empty() {
+        return Err(GrimoireCssError::InvalidInput(
+            "No CSS file patterns provided.".into(),
+        ));
+    }
+
+    // Expand file paths based on glob patterns
+    let exclude = exclude_patterns(&cwd, exclude, kind)?;
+    let expanded_paths = expand_file_paths(&cwd, &args, &exclude, kind)?;
+    if expanded_paths.is_empty() {
+        return Err(GrimoireCssError::InvalidPath(NO_FILES_MATCHED.into()));
+    }
+
+    // Read CSS files
+    read_and_clean_files(&expanded_paths, kind, &exclude, limits, deadline, progress)
+}
+
+/// Run the transmutation process on multiple CSS files.
+/// This is the main entry point for the paths mode.
+///
+/// See [`run_transmutation_with`] for the other options.
+pub fn run_transmutation(
+    args: Vec<String>,
+    include_oneliner: bool,
+) -> Result<(Duration, String), GrimoireCssError> {
+    let options = TransmuteOptions::new().include_oneliner(include_oneliner);
+    let (timings, data) = run_transmutation_with(args, &options, RunHooks::default())?;
+
+    Ok((timings.total, data))
+}
+
+/// Like [`run_transmutation`], with the output shaped by `options`, see
+/// [`run_transmutation_to_struct_with`]. Returns the [`Timings`] of the run
+/// along with the output.
+pub fn run_transmutation_with(
+    args: Vec<String>,
+    options: &TransmuteOptions,
+    hooks: RunHooks<'_>,
+) -> Result<(Timings, String), GrimoireCssError> {
+    let start_time = Stopwatch::start();
+
+    let transmuted = run_transmutation_to_struct_with(args, options, hooks)?;
+
+    serialize_timed(&transmuted, start_time, options.format, options.minify)
+}
+
+/// Serializes the result, completing its timings with the serialization and
+/// the total since `start_time`.
+fn serialize_timed(
+    transmuted: &Transmuted,
+    start_time: Stopwatch,
+    format: OutputFormat,
+    minify: bool,
+) -> Result<(Timings, String), GrimoireCssError> {
+    let serialize_start = Stopwatch::start();
+    let data = transmuted.serialize_as(format, minify)?;
+
+    let timings = Timings {
+        serialize: serialize_start.elapsed(),
+        total: start_time.elapsed(),
+        ..transmuted.timings
+    };
+
+    Ok((timings, data))
+}
+
+/// Transmutes several in-memory stylesheets, given as `(name, css)` pairs,
+/// into one merged result the way paths mode merges files. Each source is
+/// parsed on its own and source map spans name the source they point into.
+pub fn transmute_sources_to_struct(
+    sources: &[(String, String)],
+    include_oneliner: bool,
+    include_source: bool,
+    options: &ParseOptions,
+) -> Result<Transmuted, GrimoireCssError> {
+    let mut bundle = SourceBundle::default();
+    for (name, css) in sources {
+        bundle.push(PathBuf::from(name), clean_source(css));
+    }
+
+    let parse_start = Stopwatch::start();
+    let (processed_css, parser_state) = process_bundle(&bundle, None, options, None)?;
+
+    ensure_transmuted(bundle.contents(), &processed_css)?;
+
+    let mut transmuted = build_transmuted(
+        processed_css,
+        parser_state,
+        include_oneliner,
+        include_source,
+        |span| bundle.locate(span),
+    )?;
+    transmuted.timings.parse = parse_start.elapsed();
+
+    Ok(transmuted)
+}
+
+/// Transmutes CSS content and returns the typed result.
+pub fn transmute_content_to_struct(
+    css_content: &str,
+    include_oneliner: bool,
+    include_source: bool,
+    options: &ParseOptions,
+) -> Result<Transmuted, GrimoireCssError> {
+    let parse_start = Stopwatch::start();
+    let mut parser_state = ParserState {
+        options: options.clone(),
+        ..Default::default()
+    };
+
+    let processed_css = process_css_into_raw_spells(&blank_bom(css_content), &mut parser_state)?;
+
+    ensure_transmuted(std::iter::once(css_content), &processed_css)?;
+
+    let mut transmuted = build_transmuted(
+        processed_css,
+        parser_state,
+        include_oneliner,
+        include_source,
+        |span| SourceSpan::from(span),
+    )?;
+    transmuted.timings.parse = parse_start.elapsed();
+
+    Ok(transmuted)
+}
+
+/// Transmutes CSS content to Grimoire CSS format.
+/// This is the main entry point for the content mode.
+///
+/// See [`transmute_from_content_with`] for the other options. Returns the
+/// time taken in seconds along with the JSON output.
+pub fn transmute_from_content(
+    css_content: &str,
+    include_oneliner: bool,
+) -> Result<(f64, String), GrimoireCssError> {
+    let options = TransmuteOptions::new().include_oneliner(include_oneliner);
+    let (timings, data) = transmute_from_content_with(css_content, &options)?;
+
+    Ok((timings.total.as_secs_f64(), data))
+}
+
+/// Like [`transmute_from_content`], with the output shaped by `options`.
+/// Returns the [`Timings`] of the run along with the output.
+pub fn transmute_from_content_with(
+    css_content: &str,
+    options: &TransmuteOptions,
+) -> Result<(Timings, String), GrimoireCssError> {
+    let start_time = Stopwatch::start();
+
+    let transmuted = transmute_content_to_struct_with(css_content, options)?;
+
+    serialize_timed(&transmuted, start_time, options.format, options.minify)
+}
+
+/// Like [`transmute_content_to_struct`], with the result shaped by `options`;
+/// `format` and `minify` do not apply.
+pub fn transmute_content_to_struct_with(
+    css_content: &str,
+    options: &TransmuteOptions,
+) -> Result<Transmuted, GrimoireCssError> {
+    transmute_content_to_struct(
+        css_content,
+        options.include_oneliner,
+        options.include_source,
+        &options.parse,
+    )
+}
+
+/// Transmutes CSS content to compact JSON for use from JavaScript. Errors
+/// are returned as `{"error":"<message>"}` rather than thrown.
+#[cfg(feature = "wasm")]
+#[wasm_bindgen::prelude::wasm_bindgen]
+pub fn transmute_wasm(css: &str, with_oneliner: bool) -> String {
+    transmute_to_json_or_error(css, with_oneliner)
+}
+
+/// The parse and serialize path behind `transmute_wasm`, which neither
+/// touches the filesystem nor the clock.
+#[cfg(any(feature = "wasm", test))]
+fn transmute_to_json_or_error(css: &str, with_oneliner: bool) -> String {
+    transmute_content_to_struct(css, with_oneliner, false, &ParseOptions::default())
+        .and_then(|transmuted| transmuted.to_json_compact())
+        .unwrap_or_else(|err| serde_json::json!({ "error": err.to_string() }).to_string())
+}
+
+/// Lists the names of the scrolls the CSS would produce, without generating
+/// their spells. Cheaper than a full transmutation when only names are needed.
+pub fn list_scroll_names(css_content: &str) -> Result<Vec<String>, GrimoireCssError> {
+    let mut parser_state = ParserState {
+        names_only: true,
+        ..Default::default()
+    };
+
+    let processed_css = process_css_into_raw_spells(&blank_bom(css_content), &mut parser_state)?;
+
+    let mut names: Vec<String> = processed_css
+        .into_keys()
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+
+    Ok(names)
+}
+
+/// Checks whether CSS would transmute cleanly, reporting unsupported
+/// constructs, classes that already are spells and dropped rules without
+/// building the scrolls. Input that cannot be parsed at all is an error.
+pub fn analyze_content(
+    css_content: &str,
+    options: &ParseOptions,
+) -> Result<AnalysisReport, GrimoireCssError> {
+    let mut parser_state = ParserState {
+        options: options.clone(),
+        ..Default::default()
+    };
+    process_css_into_raw_spells(&blank_bom(css_content), &mut parser_state)?;
+
+    Ok(AnalysisReport {
+        rules: parser_state.stats.rules,
+        selectors: parser_state.stats.selectors,
+        skipped_existing_spells: parser_state.skipped_existing_spells,
+        diagnostics: parser_state.dropped_tokens,
+        notices: parser_state.notices,
+    })
+}
+
+/// Like [`analyze_content`] for every file matched by the path patterns, in
+/// file order. `exclude` and `limits` work as in [`run_transmutation_to_struct`].
+/// A file that cannot be parsed gets its error instead of a report, so the
+/// remaining files are still analyzed.
+pub fn run_analysis(
+    args: Vec<String>,
+    exclude: &[String],
+    limits: RunLimits,
+    options: &ParseOptions,
+) -> Result<Vec<FileAnalysis>, GrimoireCssError> {
+    let deadline = limits.deadline();
+    let bundle = read_inputs(args, InputKind::Css, exclude, &limits, deadline, None)?;
+
+    bundle
+        .files
+        .iter()
+        .enumerate()
+        .map(|(index, file)| {
+            if let Some(deadline) = &deadline {
+                deadline.check(|| format!("{index} files analyzed"))?;
+            }
+            Ok((file.path.clone(), analyze_content(&file.content, options)))
+        })
+        .collect()
+}
+
+/// Transmutes a bare declaration list, such as the body of a `style`
+/// attribute (`color: red; padding: 4px`), into spell components in
+/// declaration order. Repeated properties keep only the winning declaration
+/// and custom properties are left out, as inside a rule.
+pub fn transmute_declarations(declarations: &str) -> Result<Vec<String>, GrimoireCssError> {
+    let cleaned = clean_source(declarations);
+    let mut input = ParserInput::new(&cleaned);
+    let mut parser = Parser::new(&mut input);
+
+    let components = parse_declarations(&mut parser)
+        .into_iter()
+        .filter(|(component, _)| !component.starts_with("--"))
+        .collect();
+
+    Ok(winning_declarations(components)
+        .into_iter()
+        .map(|(component, _)| component)
+        .collect())
+}
+
+/// Extracts the `style` attributes of an HTML document, transmuted with
+/// [`transmute_declarations`], and the class names already in use.
+///
+/// As in browsers, only the first of repeated attributes on an element counts.
+pub fn extract_from_html(html: &str) -> Result<HtmlExtraction, GrimoireCssError> {
+    let mut extraction = HtmlExtraction::default();
+    let mut classes = BTreeSet::new();
+
+    for (element, attributes) in html_start_tags(html) {
+        let attribute = |name: &str| {
+            attributes
+                .iter()
+                .find(|(attribute, _)| attribute == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        let element_classes: Vec<String> = attribute("class")
+            .map(|value| value.split_ascii_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+        classes.extend(element_classes.iter().cloned());
+
+        if let Some(style) = attribute("style") {
+            let spells = transmute_declarations(style)?;
+            if !spells.is_empty() {
+                extraction.inline_styles.push(InlineStyle {
+                    element,
+                    classes: element_classes,
+                    spells,
+                });
+            }
+        }
+    }
+
+    extraction.classes = classes.into_iter().collect();
+    Ok(extraction)
+}
+
+/// Like [`extract_from_html`] for every HTML file matched by the path
+/// patterns, in file order. `exclude` and `limits` work as in
+/// [`run_transmutation_to_struct`].
+pub fn run_html_extraction(
+    args: Vec<String>,
+    exclude: &[String],
+    limits: RunLimits,
+) -> Result<HtmlExtraction, GrimoireCssError> {
+    let deadline = limits.deadline();
+    let bundle = read_inputs(args, InputKind::Html, exclude, &limits, deadline, None)?;
+    let mut extraction = HtmlExtraction::default();
+    let mut classes = BTreeSet::new();
+
+    for (index, html) in bundle.contents().enumerate() {
+        if let Some(deadline) = &deadline {
+            deadline.check(|| format!("{index} files extracted"))?;
+        }
+        let file = extract_from_html(html)?;
+        extraction.inline_styles.extend(file.inline_styles);
+        classes.extend(file.classes);
+    }
+
+    extraction.classes = classes.into_iter().collect();
+    Ok(extraction)
+}
+
+/// Scans the start tags of an HTML document into their lowercased name and
+/// attributes (lowercased name, value with the common entities decoded).
+/// Comments, end tags, doctypes and the contents of `script` and `style`
+/// elements are skipped.
+fn html_start_tags(html: &str) -> Vec<(String, Vec<(String, String)>)> {
+    let bytes = html.as_bytes();
+    let mut tags = Vec::new();
+    let mut i = 0;
+
+    let skip_whitespace = |mut i: usize| {
+        while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        i
+    };
+    let find_from = |i: usize, pattern: &str| html[i..].find(pattern).map(|end| i + end);
+
+    while let Some(open) = find_from(i, "<") {
+        i = open + 1;
+
+        if html[i..].starts_with("!--") {
+            i = find_from(i, "-->").map_or(html.len(), |end| end + 3);
+            continue;
+        }
+        if !bytes.get(i).is_some_and(u8::is_ascii_alphabetic) {
+            continue;
+        }
+
+        let name_end = html[i..]
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '-')
+            .map_or(html.len(), |end| i + end);
+        let name = html[i..name_end].to_ascii_lowercase();
+        i = name_end;
+
+        let mut attributes = Vec::new();
+        loop {
+            while bytes
+                .get(i)
+                .is_some_and(|b| b.is_ascii_whitespace() || *b == b'/')
+            {
+                i += 1;
+            }
+            match bytes.get(i) {
+                None => break,
+                Some(b'>') => {
+                    i += 1;
+                    break;
+                }
+                Some(_) => {}
+            }
+
+            let attribute_end = html[i..]
+                .find(|c: char| c.is_ascii_whitespace() || matches!(c, '=' | '>' | '/'))
+                .map_or(html.len(), |end| i + end);
+            if attribute_end == i {
+                // A stray `=`
+                i += 1;
+                continue;
+            }
+            let attribute = html[i..attribute_end].to_ascii_lowercase();
+            i = skip_whitespace(attribute_end);
+
+            let mut value = "";
+            if bytes.get(i) == Some(&b'=') {
+                i = skip_whitespace(i + 1);
+                match bytes.get(i) {
+                    Some(&quote @ (b'"' | b'\'')) => {
+                        let end = html[i + 1..]
+                            .find(quote as char)
+                            .map_or(html.len(), |end| i + 1 + end);
+                        value = &html[i + 1..end];
+                        i = (end + 1).min(html.len());
+                    }
+                    _ => {
+                        let end = html[i..]
+                            .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                            .map_or(html.len(), |end| i + end);
+                        value = &html[i..end];
+                        i = end;
+                    }
+                }
+            }
+
+            attributes.push((attribute, decode_html_entities(value)));
+        }
+
+        // Raw text elements may contain anything up to their end tag
+        if matches!(name.as_str(), "script" | "style") {
+            let end_tag = format!("</{name}");
+            i = html[i..]
+                .to_ascii_lowercase()
+                .find(&end_tag)
+                .map_or(html.len(), |end| i + end);
+        }
+
+        tags.push((name, attributes));
+    }
+
+    tags
+}
+
+/// Decodes the character references commonly found in attribute values.
+fn decode_html_entities(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Expands glob patterns into a list of file paths, leaving out those matching
+/// `exclude`.
+fn expand_file_paths(
+    cwd: &Path,
+    patterns: &[String],
+    exclude: &[Pattern],
+    kind: InputKind,
+) -> Result<Vec<PathBuf>, GrimoireCssError> {
+    let mut paths = Vec::with_capacity(patterns.len() * 4);
+
+    for pattern in patterns {
+        for absolute_pattern in absolute_glob(cwd, pattern, kind) {
+            for entry_result in glob(&absolute_pattern)
+                .map_err(|e| GrimoireCssError::GlobPatternError(e.msg.to_string()))?
+            {
+                match entry_result {
+                    Ok(path) if exclude.iter().any(|p| p.matches_path(&path)) => {}
+                    Ok(path) if path.is_file() => paths.push(path),
+                    Ok(_) => {} // Skip directories
+                    Err(e) => return Err(GrimoireCssError::InvalidPath(e.to_string())),
+                }
+            }
+        }
+    }
+
+    // If no memory waste, return as is; otherwise, shrink to fit
+    if paths.len() < paths.capacity() / 2 {
+        paths.shrink_to_fit();
+    }
+
+    Ok(paths)
+}
+
+/// Compiles the exclude patterns, resolved against `cwd`.
+fn exclude_patterns(
+    cwd: &Path,
+    exclude: &[String],
+    kind: InputKind,
+) -> Result<Vec<Pattern>, GrimoireCssError> {
+    exclude
+        .iter()
+        .flat_map(|pattern| absolute_glob(cwd, pattern, kind))
+        .map(|pattern| {
+            Pattern::new(&pattern)
+                .map_err(|e| GrimoireCssError::GlobPatternError(e.msg.to_string()))
+        })
+        .collect()
+}
+
+/// The files the `@import` rules of `css` (read from `path`) pull in, in
+/// declaration order and without those matching `exclude`.
+fn imported_paths(
+    path: &Path,
+    css: &str,
+    exclude: &[Pattern],
+) -> Result<Vec<PathBuf>, GrimoireCssError> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut imports = Vec::new();
+
+    for url in import_urls(css) {
+        // Normalized first, so `parts/../vendor/x.css` cannot slip past an
+        // exclude of `vendor/*`
+        let imported = normalize_path(&base.join(&url));
+
+        if exclude.iter().any(|p| p.matches_path(&imported)) {
+            continue;
+        }
+        if !imported.is_file() {
+            return Err(GrimoireCssError::InvalidPath(format!(
+                "Imported file {url:?} not found (imported from {path:?})"
+            )));
+        }
+
+        imports.push(imported);
+    }
+
+    Ok(imports)
+}
+
+/// Resolves the `.` and `..` components of `path` without touching the file
+/// system, so symlinks are not followed.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+/// The identity of a file for telling whether it was already read.
+fn canonical_path(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| normalize_path(path))
+}
+
+/// The local stylesheets referenced by the top-level `@import` rules of `css`.
+fn import_urls(css: &str) -> Vec<String> {
+    let mut input = ParserInput::new(css);
+    let mut parser = Parser::new(&mut input);
+    let mut urls = Vec::new();
+
+    while let Ok(token) = parser.next() {
+        if !matches!(token, Token::AtKeyword(name) if name.eq_ignore_ascii_case("import")) {
+            continue;
+        }
+
+        let url = match parser.next() {
+            Ok(Token::QuotedString(url) | Token::UnquotedUrl(url)) => Some(url.to_string()),
+            Ok(Token::Function(name)) if name.eq_ignore_ascii_case("url") => parser
+                .parse_nested_block(|input| {
+                    Ok::<_, ParseError<'_, ()>>(input.expect_string()?.to_string())
+                })
+                .ok(),
+            _ => None,
+        };
+
+        urls.extend(url.filter(|url| !url.starts_with("//") && !url.contains("://")));
+    }
+
+    urls
+}
+
+/// Resolves a pattern against `cwd`; a plain directory means every file of
+/// `kind` below it.
+fn absolute_glob(cwd: &Path, pattern: &str, kind: InputKind) -> Vec<String> {
+    let absolute = cwd.join(pattern);
+
+    if absolute.is_dir() {
+        kind.extensions()
+            .iter()
+            .map(|extension| {
+                absolute
+                    .join("**")
+                    .join(format!("*.{extension}"))
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    } else {
+        vec![absolute.to_string_lossy().into_owned()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_last_char() {
+        assert_eq!(remove_last_char("hello"), "hell");
+        assert_eq!(remove_last_char("a"), "");
+        assert_eq!(remove_last_char(""), "");
+    }
+
+    #[test]
+    fn test_read_and_clean_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("test.css");
+        let content = r#"
+            /* Comment */
+            .test {
+                color: "red";
+            }"#;
+
+        fs::write(&file_path, content).unwrap();
+        let result = read_and_clean_files(
+            &[file_path],
+            InputKind::Css,
+            &[],
+            &RunLimits::default(),
+            None,
+            None,
+        )
+        .unwrap()
+        .files
+        .remove(0)
+        .content;
+        let expected = ".test { color: \"red\"; }";
+
+        let actual = result.replace("\n", "").replace(" ", "");
+        let expected_normalized = expected.replace("\n", "").replace(" ", "");
+
+        assert_eq!(actual, expected_normalized);
+    }
+
+    #[test]
+    fn test_generate_spells_map() {
+        let mut state = ParserState::default();
+        state
+            .raw_classes_spells_map
+            .insert("class1".to_string(), vec!["prefix".to_string()]);
+        state
+            .component_and_component_target_map
+            .insert("color=red".to_string());
+
+        let result: HashMap<String, HashSet<String>> = generate_spells_map(&state);
+        let left_spells = result.get("class1").unwrap();
+        let left_spells_vec: Vec<String> = left_spells.iter().map(String::from).collect();
+
+        assert_eq!(left_spells_vec, vec!["prefixcolor=red".to_string()]);
+    }
+
+    #[test]
+    fn test_merge_maps() {
+        let mut map1: HashMap<String, HashSet<String>> = HashMap::new();
+        map1.insert("class1".to_string(), HashSet::from(["spell1".to_string()]));
+
+        let mut map2: HashMap<String, HashSet<String>> = HashMap::new();
+        map2.insert("class1".to_string(), HashSet::from(["spell2".to_string()]));
+        map2.insert("class2".to_string(), HashSet::from(["spell3".to_string()]));
+
+        merge_maps(&mut map1, map2);
+
+        let left_spells = map1.get("class2").unwrap();
+        let left_spells_vec: Vec<String> = left_spells.iter().map(String::from).collect();
+
+        assert_eq!(left_spells_vec, vec!["spell3".to_string()]);
+    }
+
+    #[test]
+    fn test_process_css_into_raw_spells() {
+        let css_input = ".button { color: red; }";
+        let mut parser_state = ParserState::default();
+
+        let result = process_css_into_raw_spells(css_input, &mut parser_state);
+        assert!(result.is_ok());
+        let spells_map = result.unwrap();
+        let left_spells = spells_map.get("button").unwrap();
+        let left_spells_vec: Vec<String> = left_spells.iter().map(String::from).collect();
+
+        assert_eq!(left_spells_vec, vec!["color=red".to_string()]);
+    }
+
+    #[test]
+    fn test_expand_file_paths() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("test.css");
+        fs::write(&file_path, ".test { color: red; }").unwrap();
+
+        let cwd = temp_dir.path().to_path_buf();
+        let result = expand_file_paths(&cwd, &["test.css".to_string()], &[], InputKind::Css);
+
+        assert!(result.is_ok());
+        let paths = result.unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0], file_path);
+    }
+
+    #[test]
+    fn test_transmute_from_content() {
+        let css_input = ".button { color: red; }";
+        let result = transmute_from_content_with(css_input, &TransmuteOptions::new());
+        assert!(result.is_ok());
+        let (_duration, json_output) = result.unwrap();
+        assert!(json_output.contains("\"name\": \"button\""));
+        assert!(json_output.contains("\"color=red\""));
+    }
+
+    #[test]
+    fn test_run_transmutation_size_limits() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(temp_dir.path().join("a.css"), ".a { color: red; }").unwrap();
+        fs::write(temp_dir.path().join("b.css"), ".b { color: blue; }").unwrap();
+        let pattern = temp_dir.path().join("*.css").to_string_lossy().into_owned();
+
+        let per_file = RunLimits {
+            max_file_size: 10,
+            ..RunLimits::default()
+        };
+        match run_transmutation_to_struct_with(
+            vec![pattern.clone()],
+            &TransmuteOptions::new().limits(per_file),
+            RunHooks::default(),
+        ) {
+            Err(GrimoireCssError::InvalidInput(msg)) => {
+                assert!(msg.contains("a.css"), "{msg}");
+                assert!(msg.contains("over the limit of 10 bytes per file"), "{msg}");
+            }
+            other => panic!("expected size limit error, got {other:?}"),
+        }
+
+        let total = RunLimits {
+            max_total_size: 30,
+            ..RunLimits::default()
+        };
+        match run_transmutation_to_struct_with(
+            vec![pattern.clone()],
+            &TransmuteOptions::new().limits(total),
+            RunHooks::default(),
+        ) {
+            Err(GrimoireCssError::InvalidInput(msg)) => {
+                assert!(
+                    msg.contains("total 37 bytes, over the limit of 30 bytes"),
+                    "{msg}"
+                );
+            }
+            other => panic!("expected size limit error, got {other:?}"),
+        }
+
+        assert!(transmute_dir(temp_dir.path(), &ParseOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_bom_and_charset() {
+        let css_input =
+            "\u{feff}@charset \"utf-8\";\n.first { color: red; } .second { margin: 0; }";
+
+        let transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+        assert!(transmuted.notices.is_empty());
+        assert_eq!(
+            sorted_scrolls(&transmuted),
+            vec![
+                ("first".to_string(), vec!["color=red".to_string()]),
+                ("second".to_string(), vec!["margin=0".to_string()]),
+            ]
+        );
+
+        let from_file =
+            transmute_files(&[("bom.css", css_input)], &ParseOptions::default()).unwrap();
+        assert_eq!(sorted_scrolls(&from_file), sorted_scrolls(&transmuted));
+
+        // Spans still point into the file, BOM included
+        let span = &from_file.source_map["first"]["color=red"];
+        assert_eq!(&css_input[span.start..span.end], "color: red");
+    }
+
+    #[test]
+    fn test_transmute_sources_to_struct() {
+        let sources = vec![
+            ("a.css".to_string(), ".btn { color: red; } .a".to_string()),
+            (
+                "b.css".to_string(),
+                ".btn { margin: 0; } .card { padding: 0; }".to_string(),
+            ),
+        ];
+
+        let transmuted =
+            transmute_sources_to_struct(&sources, false, false, &ParseOptions::default()).unwrap();
+
+        assert_eq!(
+            sorted_scrolls(&transmuted),
+            vec![
+                (
+                    "btn".to_string(),
+                    vec!["color=red".to_string(), "margin=0".to_string()]
+                ),
+                ("card".to_string(), vec!["padding=0".to_string()]),
+            ]
+        );
+        assert_eq!(transmuted.stats.rules, 3);
+        let span = &transmuted.source_map["btn"]["margin=0"];
+        assert_eq!(span.file.as_deref(), Some("b.css"));
+        assert_eq!(&sources[1].1[span.start..span.end], "margin: 0");
+    }
+
+    #[test]
+    fn test_scrolls_in_source_order() {
+        let css_input = r#"
+            .zeta, .beta { color: red; margin: 0; padding: 0; }
+            .alpha { color: blue; }
+            @media print { .gamma { display: none; } .alpha { display: none; } }
+            .delta > .x { color: green; }
+            .beta { border: 0; }
+        "#;
+
+        let expected = vec!["zeta", "beta", "alpha", "gamma", "delta"];
+        let first =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+        let names: Vec<&str> = first.scrolls.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, expected);
+        assert_eq!(
+            first.scrolls[0].spells,
+            vec!["color=red", "margin=0", "padding=0"]
+        );
+
+        for _ in 0..10 {
+            let again =
+                transmute_content_to_struct(css_input, false, false, &ParseOptions::default())
+                    .unwrap();
+            assert_eq!(again.to_json().unwrap(), first.to_json().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_run_transmutation_timeout() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let rules: String = (0..2000)
+            .map(|i| format!(".class-{i} {{ color: red; margin: {i}px; }}\n"))
+            .collect();
+
+        for i in 0..20 {
+            fs::write(temp_dir.path().join(format!("file-{i}.css")), &rules).unwrap();
+        }
+
+        let pattern = temp_dir.path().join("*.css").to_string_lossy().into_owned();
+        let result = run_transmutation_with(
+            vec![pattern],
+            &TransmuteOptions::new().limits(RunLimits {
+                timeout: Some(Duration::from_nanos(1)),
+                ..RunLimits::default()
+            }),
+            RunHooks::default(),
+        );
+
+        match result {
+            Err(err) if is_timeout(&err) => {
+                let msg = err.to_string();
+                assert!(msg.contains("timed out"));
+                assert!(msg.contains("partial result"));
+            }
+            other => panic!("expected timeout error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_transmute_content_to_struct() {
+        let css_input = ".button { color: red; } .link { color: blue; }";
+        let transmuted =
+            transmute_content_to_struct(css_input, true, false, &ParseOptions::default()).unwrap();
+        assert_eq!(transmuted.scrolls.len(), 2);
+
+        let mut names: Vec<&str> = transmuted.scrolls.iter().map(|s| s.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["button", "link"]);
+
+        let button = transmuted
+            .scrolls
+            .iter()
+            .find(|s| s.name == "button")
+            .unwrap();
+        assert_eq!(button.spells, vec!["color=red".to_string()]);
+        assert_eq!(button.oneliner.as_deref(), Some("color=red"));
+    }
+
+    #[test]
+    fn test_important_flag() {
+        assert_eq!(
+            spells_of(".a { color: red !important }", "a"),
+            vec!["color=red!"]
+        );
+        assert_eq!(
+            spells_of(".a { margin: 0 auto ! important; }", "a"),
+            vec!["margin=0_auto!"]
+        );
+    }
+
+    #[test]
+    fn test_important_with_trailing_comment() {
+        let css_input = ".alert { color: red !important /* override */; }";
+        let mut parser_state = ParserState::default();
+
+        let spells_map = process_css_into_raw_spells(css_input, &mut parser_state).unwrap();
+        let spells: Vec<&String> = spells_map.get("alert").unwrap().iter().collect();
+
+        assert_eq!(spells, vec!["color=red!"]);
+    }
+
+    #[test]
+    fn test_container_query() {
+        let css_input = r#"
+            .card { padding: 0; }
+            @container Sidebar (min-width: 400px) { .card { padding: 1rem; } }
+            @container (max-width:300px) { .card .title { display: none; } }
+        "#;
+
+        assert_eq!(
+            spells_of(css_input, "card"),
+            vec![
+                "container_(max-width:_300px)__{_.title}display=none",
+                "container_Sidebar_(min-width:_400px)__padding=1rem",
+                "padding=0",
+            ]
+        );
+
+        // At-rule names are case-insensitive
+        let css_input = "@Container (max-width:300px) { .a { top: 0 } } \
+                         @MEDIA print { .a { margin: 0 } } @Layer base { .b { top: 0 } }";
+        let transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+        assert!(transmuted.diagnostics.is_empty());
+        assert_eq!(
+            spells_of(css_input, "a"),
+            vec!["container_(max-width:_300px)__top=0", "print__margin=0"]
+        );
+        assert_eq!(transmuted.scrolls[1].layer.as_deref(), Some("base"));
+    }
+
+    #[test]
+    fn test_layer_blocks() {
+        let css_input = r#"
+            @layer reset, components;
+            @layer components {
+                .btn { color: red; }
+                @layer states { .btn:hover { color: blue; } }
+                @media print { .card { display: none; } }
+            }
+            .plain { margin: 0; }
+        "#;
+
+        let transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+        let scroll = |name: &str| {
+            transmuted
+                .scrolls
+                .iter()
+                .find(|scroll| scroll.name == name)
+                .unwrap()
+        };
+
+        assert!(transmuted.notices.is_empty());
+        assert_eq!(scroll("btn").layer.as_deref(), Some("components"));
+        assert_eq!(scroll("card").layer.as_deref(), Some("components"));
+        assert_eq!(scroll("card").spells, vec!["print__display=none"]);
+        assert_eq!(scroll("plain").layer, None);
+        assert_eq!(
+            spells_of(css_input, "btn"),
+            vec!["color=red", "{:hover}color=blue"]
+        );
+        assert!(transmuted
+            .to_json()
+            .unwrap()
+            .contains(r#""layer": "components""#));
+    }
+
+    #[test]
+    fn test_rule_after_media_block() {
+        let css_input = "@media print { .a { color: red; } } .b { color: blue; }";
+
+        assert_eq!(spells_of(css_input, "b"), vec!["color=blue"]);
+        assert_eq!(list_scroll_names(css_input).unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_custom_properties() {
+        let css_input = r#"
+            :root { --brand: #f00; --font:  "Open Sans",  serif; }
+            .btn { --gap: 4px; color: var(--brand); }
+            @media (prefers-color-scheme: dark) { :root { --brand: #0f0; } }
+        "#;
+
+        let transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+
+        assert_eq!(transmuted.custom_properties["--brand"], "#f00");
+        assert_eq!(
+            transmuted.custom_properties["--font"],
+            r#""Open Sans", serif"#
+        );
+        assert_eq!(transmuted.custom_properties["--gap"], "4px");
+        assert_eq!(transmuted.notices.len(), 1);
+        assert_eq!(
+            sorted_scrolls(&transmuted),
+            vec![("btn".to_string(), vec!["color=var(--brand)".to_string()])]
+        );
+    }
+
+    #[test]
+    fn test_element_selectors() {
+        let css_input = r#"
+            body { margin: 0; }
+            h1 { font-size: 2em; }
+            * { box-sizing: border-box; }
+            .title { color: red; }
+        "#;
+
+        let names = |options: &ParseOptions| {
+            let transmuted = transmute_content_to_struct(css_input, false, false, options).unwrap();
+            let mut names: Vec<String> = transmuted.scrolls.into_iter().map(|s| s.name).collect();
+            names.sort_unstable();
+            names
+        };
+
+        assert_eq!(
+            names(&ParseOptions::default()),
+            ["*", "body", "h1", "title"]
+        );
+        assert_eq!(spells_of(css_input, "body"), vec!["margin=0"]);
+        assert_eq!(spells_of(css_input, "h1"), vec!["font-size=2em"]);
+
+        let options = ParseOptions {
+            include_elements: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            names(&options),
+            ["element:*", "element:body", "element:h1", "title"]
+        );
+    }
+
+    #[test]
+    fn test_skipped_existing_spells() {
+        let css_input =
+            r".color\=red { color: red; } .btn { color: blue; } .color\=red:hover { color: red; }";
+
+        let transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+
+        assert_eq!(transmuted.skipped_existing_spells, vec!["color=red"]);
+        assert_eq!(transmuted.stats.skipped_spells, 2);
+        assert_eq!(
+            sorted_scrolls(&transmuted),
+            vec![("btn".to_string(), vec!["color=blue".to_string()])]
+        );
+    }
+
+    #[test]
+    fn test_skip_existing_spells_with_config() {
+        let config = GrimoireConfig::from_json(
+            r#"{
+                "scrolls": [{ "name": "btn", "spells": ["color=red"] }],
+                "shared": [{ "outputPath": "shared.css", "styles": ["base.css", "card"] }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.shared_spells, HashSet::from(["card".to_string()]));
+
+        let css_input = ".btn { color: red; } .card { padding: 0; } .link { color: blue; }";
+        let transmute = |grimoire_config| {
+            let options = ParseOptions {
+                grimoire_config,
+                ..Default::default()
+            };
+            transmute_content_to_struct(css_input, false, false, &options).unwrap()
+        };
+
+        assert_eq!(transmute(Some(GrimoireConfig::default())).scrolls.len(), 3);
+
+        let mut transmuted = transmute(Some(config));
+        transmuted.skipped_existing_spells.sort();
+        assert_eq!(transmuted.skipped_existing_spells, vec!["btn", "card"]);
+        assert_eq!(transmuted.stats.skipped_spells, 2);
+        assert_eq!(
+            sorted_scrolls(&transmuted),
+            vec![("link".to_string(), vec!["color=blue".to_string()])]
+        );
+        assert!(!transmuted.source_map.contains_key("btn"));
+    }
+
+    #[test]
+    fn test_split_media() {
+        let css_input = r#"
+            .page { color: red; }
+            @media screen { .page { color: green; } }
+            @media print { .page { color: black; } .nav { display: none; } }
+        "#;
+        let mut transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+        transmuted.split_media(&["print".to_string()]);
+
+        assert_eq!(transmuted.scrolls.len(), 1);
+        let mut page_spells = transmuted.scrolls[0].spells.clone();
+        page_spells.sort();
+        assert_eq!(page_spells, vec!["color=red", "screen__color=green"]);
+
+        let print = transmuted.split_media.get("print").unwrap();
+        let page = print.iter().find(|s| s.name == "page").unwrap();
+        let nav = print.iter().find(|s| s.name == "nav").unwrap();
+        assert_eq!(page.spells, vec!["print__color=black"]);
+        assert_eq!(nav.spells, vec!["print__display=none"]);
+    }
+
+    #[test]
+    fn test_existing_spell_notices() {
+        let css_input = r".color\=red { color: red; } .button { color: red; }";
+        let transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+
+        assert_eq!(transmuted.scrolls.len(), 1);
+        assert_eq!(transmuted.notices.len(), 1);
+        assert!(transmuted.notices[0].contains("color=red"));
+    }
+
+    #[test]
+    fn test_unbalanced_braces() {
+        for css_input in [
+            ".a { color: red; }}",
+            ".a { color: red;",
+            "@media print { .a { color: red; }",
+        ] {
+            let mut parser_state = ParserState::default();
+            let result = process_css_into_raw_spells(css_input, &mut parser_state);
+
+            match result {
+                Err(GrimoireCssError::InvalidInput(msg)) => {
+                    assert!(msg.contains("Unbalanced braces"), "{msg}");
+                    assert!(msg.contains("byte offset"), "{msg}");
+                }
+                other => panic!("expected error for {css_input:?}, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_canonical_media_condition() {
+        assert_eq!(
+            canonical_media_condition(" ( min-width :  600px ) "),
+            "(min-width: 600px)"
+        );
+        assert_eq!(
+            canonical_media_condition("(min-width:600px) and screen and (max-width: 900px)"),
+            "screen and (max-width: 900px) and (min-width: 600px)"
+        );
+        assert_eq!(
+            canonical_media_condition("Only SCREEN AND (MIN-WIDTH: 600px)"),
+            "only screen and (min-width: 600px)"
+        );
+        assert_eq!(
+            canonical_media_condition("Sidebar (MIN-WIDTH: 400px)"),
+            "Sidebar (min-width: 400px)"
+        );
+        assert_eq!(
+            canonical_media_condition("(--customMedia) AND (Orientation: Landscape)"),
+            "(--customMedia) and (orientation: Landscape)"
+        );
+    }
+
+    #[test]
+    fn test_media_conditions_dedupe_across_files() {
+        let files = [
+            ("a.css", "@media (min-width:600px) { .btn { color: red; } }"),
+            (
+                "b.css",
+                "@media ( min-width :   600px ) { .btn { color: red; } }",
+            ),
+        ];
+        let transmuted = transmute_files(&files, &ParseOptions::default()).unwrap();
+
+        assert_eq!(transmuted.scrolls.len(), 1);
+        assert_eq!(
+            transmuted.scrolls[0].spells,
+            vec!["(min-width:_600px)__color=red"]
+        );
+    }
+
+    #[test]
+    fn test_areas_stay_distinct_across_files() {
+        let files = [
+            ("a.css", ".btn { color: red }"),
+            ("b.css", "@media print { .btn { color: black } }"),
+        ];
+        let transmuted = transmute_files(&files, &ParseOptions::default()).unwrap();
+
+        assert_eq!(transmuted.scrolls.len(), 1);
+        assert_eq!(transmuted.scrolls[0].name, "btn");
+        assert_eq!(
+            sorted_scrolls(&transmuted)[0].1,
+            vec!["color=red", "print__color=black"]
+        );
+    }
+
+    #[test]
+    fn test_follow_imports() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::create_dir(temp_dir.path().join("parts")).unwrap();
+        fs::create_dir(temp_dir.path().join("vendor")).unwrap();
+        fs::write(
+            temp_dir.path().join("main.css"),
+            "@import url(\"parts/button.css\");\n@import \"parts/../parts/button.css\";\n@import \"parts/../vendor/lib.css\";\n@import \"https://example.com/x.css\";\n.page { margin: 0; }",
+        )
+        .unwrap();
+        // Imports back into main.css, which must not loop
+        fs::write(
+            temp_dir.path().join("parts/button.css"),
+            "@import '../main.css'; .btn { color: red; }",
+        )
+        .unwrap();
+        fs::write(
+            temp_dir.path().join("vendor/lib.css"),
+            ".lib { color: blue; }",
+        )
+        .unwrap();
+
+        let main = vec![temp_dir
+            .path()
+            .join("main.css")
+            .to_string_lossy()
+            .into_owned()];
+        let limits = RunLimits {
+            base_dir: Some(temp_dir.path().to_path_buf()),
+            follow_imports: true,
+            ..Default::default()
+        };
+        let mut read = Vec::new();
+        let transmuted = run_transmutation_to_struct_with(
+            main.clone(),
+            &TransmuteOptions::new()
+                .exclude(vec!["vendor/*.css".to_string()])
+                .limits(limits),
+            RunHooks {
+                progress: Some(&mut |_, _, path: &Path| read.push(path.to_path_buf())),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            sorted_scrolls(&transmuted),
+            vec![
+                ("btn".to_string(), vec!["color=red".to_string()]),
+                ("page".to_string(), vec!["margin=0".to_string()]),
+            ]
+        );
+        // Each file is read once, imports right after the importing file
+        assert_eq!(
+            read,
+            vec![
+                temp_dir.path().join("main.css"),
+                temp_dir.path().join("parts/button.css")
+            ]
+        );
+
+        // Imported files are held to the per-file limit too
+        let main_size = fs::metadata(temp_dir.path().join("main.css"))
+            .unwrap()
+            .len();
+        fs::write(
+            temp_dir.path().join("parts/button.css"),
+            format!(".btn {{ color: red; }}{}", " ".repeat(main_size as usize)),
+        )
+        .unwrap();
+        let limits = RunLimits {
+            base_dir: Some(temp_dir.path().to_path_buf()),
+            follow_imports: true,
+            max_file_size: main_size,
+            ..Default::default()
+        };
+        let err = run_transmutation_to_struct_with(
+            main.clone(),
+            &TransmuteOptions::new().limits(limits),
+            RunHooks::default(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(&err, GrimoireCssError::InvalidInput(msg) if msg.contains("button.css' is")),
+            "{err:?}"
+        );
+
+        // Without following, only main.css is transmuted
+        let transmuted =
+            run_transmutation_to_struct_with(main, &TransmuteOptions::new(), RunHooks::default())
+                .unwrap();
+        assert_eq!(transmuted.scrolls.len(), 1);
+    }
+
+    #[test]
+    fn test_list_scroll_names() {
+        let css_input = r#"
+            .button { color: red; }
+            .link:hover { color: blue; }
+            @media print { .nav { display: none; } }
+        "#;
+
+        let names = list_scroll_names(css_input).unwrap();
+        assert_eq!(names, vec!["button", "link", "nav"]);
+
+        let transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+        let mut full_names: Vec<String> = transmuted.scrolls.into_iter().map(|s| s.name).collect();
+        full_names.sort();
+        assert_eq!(names, full_names);
+    }
+
+    #[test]
+    fn test_read_and_clean_files_keeps_comment_like_values() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("test.css");
+        let content = r#"
+            /* Comment */
+            .quote::before { content: "a /* b */ c"; }
+            .bg { background: url(http://example.com/*x*/y.png); }"#;
+
+        fs::write(&file_path, content).unwrap();
+        let result = read_and_clean_files(
+            &[file_path],
+            InputKind::Css,
+            &[],
+            &RunLimits::default(),
+            None,
+            None,
+        )
+        .unwrap()
+        .files
+        .remove(0)
+        .content;
+
+        assert!(!result.contains("Comment"));
+        assert!(result.contains("\"a /* b */ c\""));
+        assert!(result.contains("url(http://example.com/*x*/y.png)"));
+    }
+
+    #[test]
+    fn test_nth_child_an_plus_b_arguments() {
+        for (argument, expected) in [
+            ("-n+3", "-n+3"),
+            ("0n+1", "0n+1"),
+            ("even", "even"),
+            ("odd", "odd"),
+            (" 2n + 1 ", "2n+1"),
+            ("-2n - 1 of .item", "-2n-1_of_.item"),
+        ] {
+            let css_input = format!("li:nth-child({argument}) {{ color: red; }}");
+            let mut parser_state = ParserState::default();
+            let spells_map = process_css_into_raw_spells(&css_input, &mut parser_state).unwrap();
+            let spells: Vec<&String> = spells_map.get("li").unwrap().iter().collect();
+
+            assert_eq!(
+                spells,
+                vec![&format!("{{:nth-child({expected})}}color=red")],
+                "argument {argument:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_attribute_selector_quotes_preserved() {
+        let files = [("attr.css", r#".field[data-foo="bar"] { color: red; }"#)];
+        let transmuted = transmute_files(&files, &ParseOptions::default()).unwrap();
+
+        assert_eq!(transmuted.scrolls.len(), 1);
+        assert_eq!(
+            transmuted.scrolls[0].spells,
+            vec![r#"{[data-foo="bar"]}color=red"#]
+        );
+    }
+
+    #[test]
+    fn test_check_grimoire() {
+        let mut transmuted = transmute_content_to_struct(
+            ".button { color: red; }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert!(transmuted.check_grimoire().is_empty());
+
+        transmuted.scrolls.push(TransmutedClass {
+            name: "broken".to_string(),
+            spells: vec!["{:hover}".to_string()],
+            oneliner: None,
+            collapsed: false,
+            source_selector: None,
+            layer: None,
+            spell_sources: Vec::new(),
+            shared_with: Vec::new(),
+            duplicate_count: 1,
+        });
+
+        let issues = transmuted.check_grimoire();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].scroll, "broken");
+        assert_eq!(issues[0].spell, "{:hover}");
+
+        // Parses as a spell, but the builder knows no such animation
+        assert!(is_spell("g-anim=no-such-animation", None).unwrap());
+        transmuted.scrolls[1].spells = vec!["g-anim=no-such-animation".to_string()];
+
+        let issues = transmuted.check_grimoire();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].spell, "g-anim=no-such-animation");
+        assert!(issues[0].reason.contains("no-such-animation"));
+    }
+
+    #[test]
+    fn test_source_map_content() {
+        let css_input = ".card {\n  color: red;\n  margin: 0 auto;\n}";
+        let transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+
+        let span = &transmuted.source_map["card"]["margin=0_auto"];
+        let start = css_input.find("margin").unwrap();
+        assert_eq!(span.file, None);
+        assert_eq!(span.start, start);
+        assert_eq!(&css_input[span.start..span.end], "margin: 0 auto");
+    }
+
+    #[test]
+    fn test_source_map_paths() {
+        let second = "/* header */\n.b { padding: 4px; }";
+        let files = [("a.css", ".a { color: red; }"), ("b.css", second)];
+        let transmuted = transmute_files(&files, &ParseOptions::default()).unwrap();
+
+        let span = &transmuted.source_map["b"]["padding=4px"];
+        assert!(span.file.as_deref().unwrap().ends_with("b.css"));
+        assert_eq!(&second[span.start..span.end], "padding: 4px");
+    }
+
+    #[test]
+    fn test_empty_block_before_media() {
+        let css_input = ".a {} @media (min-width:1px) { .b {color:red} }";
+        let transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
+
+        assert!(transmuted
+            .scrolls
+            .iter()
+            .filter(|s| s.name == "a")
+            .all(|s| s.spells.is_empty()));
+
+        let b = transmuted.scrolls.iter().find(|s| s.name == "b").unwrap();
+        assert_eq!(b.spells, vec!["(min-width:_1px)__color=red"]);
+    }
+
+    #[test]
+    fn test_expand_file_paths_directory() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let nested = temp_dir.path().join("styles").join("components");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(temp_dir.path().join("styles").join("base.css"), "").unwrap();
+        fs::write(nested.join("button.css"), "").unwrap();
+        fs::write(nested.join("notes.txt"), "").unwrap();
+
+        let cwd = temp_dir.path().to_path_buf();
+        let mut paths =
+            expand_file_paths(&cwd, &["styles".to_string()], &[], InputKind::Css).unwrap();
+        paths.sort();
+
+        assert_eq!(
+            paths,
+            vec![
+                temp_dir.path().join("styles").join("base.css"),
+                nested.join("button.css"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_html_directory() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let site = temp_dir.path().join("site");
+        fs::create_dir_all(site.join("blog")).unwrap();
+        fs::write(
+            site.join("index.html"),
+            r#"<p class="a">/*</p><p class="b">*/</p>"#,
+        )
+        .unwrap();
+        fs::write(site.join("blog").join("post.htm"), r#"<p class="c">"#).unwrap();
+        fs::write(site.join("site.css"), ".d { color: red }").unwrap();
+
+        let extraction = run_html_extraction(
+            vec![site.to_string_lossy().into_owned()],
+            &[],
+            RunLimits::default(),
+        )
+        .unwrap();
+
+        assert_eq!(extraction.classes, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_expand_file_paths_exclude() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let vendor = temp_dir.path().join("vendor");
+        fs::create_dir_all(&vendor).unwrap();
+        fs::write(temp_dir.path().join("app.css"), "").unwrap();
+        fs::write(vendor.join("lib.css"), "").unwrap();
+
+        let cwd = temp_dir.path().to_path_buf();
+        let paths = expand_file_paths(
+            &cwd,
+            &["**/*.css".to_string()],
+            &exclude_patterns(&cwd, &["vendor/**".to_string()], InputKind::Css).unwrap(),
+            InputKind::Css,
+        )
+        .unwrap();
+
+        assert_eq!(paths, vec![temp_dir.path().join("app.css")]);
+    }
+
+    #[test]
+    fn test_to_rust_source() {
+        let css = r#".btn { color: red; content: "a\"b"; } .card { margin: 0; }"#;
+        let source = transmute_content_to_struct(css, false, false, &ParseOptions::default())
+            .unwrap()
+            .to_rust_source();
+
+        assert!(source.contains("pub static TRANSMUTED: &[(&str, &[&str])] = &["));
+        assert!(source.contains(r#"("card", &["margin=0"]),"#));
+        assert!(source.contains(r#""color=red""#));
+        assert!(source.contains("pub fn transmuted() -> HashMap<&'static str"));
+    }
+
+    #[test]
+    fn test_collapse_single() {
+        let css = ".single { color: red; } .multi { color: blue; margin: 0; }";
+        let mut transmuted =
+            transmute_content_to_struct(css, true, false, &ParseOptions::default()).unwrap();
+        transmuted.collapse_single();
+        let json: serde_json::Value = serde_json::from_str(&transmuted.to_json().unwrap()).unwrap();
+
+        let scroll = |name: &str| {
+            json["scrolls"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|s| s["name"] == name)
+                .unwrap()
+                .clone()
+        };
+
+        let single = scroll("single");
+        assert_eq!(single["spell"], "color=red");
+        assert!(single.get("spells").is_none());
+        assert!(single.get("oneliner").is_none());
+
+        let multi = scroll("multi");
+        assert!(multi.get("spell").is_none());
+        assert_eq!(multi["spells"].as_array().unwrap().len(), 2);
+        assert!(multi.get("oneliner").is_some());
+    }
+
+    fn sorted_scrolls(transmuted: &Transmuted) -> Vec<(String, Vec<String>)> {
+        let mut scrolls: Vec<_> = transmuted
+            .scrolls
+            .iter()
+            .map(|scroll| {
+                let mut spells = scroll.spells.clone();
+                spells.sort();
+                (scroll.name.clone(), spells)
+            })
+            .collect();
+        scrolls.sort();
+        scrolls
+    }
+
+    /// Writes `files` (name and content) to a temporary directory and
+    /// transmutes them in paths mode, see [`transmute_dir`].
+    fn transmute_files(
+        files: &[(&str, &str)],
+        options: &ParseOptions,
+    ) -> Result<Transmuted, GrimoireCssError> {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in files {
+            fs::write(dir.path().join(name), content).unwrap();
+        }
+        transmute_dir(dir.path(), options)
+    }
+
+    /// Transmutes every CSS file in `dir` with the default limits.
+    fn transmute_dir(dir: &Path, options: &ParseOptions) -> Result<Transmuted, GrimoireCssError> {
+        let pattern = dir.join("*.css").to_string_lossy().into_owned();
+        run_transmutation_to_struct_with(
+            vec![pattern],
+            &TransmuteOptions::new().parse(options.clone()),
+            RunHooks::default(),
+        )
+    }
+
+    #[test]
+    fn test_many_files_match_serial_parse() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut all_css = String::new();
+
+        for i in 0..200 {
+            let css = format!(
+                ".c{i} {{ color: red; }} .shared {{ margin: {i}px; }} \
+                 @media (min-width: {i}px) {{ .c{i} {{ display: none; }} }}\n"
+            );
+            fs::write(temp_dir.path().join(format!("f{i}.css")), &css).unwrap();
+            all_css.push_str(&css);
+        }
+
+        let parallel = transmute_dir(temp_dir.path(), &ParseOptions::default()).unwrap();
+        let serial =
+            transmute_content_to_struct(&all_css, false, false, &ParseOptions::default()).unwrap();
+
+        assert_eq!(sorted_scrolls(&parallel), sorted_scrolls(&serial));
+        assert_eq!(parallel.scrolls.len(), 201);
+    }
+
+    #[test]
+    fn test_files_read_separately() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let paths = [temp_dir.path().join("a.css"), temp_dir.path().join("b.css")];
+        // An unterminated comment stays within its file
+        fs::write(&paths[0], ".a { color: red } /* open").unwrap();
+        fs::write(&paths[1], ".b { color: blue }").unwrap();
+
+        let bundle = read_and_clean_files(
+            &paths,
+            InputKind::Css,
+            &[],
+            &RunLimits::default(),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(bundle.files.len(), 2);
+        assert_eq!(bundle.files[1].content, ".b { color: blue }");
+        assert_eq!(bundle.files[1].offset, bundle.files[0].content.len());
+
+        let span = bundle.locate(&(bundle.files[1].offset + 5..bundle.files[1].offset + 16));
+        assert!(span.file.unwrap().ends_with("b.css"));
+        assert_eq!((span.start, span.end), (5, 16));
+    }
+
+    #[test]
+    fn test_files_parse_independently() {
+        // A dangling selector must not attach itself to the next file's rule
+        let files = [("a.css", ".a"), ("b.css", ".b { color: red; }")];
+        let transmuted = transmute_files(&files, &ParseOptions::default()).unwrap();
+
+        assert_eq!(
+            sorted_scrolls(&transmuted),
+            vec![("b".to_string(), vec!["color=red".to_string()])]
+        );
+    }
+
+    fn spells_of(css: &str, name: &str) -> Vec<String> {
+        let transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+        let mut spells = transmuted
+            .scrolls
+            .into_iter()
+            .find(|scroll| scroll.name == name)
+            .unwrap()
+            .spells;
+        spells.sort();
+        spells
+    }
+
+    #[test]
+    fn test_colon_pseudo_handling() {
+        assert_eq!(
+            spells_of(".a:hover { color: red }", "a"),
+            ["{:hover}color=red"]
+        );
+        assert_eq!(
+            spells_of(".a:nth-child(2) { color: red }", "a"),
+            ["{:nth-child(2)}color=red"]
+        );
+        assert_eq!(
+            spells_of(".a::before { color: red }", "a"),
+            ["{::before}color=red"]
+        );
+
+        // A pseudo after a combinator keeps both, for idents and functions alike
+        assert_eq!(
+            spells_of(".a > :first-child { color: red }", "a"),
+            ["{_>_:first-child}color=red"]
+        );
+        assert_eq!(
+            spells_of(".a > :nth-child(2) { color: red }", "a"),
+            ["{_>_:nth-child(2)}color=red"]
+        );
+    }
+
+    #[test]
+    fn test_combinator_selectors() {
+        assert_eq!(
+            spells_of(".card > .title { color: red }", "card"),
+            ["{_>_.title}color=red"]
+        );
+        assert_eq!(
+            spells_of(".card > title { color: red }", "card"),
+            ["{_>_title}color=red"]
+        );
+        assert_eq!(spells_of(".a + .b { margin: 0 }", "a"), ["{_+_.b}margin=0"]);
+        assert_eq!(spells_of(".a ~ .b { margin: 0 }", "a"), ["{_~_.b}margin=0"]);
+        assert_eq!(
+            spells_of(".a > p > span { color: red }", "a"),
+            ["{_>_p_>_span}color=red"]
+        );
+        assert_eq!(spells_of(".a > * { color: red }", "a"), ["{_>_*}color=red"]);
+    }
+
+    #[test]
+    fn test_compound_class_selector() {
+        let transmuted = transmute_content_to_struct(
+            ".btn.primary { color: red }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(transmuted.scrolls.len(), 1);
+        assert_eq!(transmuted.scrolls[0].name, "btn.primary");
+        assert_eq!(transmuted.scrolls[0].spells, ["color=red"]);
+
+        // Whitespace still separates the classes
+        assert_eq!(spells_of(".a .b { color: red }", "a"), ["{_.b}color=red"]);
+    }
+
+    #[test]
+    fn test_group_breakpoints() {
+        let css = r#"
+            .grid { display: block; }
+            @media (min-width: 768px) { .grid { display: flex; } }
+            @media (min-width: 1024px) { .grid { display: grid; } }
+        "#;
+        let mut transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+        transmuted.group_breakpoints(&[
+            ("md".to_string(), "(min-width:768px)".to_string()),
+            ("lg".to_string(), "(min-width: 1024px)".to_string()),
+        ]);
+
+        let variants = &transmuted.responsive["grid"];
+        assert_eq!(variants.len(), 3);
+        assert_eq!(variants["base"], ["display=block"]);
+        assert_eq!(variants["md"], ["display=flex"]);
+        assert_eq!(variants["lg"], ["display=grid"]);
+    }
+
+    #[test]
+    fn test_stray_semicolons() {
+        let css = ";.a{color:red};; ; .b{color:blue};";
+        assert_eq!(spells_of(css, "a"), ["color=red"]);
+        assert_eq!(spells_of(css, "b"), ["color=blue"]);
+
+        // A dangling selector ended by `;` does not leak into the next rule
+        let transmuted = transmute_content_to_struct(
+            ".a:hover;.b{color:blue}",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(transmuted.scrolls.len(), 1);
+        assert_eq!(transmuted.scrolls[0].name, "b");
+        assert_eq!(transmuted.scrolls[0].spells, ["color=blue"]);
+        assert_eq!(transmuted.notices.len(), 1);
+    }
+
+    #[test]
+    fn test_descendant_combinator() {
+        let transmuted = transmute_content_to_struct(
+            ".a .b { display: none }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(transmuted.scrolls.len(), 1);
+        assert_eq!(transmuted.scrolls[0].name, "a");
+        assert_eq!(transmuted.scrolls[0].spells, ["{_.b}display=none"]);
+
+        assert_eq!(
+            spells_of(".menu li { color: red }", "menu"),
+            ["{_li}color=red"]
+        );
+        assert_eq!(
+            spells_of(".menu :hover { color: red }", "menu"),
+            ["{_:hover}color=red"]
+        );
+        assert_eq!(
+            spells_of(".menu [data-open] { color: red }", "menu"),
+            ["{_[data-open]}color=red"]
+        );
+        assert_eq!(
+            spells_of(".menu:hover .item { color: red }", "menu"),
+            ["{:hover_.item}color=red"]
+        );
+        // Whitespace around explicit combinators does not add a descendant step
+        assert_eq!(
+            spells_of(".menu > .item { color: red }", "menu"),
+            ["{_>_.item}color=red"]
+        );
+    }
+
+    #[test]
+    fn test_id_selectors() {
+        let transmuted = transmute_content_to_struct(
+            "#header { background: black }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(transmuted.scrolls.len(), 1);
+        assert_eq!(transmuted.scrolls[0].name, "#header");
+        assert_eq!(transmuted.scrolls[0].spells, ["background=black"]);
+
+        assert_eq!(
+            spells_of(".page #header { color: red }", "page"),
+            ["{_#header}color=red"]
+        );
+    }
+
+    #[test]
+    fn test_functional_pseudo_classes() {
+        assert_eq!(
+            spells_of(".btn:not(.disabled) { color: red }", "btn"),
+            ["{:not(.disabled)}color=red"]
+        );
+        assert_eq!(
+            spells_of("li:nth-child(odd) { color: red }", "li"),
+            ["{:nth-child(odd)}color=red"]
+        );
+        assert_eq!(
+            spells_of(".btn:not( .a,  .b ) { color: red }", "btn"),
+            ["{:not(.a,_.b)}color=red"]
+        );
+        assert_eq!(
+            spells_of(".card:has(> img) { padding: 0 }", "card"),
+            ["{:has(>_img)}padding=0"]
+        );
+    }
+
+    #[test]
+    fn test_group_breakpoints_color_scheme() {
+        let css = r#"
+            .card { color: black; }
+            @media screen and (prefers-color-scheme: dark) { .card { color: white; } }
+            @media (prefers-color-scheme: light) { .card { border: 0; } }
+        "#;
+
+        let mut transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+        transmuted.group_breakpoints(&[(
+            "night".to_string(),
+            "(prefers-color-scheme: dark)".to_string(),
+        )]);
+        let variants = &transmuted.responsive["card"];
+        assert_eq!(variants["base"], ["color=black"]);
+        assert_eq!(variants["night"], ["color=white"]);
+        // Unaliased color schemes are keyed by the scheme itself
+        assert_eq!(variants["light"], ["border=0"]);
+    }
+
+    #[test]
+    fn test_normalize_target() {
+        let cases = [
+            ("red", "red"),
+            ("  red  ", "red"),
+            ("red !important", "red!"),
+            ("red ! IMPORTANT ", "red!"),
+            ("0 10px", "0_10px"),
+            ("rgb( 0 ,0,  0 )", "rgb(0,_0,_0)"),
+            ("calc(100% - 20px)", "calc(100%_-_20px)"),
+            ("calc( (1px + 2px) * 3 )", "calc((1px_+_2px)_*_3)"),
+            ("\"Open  Sans\" , serif", "\"Open__Sans\",_serif"),
+            ("'a\\'b c'", "\"a'b_c\""),
+            ("\"\\2192\"", "\"→\""),
+            ("'unterminated", "'unterminated"),
+            ("12px / 1.5 serif", "12px/1.5_serif"),
+            ("1 / 2 / 3", "1/2/3"),
+            ("url(a.png) /* bg */ no-repeat", "url(a.png)_no-repeat"),
+            ("var( --gap , 4px )", "var(--gap,_4px)"),
+        ];
+
+        for (raw, expected) in cases {
+            assert_eq!(normalize_target(raw), expected, "normalizing {raw:?}");
+        }
+    }
+
+    #[test]
+    fn test_multiline_shorthand_whitespace() {
+        let css_input = ".box {\n  margin: 0   10px\n      20px\t 5px;\n  font: 12px\n  serif;\n}";
+
+        assert_eq!(
+            spells_of(css_input, "box"),
+            vec!["font=12px_serif", "margin=0_10px_20px_5px"]
+        );
+    }
+
+    #[test]
+    fn test_yaml_round_trip() {
+        let css = ".btn { color: red; } .card { margin: 0; padding: 0; }";
+        let mut transmuted =
+            transmute_content_to_struct(css, true, false, &ParseOptions::default()).unwrap();
+        transmuted.collapse_single();
+
+        let (_, yaml) = transmute_from_content_with(
+            css,
+            &TransmuteOptions::new()
+                .include_oneliner(true)
+                .format(OutputFormat::Yaml),
+        )
+        .unwrap();
+        assert!(yaml.contains("scrolls:"));
+
+        // The source map, notices, stats, histogram and timings are not part of
+        // the serialized output
+        transmuted.source_map.clear();
+        transmuted.stats = TransmutationStats::default();
+        transmuted.property_histogram.clear();
+        transmuted.timings = Timings::default();
+        let parsed: Transmuted = serde_norway::from_str(&transmuted.to_yaml().unwrap()).unwrap();
+        assert_eq!(parsed, transmuted);
+    }
+
+    #[test]
+    fn test_minified_json() {
+        let css = ".btn { color: red; } @media print { .btn { display: none; } }";
+        let transmuted =
+            transmute_content_to_struct(css, true, false, &ParseOptions::default()).unwrap();
+        let pretty = transmuted.serialize_as(OutputFormat::Json, false).unwrap();
+        let minified = transmuted.serialize_as(OutputFormat::Json, true).unwrap();
+
+        assert!(pretty.contains('\n'));
+        assert!(!minified.contains('\n'));
+
+        let pretty: serde_json::Value = serde_json::from_str(&pretty).unwrap();
+        let minified: serde_json::Value = serde_json::from_str(&minified).unwrap();
+        assert_eq!(pretty, minified);
+
+        let (_, content_output) =
+            transmute_from_content_with(css, &TransmuteOptions::new().minify(true)).unwrap();
+        assert!(!content_output.contains('\n'));
+    }
+
+    #[test]
+    fn test_run_transmutation_per_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(temp_dir.path().join("a.css"), ".a { color: red; }").unwrap();
+        fs::write(temp_dir.path().join("b.css"), ".b { color: blue; }").unwrap();
+        fs::write(temp_dir.path().join("empty.css"), "/* nothing */").unwrap();
+
+        let pattern = temp_dir.path().join("*.css").to_string_lossy().into_owned();
+        let mut results = run_transmutation_per_file_with(
+            vec![pattern],
+            &TransmuteOptions::new(),
+            RunHooks::default(),
+        )
+        .unwrap();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].0, temp_dir.path().join("a.css"));
+        assert_eq!(
+            sorted_scrolls(&results[0].1),
+            [("a".to_string(), vec!["color=red".to_string()])]
+        );
+        assert_eq!(
+            sorted_scrolls(&results[1].1),
+            [("b".to_string(), vec!["color=blue".to_string()])]
+        );
+        assert!(results[2].1.scrolls.is_empty());
+    }
+
+    #[test]
+    fn test_source_selector() {
+        let css = ".btn:hover { color: red } .btn , .link { margin: 0 }";
+        let transmuted =
+            transmute_content_to_struct(css, false, true, &ParseOptions::default()).unwrap();
+        let selector = |name: &str| {
+            transmuted
+                .scrolls
+                .iter()
+                .find(|scroll| scroll.name == name)
+                .and_then(|scroll| scroll.source_selector.clone())
+        };
+
+        assert_eq!(selector("btn").as_deref(), Some(".btn:hover, .btn , .link"));
+        assert_eq!(selector("link").as_deref(), Some(".btn , .link"));
+
+        let without =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+        assert!(without.scrolls.iter().all(|s| s.source_selector.is_none()));
+        assert!(!without.to_json().unwrap().contains("source_selector"));
+    }
+
+    #[test]
+    fn test_transmutation_stats() {
+        let css = r#"
+            .a, .b { color: red; margin: 0; }
+            .a { color: red; }
+            .c { padding: 0; }
+            @media print { .a { display: none; } }
+        "#;
+        let stats = transmute_content_to_struct(css, false, false, &ParseOptions::default())
+            .unwrap()
+            .stats;
+
+        assert_eq!(stats.rules, 4);
+        assert_eq!(stats.selectors, 5);
+        assert_eq!(stats.skipped_spells, 0);
+        assert_eq!(stats.scrolls, 3);
+        assert_eq!(stats.spells_generated, 7);
+        assert_eq!(stats.spells, 6);
+        assert_eq!(stats.average_spells_per_scroll(), 2.0);
+        assert!((stats.dedup_ratio() - 1.0 / 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let transmuted = transmute_content_to_struct(
+            ".a { color: red; }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&transmuted.to_json().unwrap()).unwrap();
+
+        assert_eq!(json["version"], SCHEMA_VERSION);
+        assert_eq!(json["generator"], GENERATOR);
+        assert!(GENERATOR.ends_with(env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn test_group_by_area() {
+        let mut transmuted = transmute_content_to_struct(
+            ".a { color: red; } @media (min-width: 600px) { .a { color: blue; } }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        transmuted.group_by_area();
+
+        assert!(transmuted.scrolls.is_empty());
+        assert_eq!(transmuted.areas.len(), 2);
+        assert_eq!(transmuted.areas["base"][0].name, "a");
+        assert_eq!(transmuted.areas["base"][0].spells, vec!["color=red"]);
+        assert_eq!(transmuted.areas["(min-width:_600px)"][0].name, "a");
+        assert_eq!(
+            transmuted.areas["(min-width:_600px)"][0].spells,
+            vec!["color=blue"]
+        );
+    }
+
+    #[test]
+    fn test_repeated_property_last_wins() {
+        assert_eq!(
+            spells_of(".a { color: red; color: blue; }", "a"),
+            vec!["color=blue"]
+        );
+        assert_eq!(
+            spells_of(".a { color: red !important; color: blue; }", "a"),
+            vec!["color=red!"]
+        );
+
+        let options = ParseOptions {
+            keep_duplicate_properties: true,
+            ..Default::default()
+        };
+        let transmuted = transmute_content_to_struct(
+            ".a { background: red; background: url(a.png), red; }",
+            false,
+            false,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(
+            transmuted.scrolls[0].spells,
+            vec!["background=red", "background=url(a.png),_red"]
+        );
+    }
+
+    #[test]
+    fn test_url_value_keeps_colons() {
+        assert_eq!(
+            spells_of(".a { background: url(https://x/y.png) no-repeat; }", "a"),
+            vec!["background=url(https://x/y.png)_no-repeat"]
+        );
+        assert_eq!(
+            spells_of(".a { background: url('https://x/y.png') }", "a"),
+            vec!["background=url(\"https://x/y.png\")"]
+        );
+        assert_eq!(
+            spells_of(".a { filter: progid:DXImage.Alpha(opacity=50) }", "a"),
+            vec!["filter=progid:DXImage.Alpha(opacity=50)"]
+        );
+    }
+
+    #[test]
+    fn test_function_values() {
+        assert_eq!(
+            spells_of(".a { width: calc( 100% - (2 * 10px) ) }", "a"),
+            vec!["width=calc(100%_-_(2_*_10px))"]
+        );
+        assert_eq!(
+            spells_of(".a { color: var(--brand, #000); }", "a"),
+            vec!["color=var(--brand,_#000)"]
+        );
+        assert_eq!(
+            spells_of(".a { width: min(100%, max(20rem, 50vw)); }", "a"),
+            vec!["width=min(100%,_max(20rem,_50vw))"]
+        );
+    }
+
+    #[test]
+    fn test_transmute_declarations() {
+        assert_eq!(
+            transmute_declarations("color: red").unwrap(),
+            vec!["color=red"]
+        );
+        assert_eq!(
+            transmute_declarations("color: red; padding: 4px 8px !important; --x: 1;").unwrap(),
+            vec!["color=red", "padding=4px_8px!"]
+        );
+        assert!(transmute_declarations("  ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_extract_from_html() {
+        let html = r#"<!DOCTYPE html>
+            <!-- <p style="color: gray"> -->
+            <div class="card  shadow" style="padding: 4px; color: red" style="color: blue">
+              <P CLASS='title' STYLE='font-weight: bold; content: &quot;a&quot;'>Hi</P>
+              <span class="card">x</span>
+              <script>let s = '<b style="color: green">';</script>
+            </div>"#;
+
+        let extraction = extract_from_html(html).unwrap();
+
+        assert_eq!(
+            extraction.inline_styles,
+            vec![
+                InlineStyle {
+                    element: "div".into(),
+                    classes: vec!["card".into(), "shadow".into()],
+                    spells: vec!["padding=4px".into(), "color=red".into()],
+                },
+                InlineStyle {
+                    element: "p".into(),
+                    classes: vec!["title".into()],
+                    spells: vec!["font-weight=bold".into(), "content=\"a\"".into()],
+                },
+            ]
+        );
+        assert_eq!(extraction.classes, vec!["card", "shadow", "title"]);
+    }
+
+    #[test]
+    fn test_transmute_to_json_or_error() {
+        let json: serde_json::Value =
+            serde_json::from_str(&transmute_to_json_or_error(".a { color: red; }", true)).unwrap();
+        assert_eq!(json["scrolls"][0]["name"], "a");
+        assert_eq!(json["scrolls"][0]["oneliner"], "color=red");
+
+        let error: serde_json::Value =
+            serde_json::from_str(&transmute_to_json_or_error("", false)).unwrap();
+        assert!(error["error"].as_str().is_some());
+    }
+
+    #[test]
+    fn test_area_separator() {
+        let css = "@media (min-width: 600px) { .block__element .icon__svg { color: red } }";
+
+        let default =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            default.scrolls[0].spells,
+            vec!["(min-width:_600px)__{_.icon__svg}color=red"]
+        );
+
+        let options = ParseOptions {
+            area_separator: "@".into(),
+            ..Default::default()
+        };
+        let mut transmuted = transmute_content_to_struct(css, false, false, &options).unwrap();
+        assert_eq!(transmuted.scrolls[0].name, "block__element");
+        assert_eq!(
+            transmuted.scrolls[0].spells,
+            vec!["(min-width:_600px)@{_.icon__svg}color=red"]
+        );
+        assert!(transmuted
+            .to_json()
+            .unwrap()
+            .contains(r#""area_separator": "@""#));
+
+        transmuted.group_by_area();
+        assert_eq!(
+            transmuted.areas["(min-width:_600px)"][0].spells,
+            vec!["{_.icon__svg}color=red"]
+        );
+    }
+
+    #[test]
+    fn test_lowercase_options() {
+        let css = ".a { COLOR: RED; color: red; } .b { Background: URL(Img.PNG) \"Q\" }";
+
+        let preserved =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+        assert_eq!(preserved.scrolls[0].spells, vec!["COLOR=RED", "color=red"]);
+
+        let options = ParseOptions {
+            lowercase_properties: true,
+            lowercase_values: true,
+            ..Default::default()
+        };
+        let transmuted = transmute_content_to_struct(css, false, false, &options).unwrap();
+        assert_eq!(transmuted.scrolls[0].spells, vec!["color=red"]);
+        assert_eq!(
+            transmuted.scrolls[1].spells,
+            vec!["background=url(Img.PNG)_\"Q\""]
+        );
+    }
+
+    #[test]
+    fn test_spell_error_location() {
+        // The block of `@media print {` opened at the end of the second line
+        let state = ParserState {
+            line_offset: 1,
+            column_offset: 14,
+            ..Default::default()
+        };
+        let nested = "\n  .b .c { color: blue; }\n";
+        let mut nested_input = ParserInput::new(nested);
+        let mut nested_parser = Parser::new(&mut nested_input);
+        while !matches!(nested_parser.next(), Ok(Token::CurlyBracketBlock) | Err(_)) {}
+        assert_eq!(state.source_location(&nested_parser), (3, 10));
+
+        let err = with_rule_location(
+            GrimoireCssError::InvalidSpellFormat("unbalanced focus".into()),
+            ".b .c",
+            state.source_location(&nested_parser),
+        );
+        let message = err.to_string();
+        assert!(message.contains("unbalanced focus"));
+        assert!(message.contains("'.b .c' at line 3, column 10"));
+
+        // The kind of the error is kept, only the message gains the location
+        let location = state.source_location(&nested_parser);
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "config gone");
+        match with_rule_location(GrimoireCssError::Io(io), ".io", location) {
+            GrimoireCssError::Io(err) => {
+                assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+                assert!(err
+                    .to_string()
+                    .contains("config gone (in rule '.io' at line 3"));
+            }
+            other => panic!("expected an I/O error, got {other:?}"),
+        }
+        let serde = serde_json::from_str::<u8>("x").unwrap_err();
+        match with_rule_location(GrimoireCssError::Serde(serde), ".serde", location) {
+            GrimoireCssError::Serde(err) => assert!(err.to_string().contains("in rule '.serde'")),
+            other => panic!("expected a serialization error, got {other:?}"),
+        }
+
+        // Invalid input keeps its kind through the public entry point too
+        let err =
+            transmute_from_content_with(".a { color: red", &TransmuteOptions::new()).unwrap_err();
+        assert!(matches!(err, GrimoireCssError::InvalidInput(_)), "{err:?}");
+    }
+
+    #[test]
+    fn test_repeated_selector_prefixes() {
+        let mut state = ParserState {
+            current_class: "a".into(),
+            ..Default::default()
+        };
+        state.push_raw_spell("{_.b}".into());
+        state.push_raw_spell(String::new());
+        state.push_raw_spell("{_.b}".into());
+        assert_eq!(state.raw_classes_spells_map["a"], vec!["{_.b}", ""]);
+
+        let transmuted = transmute_content_to_struct(
+            ".a, .b, .a { color: red; }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(transmuted.scrolls[0].spells, vec!["color=red"]);
+        assert_eq!(transmuted.scrolls[1].spells, vec!["color=red"]);
+        assert_eq!(transmuted.stats.spells_generated, 2);
+    }
+
+    #[test]
+    fn test_empty_input_errors() {
+        let message = |css: &str| match transmute_content_to_struct(
+            css,
+            false,
+            false,
+            &ParseOptions::default(),
+        ) {
+            Err(GrimoireCssError::InvalidInput(message)) => message,
+            other => panic!("expected an invalid input error, got {other:?}"),
+        };
+
+        assert!(message("").contains("input is empty"));
+        assert!(message(" \n\t/* nothing */\n").contains("input is empty"));
+        assert!(message("@import url(\"base.css\");").contains("no rules that produce spells"));
+    }
+
+    #[test]
+    fn test_attribute_selector_modifier() {
+        assert_eq!(
+            spells_of(r#".a[data-x="y" i] { color: red }"#, "a"),
+            [r#"{[data-x="y"_i]}color=red"#]
+        );
+        assert_eq!(
+            spells_of(".a[ data-x = y S ] { color: red }", "a"),
+            [r#"{[data-x="y"_s]}color=red"#]
+        );
+        assert_eq!(
+            spells_of(".a[data-x='y'] { color: red }", "a"),
+            [r#"{[data-x="y"]}color=red"#]
+        );
+        assert_eq!(
+            spells_of(r#".a[lang|="en" i] { color: red }"#, "a"),
+            [r#"{[lang|="en"_i]}color=red"#]
+        );
+    }
+
+    #[test]
+    fn test_escaped_class_names() {
+        let css = r".w-1\/2 { width: 50% } .md\:flex { display: flex } .list .w-1\/2 { margin: 0 }";
+        let transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+
+        assert_eq!(transmuted.scrolls[0].name, "w-1/2");
+        assert_eq!(transmuted.scrolls[0].spells, vec!["width=50%"]);
+        assert_eq!(transmuted.scrolls[1].name, "md:flex");
+        assert_eq!(transmuted.scrolls[2].name, "list");
+        assert_eq!(transmuted.scrolls[2].spells, vec![r"{_.w-1\/2}margin=0"]);
+    }
+
+    #[test]
+    fn test_write_as_matches_serialize_as() {
+        let transmuted = transmute_content_to_struct(
+            ".a { color: red; } @media print { .a { display: none; } }",
+            true,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        for (format, minify) in [
+            (OutputFormat::Json, false),
+            (OutputFormat::Json, true),
+            (OutputFormat::Yaml, false),
+            (OutputFormat::Toml, false),
+        ] {
+            let path = temp_dir.path().join("out");
+            transmuted
+                .write_as(fs::File::create(&path).unwrap(), format, minify)
+                .unwrap();
+
+            assert_eq!(
+                fs::read_to_string(&path).unwrap(),
+                transmuted.serialize_as(format, minify).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_timings_add_up() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        for i in 0..20 {
+            let css: String = (0..50)
+                .map(|j| format!(".c{i}-{j} {{ color: red; margin: {j}px; }}\n"))
+                .collect();
+            fs::write(temp_dir.path().join(format!("{i}.css")), css).unwrap();
+        }
+        let pattern = temp_dir.path().join("*.css").to_string_lossy().into_owned();
+
+        let (timings, _) =
+            run_transmutation_with(vec![pattern], &TransmuteOptions::new(), RunHooks::default())
+                .unwrap();
+
+        let phases = timings.read + timings.parse + timings.serialize;
+        assert!(timings.read > Duration::ZERO && timings.parse > Duration::ZERO);
+        assert!(phases <= timings.total);
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(
+                temp_dir.path().join(format!("{name}.css")),
+                format!(".{name} {{ color: red; }}"),
+            )
+            .unwrap();
+        }
+        let pattern = temp_dir.path().join("*.css").to_string_lossy().into_owned();
+
+        let mut calls = Vec::new();
+        run_transmutation_to_struct_with(
+            vec![pattern],
+            &TransmuteOptions::new(),
+            RunHooks {
+                progress: Some(&mut |done, total, path: &Path| {
+                    calls.push((done, total, path.file_name().unwrap().to_owned()))
+                }),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            calls,
+            vec![
+                (1, 3, "a.css".into()),
+                (2, 3, "b.css".into()),
+                (3, 3, "c.css".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gzipped_file_matches_plain_twin() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let css = ".btn { color: red; } .btn:hover { color: blue; }";
+        let plain = temp_dir.path().join("plain.css");
+        fs::write(&plain, css).unwrap();
+
+        let gzipped = temp_dir.path().join("gzipped.css.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(css.as_bytes()).unwrap();
+        fs::write(&gzipped, encoder.finish().unwrap()).unwrap();
+
+        let transmute = |path: &Path| {
+            run_transmutation_to_struct_with(
+                vec![path.to_string_lossy().into_owned()],
+                &TransmuteOptions::new(),
+                RunHooks::default(),
+            )
+            .unwrap()
+            .scrolls
+        };
+
+        assert_eq!(transmute(&gzipped), transmute(&plain));
+    }
+
+    #[test]
+    fn test_gzip_size_limits() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let css = ".a { color: red; }\n".repeat(1000);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(css.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed.len() < 1000);
+
+        fs::write(temp_dir.path().join("a.css.gz"), &compressed).unwrap();
+        fs::write(temp_dir.path().join("b.css.gz"), &compressed).unwrap();
+        let pattern = temp_dir.path().join("*.gz").to_string_lossy().into_owned();
+
+        let transmute = |limits: RunLimits| {
+            run_transmutation_to_struct_with(
+                vec![pattern.clone()],
+                &TransmuteOptions::new().limits(limits),
+                RunHooks::default(),
+            )
+        };
+
+        match transmute(RunLimits {
+            max_file_size: 1000,
+            ..RunLimits::default()
+        }) {
+            Err(GrimoireCssError::InvalidInput(msg)) => {
+                assert!(msg.contains("a.css.gz"), "{msg}");
+                assert!(
+                    msg.contains("over the limit of 1000 bytes per file"),
+                    "{msg}"
+                );
+            }
+            other => panic!("expected size limit error, got {other:?}"),
+        }
+
+        match transmute(RunLimits {
+            max_total_size: css.len() as u64 + 1,
+            ..RunLimits::default()
+        }) {
+            Err(GrimoireCssError::InvalidInput(msg)) => {
+                assert!(msg.contains("over the limit of"), "{msg}");
+            }
+            other => panic!("expected size limit error, got {other:?}"),
+        }
+
+        assert!(transmute(RunLimits::default()).is_ok());
+    }
+
+    #[test]
+    fn test_content_strings_stay_quoted() {
+        assert_eq!(
+            spells_of(".a::before { content: \"→\"; }", "a"),
+            vec!["{::before}content=\"→\""]
+        );
+        assert_eq!(
+            spells_of(".a::after { content: 'say \"hi\"' }", "a"),
+            vec!["{::after}content=\"say_\\\"hi\\\"\""]
+        );
+    }
+
+    #[test]
+    fn test_keyframes_do_not_become_scrolls() {
+        let css =
+            "@keyframes spin { from { transform: rotate(0) } to { transform: rotate(360deg) } }
+                   @-webkit-keyframes fade { 0%, 50% { opacity: 0 } }
+                   .btn { color: red }";
+        let transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+
+        let names: Vec<_> = transmuted.scrolls.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["btn"]);
+        assert_eq!(
+            transmuted.keyframes["spin"]["from"],
+            ["transform=rotate(0)"]
+        );
+        assert_eq!(
+            transmuted.keyframes["spin"]["to"],
+            ["transform=rotate(360deg)"]
+        );
+        assert_eq!(transmuted.keyframes["fade"]["50%"], ["opacity=0"]);
+    }
+
+    #[test]
+    fn test_selectorless_at_rules_are_not_scrolls() {
+        let css = "@font-face { font-family: 'X'; src: url(x.woff2) }
+                   @page :first { margin: 1cm }
+                   .btn { color: red }";
+        let transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+
+        let names: Vec<_> = transmuted.scrolls.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["btn"]);
+        assert_eq!(
+            transmuted.at_rules,
+            vec![
+                TransmutedAtRule {
+                    name: "font-face".into(),
+                    prelude: None,
+                    spells: vec!["font-family=\"X\"".into(), "src=url(x.woff2)".into()],
+                },
+                TransmutedAtRule {
+                    name: "page".into(),
+                    prelude: Some(":first".into()),
+                    spells: vec!["margin=1cm".into()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oneliner_order() {
+        let css = ".card { transition: none; margin: 0; color: red; display: flex; }";
+        let oneliner = |oneliner_order| {
+            let options = ParseOptions {
+                oneliner_order,
+                ..Default::default()
+            };
+            let transmuted = transmute_content_to_struct(css, true, false, &options).unwrap();
+            transmuted.scrolls[0].oneliner.clone().unwrap()
+        };
+
+        assert_eq!(
+            oneliner(OnelinerOrder::Alphabetical),
+            "color=red display=flex margin=0 transition=none"
+        );
+        assert_eq!(
+            oneliner(OnelinerOrder::Source),
+            "transition=none margin=0 color=red display=flex"
+        );
+        assert_eq!(
+            oneliner(OnelinerOrder::Category),
+            "display=flex margin=0 color=red transition=none"
+        );
+    }
+
+    #[test]
+    fn test_skip_properties() {
+        let css = ".icon::before { content: \"*\"; color: red; }";
+        let options = ParseOptions {
+            skip_properties: vec!["content".into()],
+            ..Default::default()
+        };
+        let transmuted = transmute_content_to_struct(css, false, false, &options).unwrap();
+
+        assert_eq!(transmuted.scrolls[0].spells, vec!["{::before}color=red"]);
+    }
+
+    #[test]
+    fn test_strip_vendor_prefixes() {
+        let css = ".fade {
+            -webkit-transition: opacity 1s;
+            -moz-transition: opacity 1s;
+            transition: opacity 1s;
+        }";
+        let options = ParseOptions {
+            strip_vendor_prefixes: true,
+            ..Default::default()
+        };
+
+        let transmuted = transmute_content_to_struct(css, false, false, &options).unwrap();
+        assert_eq!(transmuted.scrolls[0].spells, vec!["transition=opacity_1s"]);
+
+        let options = ParseOptions {
+            keep_duplicate_properties: true,
+            ..options
+        };
+        let transmuted = transmute_content_to_struct(css, false, false, &options).unwrap();
+        assert_eq!(transmuted.scrolls[0].spells, vec!["transition=opacity_1s"]);
+    }
+
+    #[test]
+    fn test_invalid_scroll_names() {
+        let css = ".w-1\\/2 { width: 50% } .w-1_2 { height: 1px } .card { color: red }";
+        let transmute = |invalid_names| {
+            let options = ParseOptions {
+                invalid_names,
+                ..Default::default()
+            };
+            transmute_content_to_struct(css, false, true, &options).unwrap()
+        };
+        let names = |transmuted: &Transmuted| -> Vec<String> {
+            transmuted.scrolls.iter().map(|s| s.name.clone()).collect()
+        };
+
+        let kept = transmute(InvalidNamePolicy::Keep);
+        assert_eq!(names(&kept), vec!["w-1/2", "w-1_2", "card"]);
+        assert!(kept.warnings.is_empty());
+
+        let sanitized = transmute(InvalidNamePolicy::Sanitize);
+        assert_eq!(names(&sanitized), vec!["w-1_2", "card"]);
+        assert_eq!(sanitized.scrolls[0].spells, vec!["height=1px", "width=50%"]);
+        assert_eq!(
+            sanitized.warnings,
+            vec![NameWarning {
+                scroll: "w-1/2".into(),
+                selector: Some(".w-1\\/2".into()),
+                message: "Renamed to 'w-1_2'.".into(),
+            }]
+        );
+
+        let skipped = transmute(InvalidNamePolicy::Skip);
+        assert_eq!(names(&skipped), vec!["w-1_2", "card"]);
+        assert_eq!(skipped.warnings[0].scroll, "w-1/2");
+        assert!(!skipped.source_map.contains_key("w-1/2"));
+    }
+
+    #[test]
+    fn test_parse_cache_reuses_unchanged_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(temp_dir.path().join("a.css"), ".a { color: red; }").unwrap();
+        fs::write(temp_dir.path().join("b.css"), ".b { margin: 0; }").unwrap();
+        let pattern = temp_dir.path().join("*.css").to_string_lossy().into_owned();
+        let cache_path = temp_dir.path().join("cache").join("cache.json");
+
+        let run = |cache: &mut ParseCache| {
+            run_transmutation_to_struct_with(
+                vec![pattern.clone()],
+                &TransmuteOptions::new(),
+                RunHooks {
+                    cache: Some(cache),
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+
+        let mut cache = ParseCache::load(&cache_path);
+        let first = run(&mut cache);
+        assert_eq!(first.stats.cached_files, 0);
+        cache.save(&cache_path).unwrap();
+        // Written through a temporary file, which is gone once renamed
+        let cache_dir = fs::read_dir(cache_path.parent().unwrap()).unwrap();
+        assert_eq!(cache_dir.count(), 1);
+
+        let mut cache = ParseCache::load(&cache_path);
+        let second = run(&mut cache);
+        assert_eq!(second.stats.cached_files, 2);
+        assert_eq!(second.scrolls, first.scrolls);
+        assert_eq!(second.source_map, first.source_map);
+
+        fs::write(temp_dir.path().join("b.css"), ".b { margin: 1px; }").unwrap();
+        let third = run(&mut cache);
+        assert_eq!(third.stats.cached_files, 1);
+        assert_eq!(third.scrolls[1].spells, vec!["margin=1px"]);
+
+        // Equal options give the same fingerprint whatever the hash order
+        let fingerprint = |shared_spells: HashSet<String>| {
+            let mut cache = ParseCache::default();
+            cache.validate(&ParseOptions {
+                grimoire_config: Some(GrimoireConfig {
+                    scrolls: None,
+                    shared_spells,
+                }),
+                ..Default::default()
+            });
+            cache.options
+        };
+        let shared_spells: HashSet<String> = (0..32).map(|i| format!("spell-{i}")).collect();
+        assert_eq!(
+            fingerprint(shared_spells.iter().cloned().collect()),
+            fingerprint(shared_spells)
+        );
+    }
+
+    #[test]
+    fn test_write_atomically_from_threads() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("out.json");
+
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let path = &path;
+                scope.spawn(move || {
+                    write_atomically(path, |file| {
+                        write!(file, "{i}").map_err(GrimoireCssError::Io)
+                    })
+                    .unwrap()
+                });
+            }
+        });
+        let content: usize = fs::read_to_string(&path).unwrap().parse().unwrap();
+        assert!(content < 8);
+        assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_spell_sources() {
+        let css = ".btn:hover { color: red; margin: 0 auto; }";
+        let options = ParseOptions {
+            spell_sources: true,
+            ..Default::default()
+        };
+        let transmuted = transmute_content_to_struct(css, false, false, &options).unwrap();
+
+        assert_eq!(
+            transmuted.scrolls[0].spell_sources,
+            vec![
+                SpellSource {
+                    spell: "{:hover}color=red".into(),
+                    component: "color=red".into(),
+                    selector: Some(".btn:hover".into()),
+                },
+                SpellSource {
+                    spell: "{:hover}margin=0_auto".into(),
+                    component: "margin=0_auto".into(),
+                    selector: Some(".btn:hover".into()),
+                },
+            ]
+        );
+
+        let plain = transmute_content_to_struct(css, false, false, &ParseOptions::default());
+        assert!(plain.unwrap().scrolls[0].spell_sources.is_empty());
+    }
+
+    #[test]
+    fn test_universal_selector() {
+        assert_eq!(
+            spells_of("* { box-sizing: border-box }", "*"),
+            ["box-sizing=border-box"]
+        );
+        assert_eq!(spells_of("* .a { color: red }", "*"), ["{_.a}color=red"]);
+        assert_eq!(
+            spells_of("* > .child { color: red }", "*"),
+            ["{_>_.child}color=red"]
+        );
+        assert_eq!(spells_of(".a * { color: red }", "a"), ["{_*}color=red"]);
+        assert_eq!(spells_of(".a *.b { color: red }", "a"), ["{_*.b}color=red"]);
+        assert_eq!(spells_of("*.b { color: red }", "b"), ["color=red"]);
+    }
+
+    #[test]
+    fn test_shared_with() {
+        let css = ".a, .b, .c { color: red } .c { margin: 0 } .d { color: red }";
+        let options = ParseOptions {
+            shared_with: true,
+            ..Default::default()
+        };
+        let transmuted = transmute_content_to_struct(css, false, false, &options).unwrap();
+        let shared: Vec<(&str, Vec<String>)> = transmuted
+            .scrolls
+            .iter()
+            .map(|scroll| (scroll.name.as_str(), scroll.shared_with.clone()))
+            .collect();
+
+        assert_eq!(
+            shared,
+            vec![
+                ("a", vec!["b".to_string(), "c".to_string()]),
+                ("b", vec!["a".to_string(), "c".to_string()]),
+                ("c", vec!["a".to_string(), "b".to_string()]),
+                ("d", vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diagnostics_count_dropped_tokens() {
+        let css = "@supports (display: grid) { .a { display: grid } } .b { color: red }";
+        let transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+
+        assert_eq!(transmuted.diagnostics.get("@supports"), Some(&1));
+        assert_eq!(transmuted.scrolls.len(), 1);
+
+        let json = serde_json::to_value(&transmuted).unwrap();
+        assert_eq!(json["diagnostics"]["@supports"], 1);
+
+        let clean = transmute_content_to_struct(
+            ".a { color: red }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert!(clean.diagnostics.is_empty());
+
+        let preludes = transmute_content_to_struct(
+            "@media (min-width: 600px) { .a { color: red } } @layer base { .b { top: 0 } } \
+             @import url(x.css) screen and (min-width: 600px);",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            preludes.diagnostics,
+            BTreeMap::from([("@import".to_string(), 1)])
+        );
+
+        let stray = transmute_content_to_struct(
+            "$ .a { color: red }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            stray.diagnostics,
+            BTreeMap::from([("delim '$'".to_string(), 1)])
+        );
+    }
+
+    #[test]
+    fn test_selector_list_items_start_clean() {
+        let transmuted = transmute_content_to_struct(
+            ".a:hover, .b { color: red }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let scrolls: Vec<(&str, &[String])> = transmuted
+            .scrolls
+            .iter()
+            .map(|scroll| (scroll.name.as_str(), scroll.spells.as_slice()))
+            .collect();
+
+        assert_eq!(
+            scrolls,
+            vec![
+                ("a", &["{:hover}color=red".to_string()][..]),
+                ("b", &["color=red".to_string()][..]),
+            ]
+        );
+    }
 
-                    let base_raw_spell = if focus_str.is_empty() {
-                        String::new()
-                    } else {
-                        format!("{{{focus_str}}}")
-                    };
+    #[test]
+    fn test_transmute_options_builder() {
+        let css = ".btn:hover { COLOR: red } .btn { margin: 0 }";
+        assert_eq!(TransmuteOptions::new(), TransmuteOptions::default());
 
-                    parser_state
-                        .raw_classes_spells_map
-                        .entry(parser_state.current_class.to_owned())
-                        .or_default()
-                        .push(base_raw_spell.clone());
+        let options = TransmuteOptions::new()
+            .include_oneliner(true)
+            .include_source(true)
+            .parse(ParseOptions {
+                lowercase_properties: true,
+                ..Default::default()
+            })
+            .format(OutputFormat::Yaml)
+            .minify(true);
 
-                    parser_state.focus.clear();
-                    parser_state.effects.clear();
-                    parser_state.current_class.clear();
-                    parser_state.class_started = false;
-                    parser_state.focus_delim.clear();
-                }
-            }
-            Token::SquareBracketBlock => {
-                let mut squared_focus = "[".to_string();
-                let start_pos = parser.position();
+        let transmuted = transmute_content_to_struct_with(css, &options).unwrap();
+        let btn = &transmuted.scrolls[0];
+        assert_eq!(btn.spells, vec!["margin=0", "{:hover}color=red"]);
+        assert!(btn.oneliner.is_some());
+        assert!(btn.source_selector.is_some());
 
-                parser
-                    .parse_nested_block(|input| {
-                        while input.next().is_ok() {}
-                        Ok::<(), cssparser::ParseError<'_, ()>>(())
-                    })
-                    .unwrap();
+        let (_, output) = transmute_from_content_with(css, &options).unwrap();
+        assert!(output.starts_with("version:"));
 
-                let slice = parser.slice_from(start_pos);
-                squared_focus.push_str(slice);
+        let (_, json) = transmute_from_content_with(css, &TransmuteOptions::new()).unwrap();
+        let (_, legacy) = transmute_from_content(css, false).unwrap();
+        assert_eq!(json, legacy);
 
-                parser_state.focus.push(squared_focus);
-            }
-            Token::CurlyBracketBlock => {
-                if let Some(start_media_pos) = parser_state.started_media_pos {
-                    let slice = parser.slice_from(start_media_pos);
-                    let trimmed_slice = slice
-                        .char_indices()
-                        .next_back()
-                        .map_or(slice, |(i, _)| &slice[..i])
-                        .trim()
-                        .replace(" ", "_");
+        // Paths mode takes the same options
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(temp_dir.path().join("a.css"), css).unwrap();
+        let args = vec![temp_dir.path().join("a.css").to_string_lossy().into_owned()];
+        let merged =
+            run_transmutation_to_struct_with(args.clone(), &options, RunHooks::default()).unwrap();
+        assert_eq!(merged.scrolls, transmuted.scrolls);
+        let per_file =
+            run_transmutation_per_file_with(args, &options, RunHooks::default()).unwrap();
+        assert_eq!(per_file[0].1.scrolls, transmuted.scrolls);
+    }
 
-                    parser_state.area = Some(trimmed_slice.to_owned());
-                    parser_state.started_media_pos = None;
+    #[test]
+    fn test_pseudo_selector_colons() {
+        let spells = |css: &str| {
+            let transmuted =
+                transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+            (transmuted.scrolls[0].spells.clone(), transmuted.notices)
+        };
 
-                    let start_nested_pos = parser.position();
-                    parser
-                        .parse_nested_block(|input| {
-                            while input.next().is_ok() {}
-                            Ok::<(), cssparser::ParseError<'_, ()>>(())
-                        })
-                        .unwrap();
+        let (hover, notices) = spells(".a:hover { color: red }");
+        assert_eq!(hover, vec!["{:hover}color=red"]);
+        assert!(notices.is_empty());
 
-                    let mut state = ParserState {
-                        area: parser_state.area.clone(),
-                        ..Default::default()
-                    };
+        let (before, notices) = spells(".a::before { color: red }");
+        assert_eq!(before, vec!["{::before}color=red"]);
+        assert!(notices.is_empty());
 
-                    let res = process_css_into_raw_spells(
-                        parser.slice_from(start_nested_pos),
-                        &mut state,
-                    )?;
-                    merge_maps(&mut result, res);
-                    parser_state.area = None;
-                } else {
-                    let spell = Spell::new(&parser_state.current_class, &HashSet::new(), &None)?;
+        let (malformed, notices) = spells(".a:::x { color: red }");
+        assert_eq!(malformed, vec!["{::x}color=red"]);
+        assert_eq!(
+            notices,
+            vec!["Malformed pseudo selector ':::x' read as '::x'."]
+        );
 
-                    if spell.is_some() {
-                        println!(
-                            "This class is already Spell: {:#?}",
-                            &parser_state.current_class
-                        );
-                    } else {
-                        let focus_str = parser_state.focus.join("").trim().replace(" ", "_");
+        let (dangling, notices) = spells(".a: :hover { color: red }");
+        assert_eq!(dangling, vec!["{_:hover}color=red"]);
+        assert_eq!(notices.len(), 1);
+    }
 
-                        let mut base_raw_spell = if focus_str.is_empty() {
-                            String::new()
-                        } else {
-                            format!("{{{focus_str}}}")
-                        };
+    #[test]
+    fn test_merge_appends_scrolls() {
+        let transmute = |css| {
+            let transmuted =
+                transmute_content_to_struct(css, true, false, &ParseOptions::default()).unwrap();
+            let json = transmuted.to_json().unwrap();
+            Transmuted::deserialize_as(&json, OutputFormat::Json).unwrap()
+        };
 
-                        if let Some(a) = &parser_state.area {
-                            base_raw_spell = format!("{a}__{base_raw_spell}");
-                        }
+        let mut merged = transmute(".a { color: red } .b { margin: 0 }");
+        merged.merge(transmute(".a { padding: 0; color: red } .c { top: 0 }"));
 
-                        parser_state
-                            .raw_classes_spells_map
-                            .entry(parser_state.current_class.to_owned())
-                            .or_default()
-                            .push(base_raw_spell.clone());
-
-                        parser
-                            .parse_nested_block(|input| {
-                                let mut start_decl_pos: SourcePosition = input.position();
-                                let mut colon_pos: SourcePosition = input.position();
-
-                                while let Ok(inner_token) = input.next() {
-                                    match inner_token {
-                                        Token::Colon => {
-                                            colon_pos = input.position();
-                                        }
-                                        Token::Semicolon => {
-                                            let component = remove_last_char(
-                                                input.slice(start_decl_pos..colon_pos),
-                                            )
-                                            .trim();
-                                            let target =
-                                                remove_last_char(input.slice_from(colon_pos))
-                                                    .trim();
-
-                                            parser_state.component_and_component_target_map.insert(
-                                                format!(
-                                                    "{}={}",
-                                                    component.to_owned(),
-                                                    target.to_owned()
-                                                )
-                                                .replace(" ", "_"),
-                                            );
-
-                                            start_decl_pos = input.position();
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                                Ok::<(), cssparser::ParseError<'_, ()>>(())
-                            })
-                            .unwrap();
+        assert_eq!(
+            sorted_scrolls(&merged),
+            vec![
+                (
+                    "a".to_string(),
+                    vec!["color=red".to_string(), "padding=0".to_string()]
+                ),
+                ("b".to_string(), vec!["margin=0".to_string()]),
+                ("c".to_string(), vec!["top=0".to_string()]),
+            ]
+        );
+        assert_eq!(
+            merged.scrolls[0].oneliner.as_deref(),
+            Some("color=red padding=0")
+        );
 
-                        merge_maps(&mut result, generate_spells_map(parser_state));
-                    }
+        let outdated = r#"{ "version": 1, "generator": "x", "scrolls": [] }"#;
+        assert!(matches!(
+            Transmuted::deserialize_as(outdated, OutputFormat::Json),
+            Err(GrimoireCssError::InvalidInput(msg)) if msg.contains("schema version 1")
+        ));
+    }
 
-                    parser_state.raw_classes_spells_map.clear();
-                    parser_state.current_class.clear();
-                    parser_state.component_and_component_target_map.clear();
-                    parser_state.effects.clear();
-                    parser_state.focus.clear();
-                    parser_state.class_started = false;
-                    parser_state.focus_delim.clear();
-                }
-            }
-            Token::Function(t) => {
-                if parser_state.effect_started {
-                    if parser_state.colons.len() > 2 {
-                        parser_state.colons = vec![":".to_string(), ":".to_string()]
-                    }
+    #[test]
+    fn test_conflict_policies() {
+        let css = ".btn.primary { color: red } .btn\\.primary { margin: 0 }";
+        let transmute = |on_conflict| {
+            let options = ParseOptions {
+                on_conflict,
+                ..Default::default()
+            };
+            transmute_content_to_struct(css, false, false, &options)
+        };
 
-                    let fn_name = t.to_string();
+        let merged = transmute(ConflictPolicy::Merge).unwrap();
+        assert_eq!(
+            sorted_scrolls(&merged),
+            vec![(
+                "btn.primary".to_string(),
+                vec!["color=red".to_string(), "margin=0".to_string()]
+            )]
+        );
+        assert!(merged.warnings.is_empty());
 
-                    let start_pos = parser.position();
+        let warned = transmute(ConflictPolicy::Warn).unwrap();
+        assert_eq!(sorted_scrolls(&warned), sorted_scrolls(&merged));
+        assert_eq!(warned.warnings.len(), 1);
+        assert_eq!(
+            warned.warnings[0].selector.as_deref(),
+            Some(".btn.primary, .btn\\.primary")
+        );
 
-                    parser
-                        .parse_nested_block(|input| {
-                            while input.next().is_ok() {}
-                            Ok::<(), cssparser::ParseError<'_, ()>>(())
-                        })
-                        .unwrap();
+        assert!(matches!(
+            transmute(ConflictPolicy::Error),
+            Err(GrimoireCssError::InvalidInput(msg)) if msg.contains("'btn.primary'")
+        ));
 
-                    let slice = parser.slice_from(start_pos);
+        let renamed = transmute(ConflictPolicy::Rename).unwrap();
+        assert_eq!(
+            sorted_scrolls(&renamed),
+            vec![
+                ("btn.primary".to_string(), vec!["color=red".to_string()]),
+                ("btn.primary-2".to_string(), vec!["margin=0".to_string()]),
+            ]
+        );
+        assert!(renamed.warnings[0].message.contains("'btn.primary-2'"));
 
-                    parser_state.focus.push(format!(
-                        "{}{}({}",
-                        parser_state.colons.join(""),
-                        &fn_name,
-                        slice
-                    ));
-                    parser_state.effects.push(fn_name);
-                    parser_state.effect_started = false;
-                    parser_state.colons.clear();
-                }
-            }
-            _ => {}
-        }
+        // The same selector repeated across rules is no conflict
+        let options = ParseOptions {
+            on_conflict: ConflictPolicy::Error,
+            ..Default::default()
+        };
+        let repeated = ".a { color: red } .a:hover { color: blue } .a { margin: 0 }";
+        assert!(transmute_content_to_struct(repeated, false, false, &options).is_ok());
     }
 
-    Ok(result)
-}
+    #[test]
+    fn test_empty_rules_make_no_scroll() {
+        let transmuted = transmute_content_to_struct(
+            ".a {} .b { color: red } .c { }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
 
-/// Run the transmutation process on multiple CSS files.
-/// This is the main entry point for the paths mode.
-pub fn run_transmutation(
-    args: Vec<String>,
-    include_oneliner: bool,
-) -> Result<(Duration, String), GrimoireCssError> {
-    // Get current directory
-    let cwd: PathBuf = std::env::current_dir().map_err(GrimoireCssError::Io)?;
+        assert_eq!(
+            sorted_scrolls(&transmuted),
+            vec![("b".to_string(), vec!["color=red".to_string()])]
+        );
+        assert_eq!(transmuted.diagnostics.get("empty rule"), Some(&2));
+        assert_eq!(
+            transmuted.notices[0],
+            "Dropped '.a', its block has no declarations to transmute."
+        );
 
-    // Validate input
-    if args.is_empty() {
-        return Err(GrimoireCssError::InvalidInput(
-            "No CSS file patterns provided.".into(),
-        ));
+        assert!(
+            transmute_content_to_struct(".a {}", false, false, &ParseOptions::default()).is_err()
+        );
     }
 
-    // Expand file paths based on glob patterns
-    let expanded_paths = expand_file_paths(&cwd, &args)?;
-    if expanded_paths.is_empty() {
-        return Err(GrimoireCssError::InvalidPath(
-            "No files found matching the provided patterns.".into(),
-        ));
+    #[test]
+    fn test_media_query_list_areas() {
+        let css = "@media screen, print { .a, .b { color: red } } \
+                   @media (min-width: 600px), (max-width: 200px) { .c { margin: 0 } }";
+        let transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+
+        assert_eq!(
+            sorted_scrolls(&transmuted),
+            vec![
+                (
+                    "a".to_string(),
+                    vec![
+                        "print__color=red".to_string(),
+                        "screen__color=red".to_string()
+                    ]
+                ),
+                (
+                    "b".to_string(),
+                    vec![
+                        "print__color=red".to_string(),
+                        "screen__color=red".to_string()
+                    ]
+                ),
+                (
+                    "c".to_string(),
+                    vec![
+                        "(max-width:_200px)__margin=0".to_string(),
+                        "(min-width:_600px)__margin=0".to_string()
+                    ]
+                ),
+            ]
+        );
     }
 
-    let start_time = Instant::now();
+    #[test]
+    fn test_non_ascii_content() {
+        let css = ".café:hover { content: \"café\"; font-family: \"Ünïcode\" }\n\
+                   @media (min-width: 600px) { .日本 { margin: 0 } }\n\
+                   .a\\:é { top: 0 } .b { content: '→' }";
+        let transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
 
-    let mut parser_state = ParserState::default();
+        assert_eq!(
+            sorted_scrolls(&transmuted),
+            vec![
+                ("a:é".to_string(), vec!["top=0".to_string()]),
+                ("b".to_string(), vec!["content=\"→\"".to_string()]),
+                (
+                    "café".to_string(),
+                    vec![
+                        "{:hover}content=\"café\"".to_string(),
+                        "{:hover}font-family=\"Ünïcode\"".to_string()
+                    ]
+                ),
+                (
+                    "日本".to_string(),
+                    vec!["(min-width:_600px)__margin=0".to_string()]
+                ),
+            ]
+        );
+        assert!(transmuted.diagnostics.is_empty());
 
-    // Read and process CSS files
-    let all_css_string = read_and_clean_files(&expanded_paths)?;
-    let processed_css = process_css_into_raw_spells(&all_css_string, &mut parser_state)?;
+        let span = &transmuted.source_map["café"]["{:hover}content=\"café\""];
+        assert_eq!(&css[span.start..span.end], "content: \"café\"");
+    }
 
-    if processed_css.is_empty() {
-        return Err(GrimoireCssError::InvalidInput(
-            "There is nothing to transmute.".into(),
-        ));
+    #[test]
+    fn test_toml_output_parses_back() {
+        let transmuted = transmute_content_to_struct(
+            ".a { color: red } .b:hover { margin: 0 } @media print { .a { display: none } }",
+            true,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let format: OutputFormat = "toml".parse().unwrap();
+        let output = transmuted.serialize_as(format, false).unwrap();
+        assert!(output.contains("[[scrolls]]"));
+
+        let value: toml::Value = toml::from_str(&output).unwrap();
+        assert_eq!(
+            value["version"].as_integer(),
+            Some(i64::from(SCHEMA_VERSION))
+        );
+        let scrolls = value["scrolls"].as_array().unwrap();
+        assert_eq!(scrolls.len(), 2);
+        assert_eq!(scrolls[0]["name"].as_str(), Some("a"));
+        assert_eq!(
+            scrolls[0]["spells"].as_array().unwrap().len(),
+            transmuted.scrolls[0].spells.len()
+        );
+
+        let parsed = Transmuted::deserialize_as(&output, OutputFormat::Toml).unwrap();
+        assert_eq!(sorted_scrolls(&parsed), sorted_scrolls(&transmuted));
     }
 
-    // Build the transmuted output structure
-    let mut transmuted = Transmuted {
-        scrolls: Vec::with_capacity(processed_css.len()),
-    };
+    #[test]
+    fn test_continue_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("valid.css"), ".a { color: red }").unwrap();
+        fs::write(dir.path().join("bad.css"), ".b { color: red;").unwrap();
+        let run = |options: &ParseOptions| transmute_dir(dir.path(), options);
 
-    for (name, spells) in processed_css {
-        if !name.is_empty() {
-            // Convert HashSet to Vec to preserve JSON ordering
-            let spells_vec: Vec<String> = spells.into_iter().collect();
+        assert!(run(&ParseOptions::default()).is_err());
 
-            let oneliner = if include_oneliner {
-                Some(spells_vec.join(" "))
-            } else {
-                None
-            };
+        let options = ParseOptions {
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let transmuted = run(&options).unwrap();
+        let names: Vec<_> = transmuted.scrolls.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["a"]);
+        assert_eq!(transmuted.failed_files.len(), 1);
+        assert!(transmuted.failed_files[0].0.ends_with("bad.css"));
+        assert!(transmuted.to_json().unwrap().contains("failed_files"));
 
-            transmuted.scrolls.push(TransmutedClass {
-                name,
-                spells: spells_vec,
-                oneliner,
-            });
+        // With no file left, the run fails naming each one and why
+        fs::write(dir.path().join("valid.css"), ".a { color: red").unwrap();
+        match run(&options) {
+            Err(GrimoireCssError::InvalidInput(msg)) => {
+                assert!(msg.starts_with("All 2 input files failed"), "{msg}");
+                assert!(msg.contains("bad.css': "), "{msg}");
+                assert!(msg.contains("valid.css': "), "{msg}");
+            }
+            other => panic!("expected the failed files, got {other:?}"),
         }
     }
 
-    let duration = start_time.elapsed();
+    #[test]
+    fn test_important_variants() {
+        for css_input in [
+            ".a { color: red !important; }",
+            ".a { color: red ! important ; }",
+            ".a { color: red !IMPORTANT; }",
+            ".a { color: red!Important\n}",
+            ".a { color: red !\timportant\n; }",
+        ] {
+            assert_eq!(
+                spells_of(css_input, "a"),
+                vec!["color=red!"],
+                "{css_input:?}"
+            );
+        }
 
-    let json_data = to_string_pretty(&transmuted).map_err(GrimoireCssError::Serde)?;
+        // Every spelling outranks a later plain declaration
+        assert_eq!(
+            spells_of(".a { margin: 0 auto ! IMPORTANT; margin: 0; }", "a"),
+            vec!["margin=0_auto!"]
+        );
+    }
 
-    Ok((duration, json_data))
-}
+    #[test]
+    fn test_limit() {
+        let css_input = ".a { color: red } .b { color: red } .c { color: red } .d { color: red }";
+        let options = ParseOptions {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let transmuted = transmute_content_to_struct_with(
+            css_input,
+            &TransmuteOptions::new().parse(options.clone()),
+        )
+        .unwrap();
+        let names: Vec<_> = transmuted.scrolls.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
 
-/// Transmutes CSS content to Grimoire CSS format.
-/// This is the main entry point for the content mode.
-pub fn transmute_from_content(
-    css_content: &str,
-    include_oneliner: bool,
-) -> Result<(f64, String), GrimoireCssError> {
-    let start_time = Instant::now();
+        // The count carries into at-rule blocks and across files
+        let css_input = "@media print { .a { color: red } .b { color: red } } .c { color: red }";
+        let transmuted = transmute_content_to_struct_with(
+            css_input,
+            &TransmuteOptions::new().parse(options.clone()),
+        )
+        .unwrap();
+        assert_eq!(transmuted.scrolls.len(), 2);
 
-    let mut parser_state = ParserState::default();
+        let files = [
+            ("1.css", ".a { color: red }"),
+            ("2.css", ".b { color: red } .c { color: red }"),
+        ];
+        let transmuted = transmute_files(&files, &options).unwrap();
+        let names: Vec<_> = transmuted.scrolls.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+    }
 
-    let processed_css = process_css_into_raw_spells(css_content, &mut parser_state)?;
+    #[test]
+    fn test_property_histogram() {
+        let css_input = ".a { color: red; margin: 0 } \
+                         .b, .c { color: blue } \
+                         @media print { .a { color: black; display: none } } \
+                         .d { --x: 1 }";
+        let transmuted =
+            transmute_content_to_struct(css_input, false, false, &ParseOptions::default()).unwrap();
 
-    if processed_css.is_empty() {
-        return Err(GrimoireCssError::InvalidInput(
-            "There is nothing to transmute.".into(),
-        ));
+        let histogram: Vec<_> = transmuted
+            .property_histogram
+            .iter()
+            .map(|(property, count)| (property.as_str(), *count))
+            .collect();
+        assert_eq!(histogram, [("color", 3), ("display", 1), ("margin", 1)]);
     }
 
-    let mut transmuted = Transmuted {
-        scrolls: Vec::with_capacity(processed_css.len()),
-    };
+    #[test]
+    fn test_nth_child_argument_in_focus() {
+        assert_eq!(
+            spells_of(".list li:nth-child(2n+1) { color: red }", "list"),
+            ["{_li:nth-child(2n+1)}color=red"]
+        );
+        assert_eq!(
+            spells_of(".a:NTH-CHILD(2N + 1) { color: red }", "a"),
+            ["{:NTH-CHILD(2N+1)}color=red"]
+        );
+    }
 
-    for (name, spells) in processed_css {
-        if !name.is_empty() {
-            // Convert HashSet to Vec to preserve JSON ordering
-            let spells_vec: Vec<String> = spells.into_iter().collect();
+    #[test]
+    fn test_media_feature_order_gives_one_area() {
+        let areas: Vec<_> = [
+            "@media screen and (min-width: 600px) { .a { color: red } }",
+            "@media (min-width: 600px) and screen { .a { color: red } }",
+            "@media SCREEN AND (Min-Width:600px) { .a { color: red } }",
+        ]
+        .into_iter()
+        .map(|css_input| spells_of(css_input, "a"))
+        .collect();
 
-            let oneliner = if include_oneliner {
-                Some(spells_vec.join(" "))
-            } else {
-                None
-            };
+        assert_eq!(areas[0], ["screen_and_(min-width:_600px)__color=red"]);
+        assert_eq!(areas[1], areas[0]);
+        assert_eq!(areas[2], areas[0]);
 
-            transmuted.scrolls.push(TransmutedClass {
-                name,
-                spells: spells_vec,
-                oneliner,
-            });
-        }
+        // Different media types stay apart
+        assert_eq!(
+            spells_of(
+                "@media print { .a { color: red } } @media screen { .a { color: red } }",
+                "a"
+            ),
+            ["print__color=red", "screen__color=red"]
+        );
     }
 
-    let duration = start_time.elapsed().as_secs_f64();
+    #[test]
+    fn test_pretty_spells() {
+        let css = ".card { color: red; gap: 4px; font-size: 14px; display: flex; margin: 0; }";
+        let options = ParseOptions {
+            pretty_spells: true,
+            ..Default::default()
+        };
+        let mut transmuted = transmute_content_to_struct(css, true, false, &options).unwrap();
+        assert_eq!(
+            transmuted.scrolls[0].oneliner.as_deref(),
+            Some("display=flex gap=4px | margin=0 | font-size=14px | color=red")
+        );
 
-    let json_data = to_string_pretty(&transmuted).map_err(GrimoireCssError::Serde)?;
+        // Removing every spell of a group drops its separator too
+        transmuted.scrolls[0]
+            .spells
+            .retain(|spell| spell != "margin=0");
+        transmuted.scrolls[0].refresh_oneliner();
+        assert_eq!(
+            transmuted.scrolls[0].oneliner.as_deref(),
+            Some("display=flex gap=4px | font-size=14px | color=red")
+        );
 
-    Ok((duration, json_data))
-}
+        // Appending regroups the oneliner rather than adding an ungrouped tail
+        let newer = ".card { padding: 0; background: blue; }";
+        transmuted.merge(transmute_content_to_struct(newer, true, false, &options).unwrap());
+        assert_eq!(
+            transmuted.scrolls[0].oneliner.as_deref(),
+            Some("display=flex gap=4px | padding=0 | font-size=14px | background=blue color=red")
+        );
+    }
 
-/// Expands glob patterns into a list of file paths.
-fn expand_file_paths(cwd: &Path, patterns: &[String]) -> Result<Vec<PathBuf>, GrimoireCssError> {
-    let mut paths = Vec::with_capacity(patterns.len() * 4);
+    #[test]
+    fn test_media_nested_in_rule() {
+        assert_eq!(
+            spells_of(
+                ".a { color: red; @media (min-width:600px) { color: blue; } }",
+                "a"
+            ),
+            ["(min-width:_600px)__color=blue", "color=red"]
+        );
 
-    for pattern in patterns {
-        let absolute_pattern = if Path::new(pattern).is_absolute() {
-            pattern.to_string()
-        } else {
-            cwd.join(pattern).to_string_lossy().into_owned()
+        // Within the rule's own area and focus, and nested once more
+        assert_eq!(
+            spells_of(
+                "@media screen { .a:hover { color: red; @media (min-width: 600px) { \
+                 color: blue; @media (hover) { color: green } } } }",
+                "a"
+            ),
+            [
+                "screen__{:hover}color=red",
+                "screen_and_(hover)_and_(min-width:_600px)__{:hover}color=green",
+                "screen_and_(min-width:_600px)__{:hover}color=blue",
+            ]
+        );
+
+        // A rule with only nested declarations still is a scroll
+        assert_eq!(
+            spells_of(".b { @media print { display: none } }", "b"),
+            ["print__display=none"]
+        );
+
+        // Combined with the enclosing condition into one canonical area
+        assert_eq!(
+            spells_of(
+                "@media (min-width:600px) { .a { @media screen { color: blue } } }",
+                "a"
+            ),
+            ["screen_and_(min-width:_600px)__color=blue"]
+        );
+
+        // Query by query, for lists at either level
+        assert_eq!(
+            spells_of(
+                ".a { @media (min-width: 600px), print { @media screen, (hover) { color: blue } } }",
+                "a"
+            ),
+            [
+                "(hover)_and_(min-width:_600px)__color=blue",
+                "print_and_(hover)__color=blue",
+                "print_and_screen__color=blue",
+                "screen_and_(min-width:_600px)__color=blue",
+            ]
+        );
+
+        // A container area cannot take a media condition
+        let transmuted = transmute_content_to_struct(
+            "@container (min-width: 400px) { .a { color: red; @media print { color: blue } } }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            transmuted.scrolls[0].spells,
+            ["container_(min-width:_400px)__color=red"]
+        );
+        assert_eq!(transmuted.diagnostics.get("@media in @container"), Some(&1));
+
+        // Other at-rules nested in a rule are left out, not read as declarations
+        let transmuted = transmute_content_to_struct(
+            ".a { @container (min-width: 400px) { color: blue } margin: 0; }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(transmuted.scrolls[0].spells, ["margin=0"]);
+        assert_eq!(transmuted.diagnostics.get("@container"), Some(&1));
+    }
+
+    #[test]
+    fn test_non_css_files() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.css"), ".a { color: red }").unwrap();
+        fs::write(dir.path().join("logo"), b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+        let pattern = dir.path().join("*").to_string_lossy().into_owned();
+        let run = |limits: RunLimits| {
+            run_transmutation_to_struct_with(
+                vec![pattern.clone()],
+                &TransmuteOptions::new().limits(limits),
+                RunHooks::default(),
+            )
         };
 
-        for entry_result in glob(&absolute_pattern)
-            .map_err(|e| GrimoireCssError::GlobPatternError(e.msg.to_string()))?
-        {
-            match entry_result {
-                Ok(path) if path.is_file() => paths.push(path),
-                Ok(_) => {} // Skip directories
-                Err(e) => return Err(GrimoireCssError::InvalidPath(e.to_string())),
+        match run(RunLimits::default()) {
+            Err(GrimoireCssError::InvalidInput(msg)) => {
+                assert!(msg.contains("logo"), "{msg}");
+                assert!(msg.contains("not a stylesheet"), "{msg}");
             }
+            other => panic!("expected an error for the binary file, got {other:?}"),
         }
-    }
 
-    // If no memory waste, return as is; otherwise, shrink to fit
-    if paths.len() < paths.capacity() / 2 {
-        paths.shrink_to_fit();
-    }
+        let transmuted = run(RunLimits {
+            non_css: NonCssPolicy::Skip,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(transmuted.scrolls.len(), 1);
+        assert_eq!(transmuted.failed_files.len(), 1);
+        assert!(transmuted.failed_files[0].0.ends_with("logo"));
 
-    Ok(paths)
-}
+        let per_file = run_transmutation_per_file_with(
+            vec![pattern.clone()],
+            &TransmuteOptions::new().limits(RunLimits {
+                non_css: NonCssPolicy::Skip,
+                ..Default::default()
+            }),
+            RunHooks::default(),
+        )
+        .unwrap();
+        assert_eq!(per_file.len(), 2);
+        assert_eq!(per_file[0].1.scrolls.len(), 1);
+        assert!(per_file[1].0.ends_with("logo"));
+        assert!(per_file[1].1.scrolls.is_empty());
+        assert!(per_file[1].1.failed_files[0]
+            .1
+            .starts_with("Not a stylesheet"));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // A misnamed asset is left out without being read: this one would
+        // decompress to over the size limit
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[b'a'; 4096]).unwrap();
+        fs::write(dir.path().join("app.js.gz"), encoder.finish().unwrap()).unwrap();
+        let transmuted = run(RunLimits {
+            non_css: NonCssPolicy::Skip,
+            max_file_size: 1024,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(transmuted.failed_files.len(), 2);
+        assert!(transmuted.failed_files[0].1.contains(".js extension"));
+
+        assert!(InputKind::Css
+            .extension_mismatch(Path::new("app.min.js"))
+            .is_some());
+        assert!(InputKind::Css
+            .extension_mismatch(Path::new("site.css.gz"))
+            .is_none());
+        assert!(InputKind::Html
+            .extension_mismatch(Path::new("index.html"))
+            .is_none());
+        assert!(InputKind::content_mismatch("a\0b").is_some());
+    }
 
     #[test]
-    fn test_remove_last_char() {
-        assert_eq!(remove_last_char("hello"), "hell");
-        assert_eq!(remove_last_char("a"), "");
-        assert_eq!(remove_last_char(""), "");
+    fn test_duplicate_count() {
+        let files = [
+            ("a.css", ".btn { color: red }"),
+            ("b.css", ".btn { color: red } .card { margin: 0 }"),
+        ];
+        let transmuted = transmute_files(&files, &ParseOptions::default()).unwrap();
+        let counts: Vec<_> = transmuted
+            .scrolls
+            .iter()
+            .map(|scroll| (scroll.name.as_str(), scroll.duplicate_count))
+            .collect();
+        assert_eq!(counts, [("btn", 2), ("card", 1)]);
+
+        let json = transmuted.to_json().unwrap();
+        assert_eq!(json.matches("duplicate_count").count(), 1);
+        assert_eq!(
+            Transmuted::deserialize_as(&json, OutputFormat::Json)
+                .unwrap()
+                .scrolls,
+            transmuted.scrolls
+        );
+
+        // Another set of spells for the same scroll is no duplicate
+        let css = ".a { color: red } .a { color: blue } .a { color: red }";
+        let transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
+        assert_eq!(transmuted.scrolls[0].duplicate_count, 2);
     }
 
     #[test]
-    fn test_read_and_clean_files() {
-        let temp_dir = tempfile::tempdir().unwrap();
-        let file_path = temp_dir.path().join("test.css");
-        let content = r#"
-            /* Comment */
-            .test {
-                color: "red";
-            }"#;
+    fn test_strict() {
+        let css = "@supports (display: grid) { .a { display: grid } } .b { color: red }";
+        let options = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
 
-        fs::write(&file_path, content).unwrap();
-        let result = read_and_clean_files(&[file_path]).unwrap();
-        let expected = ".test { color: 'red'; }";
+        assert!(transmute_content_to_struct(css, false, false, &ParseOptions::default()).is_ok());
+        let err = transmute_content_to_struct(css, false, false, &options).unwrap_err();
+        assert!(
+            err.to_string().contains("dropped @supports at line 1"),
+            "{err}"
+        );
 
-        let actual = result.replace("\n", "").replace(" ", "");
-        let expected_normalized = expected.replace("\n", "").replace(" ", "");
+        let transmuted =
+            transmute_content_to_struct(".b { color: red }", false, false, &options).unwrap();
+        assert_eq!(transmuted.scrolls[0].spells, ["color=red"]);
+        assert!(
+            transmute_content_to_struct(".a { color: red } .b {}", false, false, &options).is_err()
+        );
 
-        assert_eq!(actual, expected_normalized);
+        let css = ".a; .b { color: red }";
+        assert!(transmute_content_to_struct(css, false, false, &ParseOptions::default()).is_ok());
+        let err = transmute_content_to_struct(css, false, false, &options).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("dropped selector without a block at line 1"),
+            "{err}"
+        );
     }
 
     #[test]
-    fn test_generate_spells_map() {
-        let mut state = ParserState::default();
-        state
-            .raw_classes_spells_map
-            .insert("class1".to_string(), vec!["prefix".to_string()]);
-        state
-            .component_and_component_target_map
-            .insert("color=red".to_string());
+    fn test_pseudo_class_order() {
+        let transmuted = |css: &str| {
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap()
+        };
 
-        let result: HashMap<String, HashSet<String>> = generate_spells_map(&state);
-        let left_spells = result.get("class1").unwrap();
-        let left_spells_vec: Vec<String> = left_spells.iter().map(String::from).collect();
+        let scrolls =
+            transmuted(".a:hover:focus { color: red } .a:focus:hover { color: red }").scrolls;
+        assert_eq!(scrolls.len(), 1);
+        assert_eq!(scrolls[0].spells, ["{:focus:hover}color=red"]);
+        assert_eq!(scrolls[0].duplicate_count, 2);
 
-        assert_eq!(left_spells_vec, vec!["prefixcolor=red".to_string()]);
+        let scrolls = transmuted(":hover:focus { color: red } :focus:hover { color: red }").scrolls;
+        assert_eq!(scrolls.len(), 1);
+        assert_eq!(scrolls[0].name, ":focus");
+
+        // Pseudo-elements keep their place, the chains around them are sorted
+        let spells = &transmuted(".a:hover:focus::before { color: red }").scrolls[0].spells;
+        assert_eq!(spells, &["{:focus:hover::before}color=red"]);
+        let spells = &transmuted(".a::before:hover { color: red }").scrolls[0].spells;
+        assert_eq!(spells, &["{::before:hover}color=red"]);
     }
 
     #[test]
-    fn test_merge_maps() {
-        let mut map1: HashMap<String, HashSet<String>> = HashMap::new();
-        map1.insert("class1".to_string(), HashSet::from(["spell1".to_string()]));
+    fn test_base_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("styles")).unwrap();
+        fs::write(dir.path().join("styles/a.css"), ".a { color: red }").unwrap();
+        let run = |base_dir: Option<PathBuf>| {
+            let limits = RunLimits {
+                base_dir,
+                ..RunLimits::default()
+            };
+            run_transmutation_to_struct_with(
+                vec!["styles/*.css".to_string()],
+                &TransmuteOptions::new().limits(limits),
+                RunHooks::default(),
+            )
+        };
 
-        let mut map2: HashMap<String, HashSet<String>> = HashMap::new();
-        map2.insert("class1".to_string(), HashSet::from(["spell2".to_string()]));
-        map2.insert("class2".to_string(), HashSet::from(["spell3".to_string()]));
+        let transmuted = run(Some(dir.path().to_path_buf())).unwrap();
+        assert_eq!(transmuted.scrolls[0].name, "a");
+        assert!(run(None).is_err());
+    }
 
-        merge_maps(&mut map1, map2);
+    #[test]
+    fn test_notices_are_logged() {
+        use std::cell::RefCell;
 
-        let left_spells = map1.get("class2").unwrap();
-        let left_spells_vec: Vec<String> = left_spells.iter().map(String::from).collect();
+        thread_local! {
+            static RECORDS: RefCell<Vec<(log::Level, String)>> = const { RefCell::new(Vec::new()) };
+        }
 
-        assert_eq!(left_spells_vec, vec!["spell3".to_string()]);
+        struct CapturingLogger;
+
+        impl log::Log for CapturingLogger {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                let entry = (record.level(), record.args().to_string());
+                RECORDS.with(|records| records.borrow_mut().push(entry));
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CapturingLogger = CapturingLogger;
+        // Fails if another test installed it first, which is just as good
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        transmute_content_to_struct(
+            ".color\\=red { color: red } .a { color: red }",
+            false,
+            false,
+            &ParseOptions::default(),
+        )
+        .unwrap();
+
+        let records = RECORDS.with(|records| records.take());
+        assert!(records.contains(&(
+            log::Level::Warn,
+            "Class 'color=red' is already a spell, skipped.".to_string()
+        )));
     }
 
     #[test]
-    fn test_process_css_into_raw_spells() {
-        let css_input = ".button { color: red; }";
-        let mut parser_state = ParserState::default();
+    fn test_dangling_combinator() {
+        let css = ".a > { color: red } .b { color: blue }";
+        let transmuted =
+            transmute_content_to_struct(css, false, false, &ParseOptions::default()).unwrap();
 
-        let result = process_css_into_raw_spells(css_input, &mut parser_state);
-        assert!(result.is_ok());
-        let spells_map = result.unwrap();
-        let left_spells = spells_map.get("button").unwrap();
-        let left_spells_vec: Vec<String> = left_spells.iter().map(String::from).collect();
+        let names: Vec<_> = transmuted.scrolls.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["b"]);
+        assert_eq!(transmuted.diagnostics.get("dangling combinator"), Some(&1));
+        assert!(transmuted.notices[0].contains("'.a >'"));
 
-        assert_eq!(left_spells_vec, vec!["color=red".to_string()]);
+        let options = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(transmute_content_to_struct(css, false, false, &options).is_err());
     }
 
     #[test]
-    fn test_expand_file_paths() {
+    fn test_analyze_content() {
+        let css = "@import \"x.css\"; .a { color: red } .color\\=red { color: red } \
+                   .c {} .d > { color: red }";
+        let report = analyze_content(css, &ParseOptions::default()).unwrap();
+
+        assert!(!report.is_clean());
+        assert_eq!(report.rules, 4);
+        assert_eq!(report.selectors, 1);
+        assert_eq!(report.skipped_existing_spells, ["color=red"]);
+        assert_eq!(
+            report.diagnostics,
+            BTreeMap::from([
+                ("@import".to_string(), 1),
+                ("dangling combinator".to_string(), 1),
+                ("empty rule".to_string(), 1),
+            ])
+        );
+        assert_eq!(report.notices.len(), 3);
+
+        let options = ParseOptions::default();
+        assert!(analyze_content(".a { color: red }", &options)
+            .unwrap()
+            .is_clean());
+        assert!(analyze_content(".a { color: red", &options).is_err());
+
+        // The parse options apply, e.g. strict mode fails on the first issue
+        let strict = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(analyze_content(css, &strict).is_err());
+
         let temp_dir = tempfile::tempdir().unwrap();
-        let file_path = temp_dir.path().join("test.css");
-        fs::write(&file_path, ".test { color: red; }").unwrap();
+        fs::write(temp_dir.path().join("a.css"), ".a { color: red").unwrap();
+        fs::write(temp_dir.path().join("b.css"), ".b { color: red }").unwrap();
+        let pattern = temp_dir.path().join("*.css").to_string_lossy().into_owned();
+        let reports = run_analysis(vec![pattern], &[], RunLimits::default(), &options).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!(reports[0].1.is_err());
+        assert!(reports[1].1.as_ref().unwrap().is_clean());
+    }
 
-        let cwd = temp_dir.path().to_path_buf();
-        let result = expand_file_paths(&cwd, &["test.css".to_string()]);
+    #[test]
+    fn test_custom_focus_encoder() {
+        let css = ".a > .title:hover::before { color: red } .b[type=button] { color: red }";
+        let options = ParseOptions {
+            focus_encoder: FocusEncoder {
+                open: "<".to_string(),
+                close: ">".to_string(),
+                pseudo_class: "%".to_string(),
+                pseudo_element: "%%".to_string(),
+                attribute_open: "(".to_string(),
+                attribute_close: ")".to_string(),
+                space: "~".to_string(),
+            },
+            ..Default::default()
+        };
+        let transmuted = transmute_content_to_struct(css, false, false, &options).unwrap();
+        let spells: Vec<_> = transmuted
+            .scrolls
+            .iter()
+            .map(|scroll| scroll.spells[0].as_str())
+            .collect();
 
-        assert!(result.is_ok());
-        let paths = result.unwrap();
-        assert_eq!(paths.len(), 1);
-        assert_eq!(paths[0], file_path);
+        assert_eq!(
+            spells,
+            [
+                "<~>~.title%hover%%before>color=red",
+                "<(type=\"button\")>color=red"
+            ]
+        );
     }
 
     #[test]
-    fn test_transmute_from_content() {
-        let css_input = ".button { color: red; }";
-        let result = transmute_from_content(css_input, false);
-        assert!(result.is_ok());
-        let (_duration, json_output) = result.unwrap();
-        assert!(json_output.contains("\"name\": \"button\""));
-        assert!(json_output.contains("\"color=red\""));
+    fn test_custom_focus_encoder_end_to_end() {
+        let css = r#"
+            .card:hover { color: red; display: flex; }
+            .card[data-x="a=b"] { margin: 0; }
+            @media print { .card:hover { color: black; } }
+            @media (min-width: 600px) { .card { margin: 4px; } }
+            :hover:focus { outline: none; }
+        "#;
+        let options = ParseOptions {
+            focus_encoder: FocusEncoder {
+                open: "<".to_string(),
+                close: ">".to_string(),
+                pseudo_class: "%".to_string(),
+                pseudo_element: "%%".to_string(),
+                attribute_open: "(".to_string(),
+                attribute_close: ")".to_string(),
+                space: "~".to_string(),
+            },
+            oneliner_order: OnelinerOrder::Category,
+            ..Default::default()
+        };
+
+        let mut transmuted = transmute_content_to_struct(css, true, false, &options).unwrap();
+        assert_eq!(transmuted.scrolls[1].name, ":focus");
+        assert_eq!(
+            transmuted.scrolls[0].oneliner.as_deref(),
+            Some(
+                "<%hover>display=flex (min-width:_600px)__margin=4px <(data-x=\"a=b\")>margin=0 \
+                 <%hover>color=red print__<%hover>color=black"
+            )
+        );
+
+        let pretty = ParseOptions {
+            pretty_spells: true,
+            ..options.clone()
+        };
+        let pretty = transmute_content_to_struct(css, true, false, &pretty).unwrap();
+        assert_eq!(
+            pretty.scrolls[0].oneliner.as_deref(),
+            Some(
+                "<%hover>display=flex | (min-width:_600px)__margin=4px <(data-x=\"a=b\")>margin=0 \
+                 | <%hover>color=red print__<%hover>color=black"
+            )
+        );
+
+        transmuted.split_media(&["print".to_string()]);
+        assert_eq!(
+            transmuted.split_media["print"][0].spells,
+            ["print__<%hover>color=black"]
+        );
+
+        transmuted.group_by_area();
+        assert_eq!(
+            transmuted.areas["(min-width:_600px)"][0].spells,
+            ["margin=4px"]
+        );
+        assert_eq!(
+            transmuted.areas["base"][0].oneliner.as_deref(),
+            Some("<%hover>display=flex <(data-x=\"a=b\")>margin=0 <%hover>color=red")
+        );
     }
 }
@@ -1,10 +1,22 @@
 use grimoire_css_lib::GrimoireCssError;
-use grimoire_css_transmutator_lib::{run_transmutation, transmute_from_content};
+use grimoire_css_transmutator_lib::{
+    analyze_content, extract_from_html, run_analysis, run_html_extraction,
+    run_transmutation_per_file_with, run_transmutation_to_struct_with, transmute_content_to_struct,
+    write_atomically, GrimoireConfig, OutputFormat, ParseCache, ParseOptions, RunHooks, RunLimits,
+    Timings, TransmuteOptions, Transmuted, DEFAULT_CACHE_PATH, NO_FILES_MATCHED,
+};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::HashSet;
 use std::env;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::{Component, Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const HELP_MESSAGE: &str = "
 Grimoire CSS Transmutator - Convert CSS to Grimoire CSS format
@@ -13,25 +25,193 @@ USAGE:
     grimoire_css_transmutator [OPTIONS] [INPUT]
 
 OPTIONS:
-    -p, --paths           Process comma-separated list of CSS file paths or patterns
+    -p, --paths           Process comma-separated list of CSS file paths, directories or patterns
+                          (@file reads one pattern per line from that file)
+    -e, --exclude         Skip files matching any of the comma-separated patterns
+        --follow-imports  Also transmute stylesheets pulled in through @import (paths mode)
+        --html            Read HTML instead of CSS: transmute each element's style attribute
+                          and list the classes in use (written to stdout unless -o is given)
     -c, --content         Process CSS content provided as string
     -o, --output          Specify output file (default: ./grimoire/transmuted.json)
+        --output-dir      Directory of the default output file, or of the per-file output
+                          tree (default: ./grimoire); -o takes precedence (paths mode)
+        --per-file        Write one output file per input file, mirroring the input
+                          tree under the output directory (default: ./grimoire)
+        --output-template Per-file output path built from each input path with {dir},
+                          {name} and {ext}, e.g. 'grimoire/{dir}/{name}.scroll.json';
+                          implies --per-file
+    -f, --format          Output format: json, yaml or toml (default: json)
+    -m, --minify          Write compact JSON without whitespace
     -l, --with-oneliner   Include oneliner property in output (default: disabled)
+        --oneliner-order  Order of the oneliner's spells: alphabetical (like spells, the
+                          default), source or category
+        --pretty-spells   Group the oneliner by property category, separated by ' | '
+        --with-source     Include the CSS selector(s) each scroll came from
+        --spell-sources   Include the component and selector each spell came from
+        --shared-with     Include the scrolls each scroll shared a selector list with
+        --collapse-single Emit single-spell scrolls as a scalar spell field
+        --include-elements
+                          Name the scrolls of tag and universal selectors element:<tag>
+        --keep-duplicates Keep every declaration of a property repeated in a block
+                          instead of only the last (winning) one
+        --area-separator  Separator between a spell's area and the spell (default: __)
+        --lowercase-properties
+                          Lowercase property names so differently cased declarations dedupe
+        --lowercase-values
+                          Also lowercase values, except quoted strings and url() contents
+        --strip-vendor-prefixes
+                          Strip -webkit-, -moz-, -ms- and -o- from property names, merging
+                          prefixed declarations into one spell
+        --invalid-names   What to do with scroll names that are not valid Grimoire keys
+                          (e.g. w-1/2): keep (default), sanitize or skip; sanitized and
+                          skipped names are listed in a warnings section
+        --on-conflict     What to do when differently spelled selectors (.a.b and .a\\.b)
+                          become one scroll with different spells: merge (default), warn,
+                          error or rename (numeric suffix)
+        --continue-on-error
+                          Leave out input files that fail to parse and list them in a
+                          failed_files section instead of aborting
+        --strict          Fail on anything that would be dropped (unsupported at-rules and
+                          tokens, empty rules, classes that already are spells)
+        --limit           Stop after the given number of selectors, for a quick partial run
+        --skip-properties Leave declarations of the given properties (comma-separated) out
+                          of the scrolls, e.g. content
+    -t, --timeout         Abort paths mode if the whole run exceeds the given seconds
+        --max-file-size   Fail if an input file is larger than the given megabytes (default: 64)
+        --max-total-size  Fail if all input files together are larger than the given
+                          megabytes (default: 64)
+        --non-css         What to do with matched files that are obviously no stylesheet
+                          (binary, or e.g. .js or .png): error (default) or skip
+        --split-media     Move rules for the given media types (comma-separated) into
+                          a separate split_media section
+        --breakpoints     Group each scroll's spells by breakpoint into a responsive section,
+                          given as comma-separated alias=media-condition pairs
+        --group-by-area   Nest scrolls under their area in an areas section
+    -C, --config          Path to a Grimoire CSS config; classes that are scrolls or shared
+                          spells in it are skipped as existing spells
+        --check           Only report unsupported constructs, classes that already are spells
+                          and dropped rules, writing nothing; fails if there are any
+        --check-grimoire  Verify every generated spell builds with Grimoire CSS
+        --histogram       Print how many declarations each CSS property had, most used first
+        --emit-sourcemap  Write a JSON map of every spell to its source byte range
+        --emit-rust       Write the scrolls as Rust source (a static map) to the given path
+    -w, --watch           Re-run paths mode whenever a matched CSS file changes
+        --cache           Reuse the results of files unchanged since the last run (paths mode),
+                          kept in grimoire/.gcsst-cache.json
+        --append          Merge the result into the existing output file (paths mode) instead
+                          of overwriting it; scrolls of the same name get the union of spells
+        --cwd             Run as if started in the given directory: input patterns, output,
+                          config and cache paths are resolved against it
+        --dry-run         Run the whole pipeline and print a summary, but write no files
+    -q, --quiet           Print nothing but errors to stderr
+        --error-json      Report errors on stderr as a JSON object with an error_kind
+                          (e.g. no_files_matched, timed_out) and a message
+    -v, --verbose         Also print parser notices, per-file progress, stats and timings
+                          to stderr
     -h, --help            Display this help message
 
 EXAMPLES:
     grimoire_css_transmutator -p styles.css,components.css
     grimoire_css_transmutator -c '.button { color: red; }' -l
     grimoire_css_transmutator -p '*.css' -o custom_output.json --with-oneliner
+    grimoire_css_transmutator -p 'src/**/*.css' --timeout 30
+    grimoire_css_transmutator -p 'src/**/*.css' -e '**/node_modules/**,**/vendor/**'
+    grimoire_css_transmutator -p 'src/**/*.css' --split-media print
+    grimoire_css_transmutator -p 'src/**/*.css' --watch
+    grimoire_css_transmutator -p 'src/**/*.css' --breakpoints 'md=(min-width: 768px),lg=(min-width: 1024px)'
 ";
 
 type AppResult<T> = Result<T, GrimoireCssError>;
 
+/// How often the watch loop checks for Ctrl-C while idle
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Quiet period that ends a burst of file events in watch mode
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
+
 struct Config {
     mode: Mode,
     input: String,
+    exclude: Vec<String>,
+    html: bool,
     output_path: Option<String>,
+    /// Directory of the default output, `./grimoire` unless given
+    output_dir: Option<String>,
+    per_file: bool,
+    /// Per-file output path with `{dir}`, `{name}` and `{ext}` placeholders
+    output_template: Option<String>,
+    format: OutputFormat,
+    minify: bool,
     include_oneliner: bool,
+    include_source: bool,
+    collapse_single: bool,
+    limits: RunLimits,
+    parse: ParseOptions,
+    split_media: Vec<String>,
+    breakpoints: Vec<(String, String)>,
+    group_by_area: bool,
+    check_grimoire: bool,
+    /// Only report what would not transmute cleanly, writing no output
+    check: bool,
+    histogram: bool,
+    sourcemap_path: Option<String>,
+    rust_path: Option<String>,
+    watch: bool,
+    cache: bool,
+    append: bool,
+    dry_run: bool,
+    verbosity: Verbosity,
+}
+
+impl Config {
+    /// Print a status line to stderr unless quiet
+    fn status(&self, message: fmt::Arguments) {
+        if self.verbosity >= Verbosity::Normal {
+            eprintln!("{message}");
+        }
+    }
+
+    /// Print a progress or diagnostic line to stderr when verbose
+    fn detail(&self, message: fmt::Arguments) {
+        if self.verbosity >= Verbosity::Verbose {
+            eprintln!("{message}");
+        }
+    }
+
+    /// The directory relative paths are resolved against: `--cwd` or the
+    /// working directory
+    fn base_dir(&self) -> AppResult<PathBuf> {
+        match &self.limits.base_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => current_dir(),
+        }
+    }
+
+    /// `path` resolved against `--cwd`, if given
+    fn resolve(&self, path: impl AsRef<Path>) -> PathBuf {
+        match &self.limits.base_dir {
+            Some(dir) => dir.join(path),
+            None => path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The library options for a paths-mode run
+    fn transmute_options(&self) -> TransmuteOptions {
+        TransmuteOptions::new()
+            .include_oneliner(self.include_oneliner)
+            .include_source(self.include_source)
+            .parse(self.parse.clone())
+            .exclude(self.exclude.clone())
+            .limits(self.limits.clone())
+    }
+}
+
+/// How much is printed to stderr besides errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
 }
 
 enum Mode {
@@ -41,8 +221,16 @@ enum Mode {
 }
 
 fn main() {
-    process::exit(match run_app() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    // Checked up front so argument errors are reported as JSON too
+    let error_json = args.iter().any(|arg| arg == "--error-json");
+
+    process::exit(match run_app(args) {
         Ok(_) => 0,
+        Err(err) if error_json => {
+            eprintln!("{}", error_to_json(&err));
+            1
+        }
         Err(err) => {
             eprintln!("Error: {err}");
             1
@@ -50,35 +238,128 @@ fn main() {
     });
 }
 
-fn run_app() -> AppResult<()> {
-    let config = parse_args()?;
+/// The machine-readable kind of an error, reported as `error_kind` by
+/// `--error-json` so scripts can branch on it.
+fn error_kind(err: &GrimoireCssError) -> &'static str {
+    match err {
+        GrimoireCssError::InvalidPath(msg) if msg == NO_FILES_MATCHED => "no_files_matched",
+        GrimoireCssError::Io(e) if e.kind() == io::ErrorKind::TimedOut => "timed_out",
+        GrimoireCssError::InvalidPath(_) => "invalid_path",
+        GrimoireCssError::InvalidInput(_) => "invalid_input",
+        GrimoireCssError::InvalidSpellFormat(_) => "invalid_spell_format",
+        GrimoireCssError::GlobPatternError(_) => "invalid_glob",
+        GrimoireCssError::Io(_) => "io",
+        GrimoireCssError::Serde(_) => "serialization",
+        GrimoireCssError::RuntimeError(_) => "runtime",
+    }
+}
+
+/// Formats an error as a single-line JSON object for `--error-json`.
+fn error_to_json(err: &GrimoireCssError) -> String {
+    serde_json::json!({
+        "error_kind": error_kind(err),
+        "message": err.to_string(),
+    })
+    .to_string()
+}
+
+fn run_app(args: Vec<String>) -> AppResult<()> {
+    let config = parse_args(args)?;
+    init_logger(config.verbosity);
 
     match config.mode {
         Mode::Help => {
             print!("{HELP_MESSAGE}");
             Ok(())
         }
+        Mode::Paths | Mode::Content if config.check => process_check_mode(&config),
+        Mode::Paths | Mode::Content if config.html => process_html_mode(&config),
         Mode::Paths => process_paths_mode(&config),
         Mode::Content => process_content_mode(&config),
     }
 }
 
-fn parse_args() -> AppResult<Config> {
-    let args: Vec<String> = env::args().skip(1).collect();
+/// Print the library's log records (parser notices) to stderr when verbose;
+/// `RUST_LOG` overrides the level, e.g. `RUST_LOG=debug` for per-file reads
+fn init_logger(verbosity: Verbosity) {
+    let level = if verbosity >= Verbosity::Verbose {
+        log::LevelFilter::Info
+    } else {
+        log::LevelFilter::Off
+    };
+
+    // Fails when already installed, e.g. by an earlier run in the tests
+    let _ = env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .try_init();
+}
 
+fn parse_args(args: Vec<String>) -> AppResult<Config> {
     if args.is_empty() || args.contains(&"-h".to_string()) || args.contains(&"--help".to_string()) {
         return Ok(Config {
             mode: Mode::Help,
             input: String::new(),
+            exclude: Vec::new(),
+            html: false,
             output_path: None,
+            output_dir: None,
+            per_file: false,
+            output_template: None,
+            format: OutputFormat::Json,
+            minify: false,
             include_oneliner: false,
+            include_source: false,
+            collapse_single: false,
+            limits: RunLimits::default(),
+            parse: ParseOptions::default(),
+            split_media: Vec::new(),
+            breakpoints: Vec::new(),
+            group_by_area: false,
+            check_grimoire: false,
+            check: false,
+            histogram: false,
+            sourcemap_path: None,
+            rust_path: None,
+            watch: false,
+            cache: false,
+            append: false,
+            dry_run: false,
+            verbosity: Verbosity::Normal,
         });
     }
 
     let mut mode = None;
     let mut input = None;
+    let mut exclude = Vec::new();
+    let mut html = false;
     let mut output_path = None;
+    let mut output_dir = None;
+    let mut per_file = false;
+    let mut output_template = None;
+    let mut format = OutputFormat::Json;
+    let mut minify = false;
     let mut include_oneliner = false;
+    let mut include_source = false;
+    let mut collapse_single = false;
+    let mut limits = RunLimits::default();
+    let mut parse = ParseOptions::default();
+    let mut split_media = Vec::new();
+    let mut breakpoints = Vec::new();
+    let mut group_by_area = false;
+    let mut check_grimoire = false;
+    let mut check = false;
+    let mut histogram = false;
+    let mut sourcemap_path = None;
+    let mut grimoire_config_path = None;
+    let mut rust_path = None;
+    let mut watch = false;
+    let mut cache = false;
+    let mut append = false;
+    let mut dry_run = false;
+    let mut quiet = false;
+    let mut verbose = false;
     let mut i = 0;
 
     while i < args.len() {
@@ -90,6 +371,18 @@ fn parse_args() -> AppResult<Config> {
                     i += 1;
                 }
             }
+            "-e" | "--exclude" => {
+                if i + 1 < args.len() && !args[i + 1].starts_with('-') {
+                    exclude = split_list(&args[i + 1]);
+                    i += 1;
+                }
+            }
+            "--follow-imports" => {
+                limits.follow_imports = true;
+            }
+            "--html" => {
+                html = true;
+            }
             "-c" | "--content" => {
                 mode = Some(Mode::Content);
                 if i + 1 < args.len() && !args[i + 1].starts_with('-') {
@@ -103,9 +396,228 @@ fn parse_args() -> AppResult<Config> {
                     i += 1;
                 }
             }
+            "--output-dir" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Output directory not provided.".into())
+                })?;
+                output_dir = Some(value.clone());
+                i += 1;
+            }
+            "-f" | "--format" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Output format not provided.".into())
+                })?;
+                format = value.parse()?;
+                i += 1;
+            }
+            "--per-file" | "--split" => {
+                per_file = true;
+            }
+            "--output-template" => {
+                let template = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Output template not provided.".into())
+                })?;
+                validate_output_template(template)?;
+                output_template = Some(template.clone());
+                per_file = true;
+                i += 1;
+            }
+            "-m" | "--minify" => {
+                minify = true;
+            }
             "-l" | "--with-oneliner" => {
                 include_oneliner = true;
             }
+            "--with-source" => {
+                include_source = true;
+            }
+            "--collapse-single" => {
+                collapse_single = true;
+            }
+            "--include-elements" => {
+                parse.include_elements = true;
+            }
+            "--keep-duplicates" => {
+                parse.keep_duplicate_properties = true;
+            }
+            "--lowercase-properties" => {
+                parse.lowercase_properties = true;
+            }
+            "--lowercase-values" => {
+                parse.lowercase_values = true;
+            }
+            "--pretty-spells" => {
+                parse.pretty_spells = true;
+            }
+            "--oneliner-order" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Oneliner order not provided.".into())
+                })?;
+                parse.oneliner_order = value.parse()?;
+                i += 1;
+            }
+            "--strip-vendor-prefixes" => {
+                parse.strip_vendor_prefixes = true;
+            }
+            "--spell-sources" => {
+                parse.spell_sources = true;
+            }
+            "--shared-with" => {
+                parse.shared_with = true;
+            }
+            "--continue-on-error" => {
+                parse.continue_on_error = true;
+            }
+            "--strict" => {
+                parse.strict = true;
+            }
+            "--limit" => {
+                let value = args
+                    .get(i + 1)
+                    .ok_or_else(|| GrimoireCssError::InvalidInput("Limit not provided.".into()))?;
+                let limit = value
+                    .parse()
+                    .ok()
+                    .filter(|limit| *limit > 0)
+                    .ok_or_else(|| {
+                        GrimoireCssError::InvalidInput(format!("Invalid limit: {value}"))
+                    })?;
+                parse.limit = Some(limit);
+                i += 1;
+            }
+            "--invalid-names" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Invalid name policy not provided.".into())
+                })?;
+                parse.invalid_names = value.parse()?;
+                i += 1;
+            }
+            "--on-conflict" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Conflict policy not provided.".into())
+                })?;
+                parse.on_conflict = value.parse()?;
+                i += 1;
+            }
+            "--skip-properties" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Properties to skip not provided.".into())
+                })?;
+                parse.skip_properties = split_list(value);
+                i += 1;
+            }
+            "--area-separator" => {
+                parse.area_separator = args
+                    .get(i + 1)
+                    .filter(|separator| !separator.is_empty())
+                    .ok_or_else(|| {
+                        GrimoireCssError::InvalidInput("Area separator not provided.".into())
+                    })?
+                    .clone();
+                i += 1;
+            }
+            "-t" | "--timeout" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Timeout value not provided.".into())
+                })?;
+                limits.timeout = Some(parse_timeout(value)?);
+                i += 1;
+            }
+            "--non-css" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Non-CSS file policy not provided.".into())
+                })?;
+                limits.non_css = value.parse()?;
+                i += 1;
+            }
+            "--max-file-size" | "--max-total-size" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput(format!("{} value not provided.", args[i]))
+                })?;
+                let size = parse_megabytes(value)?;
+                if args[i] == "--max-file-size" {
+                    limits.max_file_size = size;
+                } else {
+                    limits.max_total_size = size;
+                }
+                i += 1;
+            }
+            "--split-media" => {
+                if i + 1 < args.len() && !args[i + 1].starts_with('-') {
+                    split_media = split_list(&args[i + 1]);
+                    i += 1;
+                }
+            }
+            "--breakpoints" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Breakpoints not provided.".into())
+                })?;
+                breakpoints = parse_breakpoints(value)?;
+                i += 1;
+            }
+            "--group-by-area" => {
+                group_by_area = true;
+            }
+            "--append" => {
+                append = true;
+            }
+            "--dry-run" => {
+                dry_run = true;
+            }
+            // Handled in `main`, before parsing
+            "--error-json" => {}
+            "--cwd" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Directory not provided for --cwd.".into())
+                })?;
+                if !Path::new(value).is_dir() {
+                    return Err(GrimoireCssError::InvalidPath(format!(
+                        "--cwd {value} is not a directory"
+                    )));
+                }
+                limits.base_dir = Some(PathBuf::from(value));
+                i += 1;
+            }
+            "-q" | "--quiet" => {
+                quiet = true;
+            }
+            "-v" | "--verbose" => {
+                verbose = true;
+            }
+            "-w" | "--watch" => {
+                watch = true;
+            }
+            "--cache" => {
+                cache = true;
+            }
+            "-C" | "--config" => {
+                let value = args.get(i + 1).ok_or_else(|| {
+                    GrimoireCssError::InvalidInput("Config path not provided.".into())
+                })?;
+                grimoire_config_path = Some(value.clone());
+                i += 1;
+            }
+            "--check-grimoire" => {
+                check_grimoire = true;
+            }
+            "--check" => {
+                check = true;
+            }
+            "--histogram" => {
+                histogram = true;
+            }
+            "--emit-sourcemap" => {
+                if i + 1 < args.len() && !args[i + 1].starts_with('-') {
+                    sourcemap_path = Some(args[i + 1].clone());
+                    i += 1;
+                }
+            }
+            "--emit-rust" => {
+                if i + 1 < args.len() && !args[i + 1].starts_with('-') {
+                    rust_path = Some(args[i + 1].clone());
+                    i += 1;
+                }
+            }
             arg if arg.starts_with('-') => {
                 return Err(GrimoireCssError::InvalidInput(format!(
                     "Unknown option: {arg}"
@@ -129,75 +641,1143 @@ fn parse_args() -> AppResult<Config> {
     let input =
         input.ok_or_else(|| GrimoireCssError::InvalidInput("Input not provided.".into()))?;
 
-    Ok(Config {
+    let verbosity = match (quiet, verbose) {
+        (true, true) => {
+            return Err(GrimoireCssError::InvalidInput(
+                "--quiet and --verbose cannot be combined.".into(),
+            ))
+        }
+        (true, false) => Verbosity::Quiet,
+        (false, true) => Verbosity::Verbose,
+        (false, false) => Verbosity::Normal,
+    };
+
+    if html && (per_file || watch) {
+        return Err(GrimoireCssError::InvalidInput(
+            "--html cannot be combined with --per-file or --watch.".into(),
+        ));
+    }
+
+    if check && (html || per_file || watch) {
+        return Err(GrimoireCssError::InvalidInput(
+            "--check cannot be combined with --html, --per-file or --watch.".into(),
+        ));
+    }
+
+    if output_template.is_some() && output_path.is_some() {
+        return Err(GrimoireCssError::InvalidInput(
+            "--output-template cannot be combined with -o.".into(),
+        ));
+    }
+
+    if output_dir.is_some() && (output_template.is_some() || !matches!(mode, Mode::Paths)) {
+        return Err(GrimoireCssError::InvalidInput(
+            "--output-dir only works in paths mode and cannot be combined with --output-template."
+                .into(),
+        ));
+    }
+
+    if append && (per_file || !matches!(mode, Mode::Paths)) {
+        return Err(GrimoireCssError::InvalidInput(
+            "--append only works with a single output file in paths mode.".into(),
+        ));
+    }
+
+    if per_file && (sourcemap_path.is_some() || rust_path.is_some()) {
+        return Err(GrimoireCssError::InvalidInput(
+            "--emit-sourcemap and --emit-rust cannot be combined with --per-file.".into(),
+        ));
+    }
+
+    let mut config = Config {
         mode,
         input,
+        exclude,
+        html,
         output_path,
+        output_dir,
+        per_file,
+        output_template,
+        format,
+        minify,
         include_oneliner,
-    })
+        include_source,
+        collapse_single,
+        limits,
+        parse,
+        split_media,
+        breakpoints,
+        group_by_area,
+        check_grimoire,
+        check,
+        histogram,
+        sourcemap_path,
+        rust_path,
+        watch,
+        cache,
+        append,
+        dry_run,
+        verbosity,
+    };
+    // Loaded once `--cwd` is known, as the path is relative to it
+    if let Some(path) = grimoire_config_path {
+        config.parse.grimoire_config = Some(GrimoireConfig::load(&config.resolve(path))?);
+    }
+
+    Ok(config)
 }
 
-/// Process CSS files in paths mode
-fn process_paths_mode(config: &Config) -> AppResult<()> {
-    // Split paths by comma and trim whitespace
-    let paths: Vec<String> = config
-        .input
+/// Splits a comma-separated CLI value into trimmed, non-empty items.
+fn split_list(value: &str) -> Vec<String> {
+    value
         .split(',')
         .map(|s| s.trim().to_string())
-        .collect();
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Parses comma-separated `alias=media-condition` breakpoint pairs. Commas
+/// inside parentheses are kept, and an item without an `alias=` continues the
+/// media query list of the pair before it, e.g. `md=print, (max-width: 600px)`.
+fn parse_breakpoints(value: &str) -> AppResult<Vec<(String, String)>> {
+    let mut breakpoints: Vec<(String, String)> = Vec::new();
+    let mut depth = 0usize;
+    let items = value.split(|c| {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        c == ',' && depth == 0
+    });
+
+    for item in items.map(str::trim).filter(|item| !item.is_empty()) {
+        // An `=` inside parentheses belongs to a range, e.g. `(width >= 600px)`
+        let pair = item
+            .split_once('=')
+            .filter(|(alias, _)| !alias.contains('('));
+        match (pair, breakpoints.last_mut()) {
+            (Some((alias, condition)), _) if !alias.trim().is_empty() => {
+                breakpoints.push((alias.trim().to_string(), condition.trim().to_string()));
+            }
+            (None, Some((_, condition))) => {
+                condition.push_str(", ");
+                condition.push_str(item);
+            }
+            _ => {
+                return Err(GrimoireCssError::InvalidInput(format!(
+                    "Invalid breakpoint '{item}', expected alias=media-condition"
+                )))
+            }
+        }
+    }
 
-    let (duration, json_output) = run_transmutation(paths, config.include_oneliner)?;
+    Ok(breakpoints)
+}
+
+/// Parses a timeout given in (possibly fractional) seconds.
+fn parse_timeout(value: &str) -> AppResult<Duration> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| GrimoireCssError::InvalidInput(format!("Invalid timeout: {value}")))
+}
+
+/// Parses a size given in (possibly fractional) megabytes into bytes.
+fn parse_megabytes(value: &str) -> AppResult<u64> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|megabytes| megabytes.is_finite() && *megabytes >= 0.0)
+        .map(|megabytes| (megabytes * 1024.0 * 1024.0) as u64)
+        .ok_or_else(|| GrimoireCssError::InvalidInput(format!("Invalid size: {value}")))
+}
+
+/// Process CSS files in paths mode
+fn process_paths_mode(config: &Config) -> AppResult<()> {
+    let paths = read_patterns(&config.input, config.limits.base_dir.as_deref())?;
+
+    if config.watch {
+        watch_paths(config, &paths)
+    } else {
+        transmute_paths(config, paths)
+    }
+}
+
+/// Splits the comma-separated `-p` value into patterns, replacing every
+/// `@file` item with the newline-separated patterns listed in that file
+/// (blank lines and `#` comments are skipped). Patterns files are read
+/// relative to `base_dir`, if given.
+fn read_patterns(input: &str, base_dir: Option<&Path>) -> AppResult<Vec<String>> {
+    let mut patterns = Vec::new();
+
+    for item in input.split(',').map(str::trim) {
+        match item.strip_prefix('@') {
+            Some(file) => {
+                let path = base_dir.map_or_else(|| PathBuf::from(file), |dir| dir.join(file));
+                let content = fs::read_to_string(path).map_err(|e| {
+                    GrimoireCssError::InvalidPath(format!("Cannot read patterns file {file}: {e}"))
+                })?;
+                patterns.extend(
+                    content
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(str::to_string),
+                );
+            }
+            None => patterns.push(item.to_string()),
+        }
+    }
+
+    Ok(patterns)
+}
+
+/// Transmute the given path patterns once and write the output
+fn transmute_paths(config: &Config, paths: Vec<String>) -> AppResult<()> {
+    if config.per_file {
+        return transmute_paths_per_file(config, paths);
+    }
+
+    let start_time = Instant::now();
+    let mut cache = config
+        .cache
+        .then(|| ParseCache::load(&config.resolve(DEFAULT_CACHE_PATH)));
+    let mut transmuted = run_transmutation_to_struct_with(
+        paths,
+        &config.transmute_options(),
+        RunHooks {
+            progress: Some(&mut progress_reporter(config)),
+            cache: cache.as_mut(),
+        },
+    )?;
+    save_cache(config, cache)?;
+    shape_output(config, &mut transmuted)?;
+    if config.append {
+        transmuted = append_to_output(config, transmuted)?;
+    }
+    let duration = start_time.elapsed();
+    if config.dry_run {
+        return Ok(());
+    }
 
     // Handle output
+    let serialize_start = Instant::now();
     match &config.output_path {
-        Some(path) => write_to_file(path, &json_output)?,
+        Some(path) => write_transmuted(config, path, &transmuted)?,
         None => {
-            let cwd = env::current_dir().map_err(GrimoireCssError::Io)?;
-            let output_dir = cwd.join("grimoire");
-            fs::create_dir_all(&output_dir).map_err(GrimoireCssError::Io)?;
-            let output_file = output_dir.join("transmuted.json");
-            write_to_file(&output_file.to_string_lossy(), &json_output)?;
+            let output_file = output_file(config)?;
+            write_transmuted(config, &output_file.to_string_lossy(), &transmuted)?;
 
-            eprintln!(
+            config.status(format_args!(
                 "Transmutation complete in {duration:.2?}. Output written to {output_file:?}"
-            );
+            ));
         }
     }
+    report_timings(config, &transmuted, serialize_start, start_time);
 
     Ok(())
 }
 
+/// The single output file of paths mode: `-o` or `transmuted.<ext>` in the
+/// output directory
+fn output_file(config: &Config) -> AppResult<PathBuf> {
+    match &config.output_path {
+        Some(path) => Ok(config.resolve(path)),
+        None => {
+            let file_name = format!("transmuted.{}", config.format.extension());
+            Ok(output_dir(config)?.join(file_name))
+        }
+    }
+}
+
+/// The working directory, with a hint at `--cwd` when it cannot be read
+fn current_dir() -> AppResult<PathBuf> {
+    env::current_dir().map_err(|e| {
+        GrimoireCssError::Io(io::Error::new(
+            e.kind(),
+            format!("Cannot read the working directory ({e}); pass one with --cwd"),
+        ))
+    })
+}
+
+/// The directory of the default output: `--output-dir` or `./grimoire`
+fn output_dir(config: &Config) -> AppResult<PathBuf> {
+    Ok(config
+        .base_dir()?
+        .join(config.output_dir.as_deref().unwrap_or("grimoire")))
+}
+
+/// Merge the result into the existing output file, if there is one
+fn append_to_output(config: &Config, transmuted: Transmuted) -> AppResult<Transmuted> {
+    let path = output_file(config)?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(transmuted),
+        Err(e) => return Err(GrimoireCssError::Io(e)),
+    };
+
+    let mut existing = Transmuted::deserialize_as(&content, config.format).map_err(|e| {
+        GrimoireCssError::InvalidInput(format!("Cannot append to {}: {e}", path.display()))
+    })?;
+    config.detail(format_args!(
+        "Appending to {} existing scrolls in {}",
+        existing.scrolls.len(),
+        path.display()
+    ));
+    existing.merge(transmuted);
+
+    Ok(existing)
+}
+
+/// Writes the parse cache back (unless this is a dry run)
+fn save_cache(config: &Config, cache: Option<ParseCache>) -> AppResult<()> {
+    match cache {
+        Some(cache) if !config.dry_run => cache.save(&config.resolve(DEFAULT_CACHE_PATH)),
+        _ => Ok(()),
+    }
+}
+
+/// Transmute each matched file separately into a mirrored output tree
+fn transmute_paths_per_file(config: &Config, paths: Vec<String>) -> AppResult<()> {
+    let start_time = Instant::now();
+    let cwd = config.base_dir()?;
+    let output_dir = match &config.output_path {
+        Some(path) => config.resolve(path),
+        None => output_dir(config)?,
+    };
+
+    let mut cache = config
+        .cache
+        .then(|| ParseCache::load(&config.resolve(DEFAULT_CACHE_PATH)));
+    let results = run_transmutation_per_file_with(
+        paths,
+        &config.transmute_options(),
+        RunHooks {
+            progress: Some(&mut progress_reporter(config)),
+            cache: cache.as_mut(),
+        },
+    )?;
+    save_cache(config, cache)?;
+
+    let mut written = HashSet::new();
+    for (path, mut transmuted) in results {
+        if transmuted.scrolls.is_empty() {
+            match transmuted.failed_files.first() {
+                Some((_, error)) => config.status(format_args!("Skipped {path:?}: {error}")),
+                None => config.status(format_args!("Nothing to transmute in {path:?}, skipped.")),
+            }
+            continue;
+        }
+
+        config.detail(format_args!("Transmuted {path:?}"));
+        shape_output(config, &mut transmuted)?;
+        if config.dry_run {
+            continue;
+        }
+        let output_file = match &config.output_template {
+            Some(template) => {
+                let base = cwd.join(expand_output_template(template, &cwd, &path));
+                let extension = base.extension().map_or_else(
+                    || config.format.extension().to_string(),
+                    |ext| ext.to_string_lossy().into_owned(),
+                );
+                unique_output_file(&base, &extension, &mut written)
+            }
+            None => unique_output_file(
+                &output_dir.join(mirrored_path(&cwd, &path)),
+                config.format.extension(),
+                &mut written,
+            ),
+        };
+        write_transmuted(config, &output_file.to_string_lossy(), &transmuted)?;
+    }
+
+    config.status(format_args!(
+        "Transmutation complete in {:.2?}, {} file(s) written.",
+        start_time.elapsed(),
+        written.len()
+    ));
+
+    Ok(())
+}
+
+/// Render `[12/340] src/foo.css` as each file is read: rewritten in place on
+/// a terminal unless quiet, otherwise one line per file when verbose
+fn progress_reporter(config: &Config) -> impl FnMut(usize, usize, &Path) + '_ {
+    let terminal = io::stderr().is_terminal();
+
+    move |done, total, path| {
+        if !terminal {
+            config.detail(format_args!("[{done}/{total}] {}", path.display()));
+        } else if config.verbosity > Verbosity::Quiet {
+            eprint!("\r\x1b[K[{done}/{total}] {}", path.display());
+            if done == total {
+                eprintln!();
+            }
+        }
+    }
+}
+
+/// The input path relative to the working directory, or its path without the
+/// root when it lies outside of it
+fn mirrored_path(cwd: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix(cwd) {
+        Ok(relative) => relative.to_path_buf(),
+        Err(_) => path
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect(),
+    }
+}
+
+/// Placeholders an output template can use
+const TEMPLATE_PLACEHOLDERS: [&str; 3] = ["{dir}", "{name}", "{ext}"];
+
+/// Fails unless the template references `{name}` and no unknown placeholder
+fn validate_output_template(template: &str) -> AppResult<()> {
+    if !template.contains("{name}") {
+        return Err(GrimoireCssError::InvalidInput(format!(
+            "Output template '{template}' must contain {{name}}."
+        )));
+    }
+
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map_or(rest.len(), |end| start + end + 1);
+        let placeholder = &rest[start..end];
+        if !TEMPLATE_PLACEHOLDERS.contains(&placeholder) {
+            return Err(GrimoireCssError::InvalidInput(format!(
+                "Unknown placeholder '{placeholder}' in output template, expected {}.",
+                TEMPLATE_PLACEHOLDERS.join(", ")
+            )));
+        }
+        rest = &rest[end..];
+    }
+
+    Ok(())
+}
+
+/// Expands an output template for an input file: `{dir}` is its directory
+/// (see `mirrored_path`), `{name}` its file name without the extension and
+/// `{ext}` the extension
+fn expand_output_template(template: &str, cwd: &Path, path: &Path) -> PathBuf {
+    let relative = mirrored_path(cwd, path);
+    let dir = relative.parent().unwrap_or(Path::new("")).to_string_lossy();
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+
+    // A file in the working directory has no directory to add
+    let template = if dir.is_empty() {
+        template.replace("{dir}/", "")
+    } else {
+        template.to_string()
+    };
+
+    PathBuf::from(
+        template
+            .replace("{dir}", &dir)
+            .replace("{name}", &name)
+            .replace("{ext}", &ext),
+    )
+}
+
+/// The output file for `base` with the given extension, numbered (`a-1.json`)
+/// when that file was already written during this run
+fn unique_output_file(base: &Path, extension: &str, written: &mut HashSet<PathBuf>) -> PathBuf {
+    let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+    let mut candidate = base.with_extension(extension);
+    let mut counter = 1;
+
+    while written.contains(&candidate) {
+        candidate = base.with_file_name(format!("{stem}-{counter}.{extension}"));
+        counter += 1;
+    }
+
+    written.insert(candidate.clone());
+    candidate
+}
+
+/// Re-transmute whenever a CSS file under the watched patterns changes, until Ctrl-C
+fn watch_paths(config: &Config, paths: &[String]) -> AppResult<()> {
+    let cwd = config.base_dir()?;
+
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_flag = Arc::clone(&running);
+    ctrlc::set_handler(move || handler_flag.store(false, Ordering::SeqCst))
+        .map_err(|e| GrimoireCssError::Io(io::Error::other(e)))?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(tx).map_err(|e| GrimoireCssError::Io(io::Error::other(e)))?;
+
+    for root in watch_roots(&cwd, paths) {
+        watcher
+            .watch(&root, RecursiveMode::Recursive)
+            .map_err(|e| GrimoireCssError::Io(io::Error::other(e)))?;
+        config.status(format_args!("Watching {root:?}"));
+    }
+
+    // Globs are re-expanded on every run, so new files are picked up too
+    if let Err(err) = transmute_paths(config, paths.to_vec()) {
+        eprintln!("Error: {err}");
+    }
+
+    while running.load(Ordering::SeqCst) {
+        match rx.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(Ok(event)) if is_css_change(&event) => {
+                // Debounce: wait until the burst of events settles
+                while rx.recv_timeout(WATCH_DEBOUNCE).is_ok() {}
+
+                if let Err(err) = transmute_paths(config, paths.to_vec()) {
+                    eprintln!("Error: {err}");
+                }
+            }
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(Err(err)) => eprintln!("Watch error: {err}"),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    config.status(format_args!("Watch stopped."));
+    Ok(())
+}
+
+/// Directories to watch: the non-glob prefix of each pattern
+fn watch_roots(cwd: &Path, patterns: &[String]) -> Vec<PathBuf> {
+    let mut roots: Vec<PathBuf> = Vec::new();
+
+    for pattern in patterns {
+        let mut root = PathBuf::new();
+        let mut has_glob = false;
+
+        for component in cwd.join(pattern).components() {
+            if component
+                .as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '[', '{'])
+            {
+                has_glob = true;
+                break;
+            }
+            root.push(component);
+        }
+
+        if !has_glob && !root.is_dir() {
+            root.pop();
+        }
+
+        if !roots.iter().any(|existing| root.starts_with(existing)) {
+            roots.retain(|existing| !existing.starts_with(&root));
+            roots.push(root);
+        }
+    }
+
+    roots
+}
+
+/// Whether a filesystem event touches a CSS file
+fn is_css_change(event: &Event) -> bool {
+    !matches!(event.kind, EventKind::Access(_))
+        && event.paths.iter().any(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("css"))
+        })
+}
+
 /// Process CSS content directly
 fn process_content_mode(config: &Config) -> AppResult<()> {
+    let start_time = Instant::now();
     // Pass the include_oneliner flag to the library function
-    let (duration, json_output) = transmute_from_content(&config.input, config.include_oneliner)?;
+    let mut transmuted = transmute_content_to_struct(
+        &config.input,
+        config.include_oneliner,
+        config.include_source,
+        &config.parse,
+    )?;
+    shape_output(config, &mut transmuted)?;
+    let duration = start_time.elapsed().as_secs_f64();
+    if config.dry_run {
+        return Ok(());
+    }
 
     // Handle output
+    let serialize_start = Instant::now();
     match &config.output_path {
-        Some(path) => write_to_file(path, &json_output)?,
+        Some(path) => write_transmuted(config, path, &transmuted)?,
         None => {
-            // Print JSON to stdout for redirection
-            io::stdout()
-                .write_all(json_output.as_bytes())
-                .map_err(GrimoireCssError::Io)?;
+            // Print the output to stdout for redirection
+            write_stdout(config, &transmuted, io::stdout().lock())?;
             // Print status to stderr
-            eprintln!("Transmutation complete in {duration:.2} seconds");
+            config.status(format_args!(
+                "Transmutation complete in {duration:.2} seconds"
+            ));
         }
     }
+    report_timings(config, &transmuted, serialize_start, start_time);
+
+    Ok(())
+}
+
+/// Write the result as the only content of stdout, ending with a newline so
+/// tools reading it get a complete line; human-readable text goes to stderr
+fn write_stdout(config: &Config, transmuted: &Transmuted, mut out: impl Write) -> AppResult<()> {
+    transmuted.write_as(&mut out, config.format, config.minify)?;
+    // YAML and TOML already end with a newline
+    if config.format == OutputFormat::Json {
+        out.write_all(b"\n").map_err(GrimoireCssError::Io)?;
+    }
+    out.flush().map_err(GrimoireCssError::Io)
+}
+
+/// Extract inline styles and class names from HTML files or content
+fn process_html_mode(config: &Config) -> AppResult<()> {
+    let extraction = match config.mode {
+        Mode::Paths => run_html_extraction(
+            read_patterns(&config.input, config.limits.base_dir.as_deref())?,
+            &config.exclude,
+            config.limits.clone(),
+        )?,
+        _ => extract_from_html(&config.input)?,
+    };
+    let output = extraction.serialize_as(config.format, config.minify)?;
+
+    if config.dry_run {
+        config.status(format_args!(
+            "Dry run: {} inline style(s), {} class name(s)",
+            extraction.inline_styles.len(),
+            extraction.classes.len()
+        ));
+        return Ok(());
+    }
+
+    match &config.output_path {
+        Some(path) => write_to_file(config, path, &output)?,
+        None => {
+            let newline = if output.ends_with('\n') { "" } else { "\n" };
+            io::stdout()
+                .write_all(format!("{output}{newline}").as_bytes())
+                .map_err(GrimoireCssError::Io)?
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply the output-shaping options and write the side outputs
+fn shape_output(config: &Config, transmuted: &mut Transmuted) -> AppResult<()> {
+    transmuted.split_media(&config.split_media);
+    if !config.breakpoints.is_empty() {
+        transmuted.group_breakpoints(&config.breakpoints);
+    }
+    for (path, error) in &transmuted.failed_files {
+        config.status(format_args!("Skipped {}: {error}", path.display()));
+    }
+    for (kind, count) in &transmuted.diagnostics {
+        config.detail(format_args!("Ignored {count} x {kind}"));
+    }
+    config.detail(format_args!("Stats: {}", transmuted.stats));
+    if config.histogram {
+        print_histogram(transmuted);
+    }
+    if config.check_grimoire {
+        check_grimoire(transmuted)?;
+    }
+    if config.dry_run {
+        report_dry_run(config, transmuted)?;
+    }
+    if let Some(path) = &config.sourcemap_path {
+        write_to_file(config, path, &transmuted.source_map_json()?)?;
+    }
+    if let Some(path) = &config.rust_path {
+        write_to_file(config, path, &transmuted.to_rust_source())?;
+    }
+    if config.group_by_area {
+        transmuted.group_by_area();
+    }
+    if config.collapse_single {
+        transmuted.collapse_single();
+    }
+
+    Ok(())
+}
+
+/// Print the time spent per phase when verbose, serialization being the
+/// writing of the output started at `serialize_start`
+fn report_timings(
+    config: &Config,
+    transmuted: &Transmuted,
+    serialize_start: Instant,
+    start_time: Instant,
+) {
+    let timings = Timings {
+        serialize: serialize_start.elapsed(),
+        total: start_time.elapsed(),
+        ..transmuted.timings
+    };
+    config.detail(format_args!("Timings: {timings}"));
+}
+
+/// Summarize what a dry run would have written and fail if it is nothing
+fn report_dry_run(config: &Config, transmuted: &Transmuted) -> AppResult<()> {
+    let scrolls: Vec<_> = transmuted
+        .scrolls
+        .iter()
+        .chain(transmuted.split_media.values().flatten())
+        .collect();
+    let spells: usize = scrolls.iter().map(|scroll| scroll.spells.len()).sum();
+
+    config.status(format_args!(
+        "Dry run: {} scroll(s), {spells} spell(s), {} class(es) skipped as existing spells",
+        scrolls.len(),
+        transmuted.skipped_existing_spells.len()
+    ));
+    for class in &transmuted.skipped_existing_spells {
+        config.status(format_args!("Class '{class}' is already a spell, skipped."));
+    }
+
+    if scrolls.is_empty() {
+        return Err(GrimoireCssError::InvalidInput(
+            "Dry run transmuted nothing.".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Print the property histogram as a table, most used properties first
+fn print_histogram(transmuted: &Transmuted) {
+    let mut rows: Vec<_> = transmuted.property_histogram.iter().collect();
+    rows.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+
+    let width = rows
+        .iter()
+        .map(|(property, _)| property.len())
+        .max()
+        .unwrap_or(0);
+    for (property, count) in rows {
+        eprintln!("{property:<width$}  {count}");
+    }
+}
+
+/// Report spells that Grimoire CSS cannot build and fail if there are any
+fn check_grimoire(transmuted: &Transmuted) -> AppResult<()> {
+    let issues = transmuted.check_grimoire();
+
+    for issue in &issues {
+        eprintln!(
+            "Spell '{}' of scroll '{}' {}",
+            issue.spell, issue.scroll, issue.reason
+        );
+    }
+
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(GrimoireCssError::InvalidInput(format!(
+            "{} spell(s) failed the Grimoire CSS check.",
+            issues.len()
+        )))
+    }
+}
+
+/// Report everything that would not transmute cleanly, failing if anything
+/// would not; nothing is written
+fn process_check_mode(config: &Config) -> AppResult<()> {
+    let reports = match config.mode {
+        Mode::Paths => {
+            let paths = read_patterns(&config.input, config.limits.base_dir.as_deref())?;
+            run_analysis(paths, &config.exclude, config.limits.clone(), &config.parse)?
+                .into_iter()
+                .map(|(path, report)| (format!("{}: ", path.display()), report))
+                .collect()
+        }
+        _ => vec![(String::new(), analyze_content(&config.input, &config.parse))],
+    };
+
+    let mut unclean = 0;
+    for (prefix, report) in &reports {
+        let report = match report {
+            Ok(report) => report,
+            Err(err) => {
+                eprintln!("{prefix}{err}");
+                unclean += 1;
+                continue;
+            }
+        };
+        for notice in &report.notices {
+            eprintln!("{prefix}{notice}");
+        }
+        for (kind, count) in &report.diagnostics {
+            eprintln!("{prefix}Ignored {count} x {kind}");
+        }
+        if !report.is_clean() {
+            unclean += 1;
+        }
+    }
+
+    if unclean > 0 {
+        return Err(GrimoireCssError::InvalidInput(format!(
+            "{unclean} of {} input(s) would not transmute cleanly.",
+            reports.len()
+        )));
+    }
+    config.status(format_args!(
+        "{} input(s) would transmute cleanly.",
+        reports.len()
+    ));
 
     Ok(())
 }
 
 /// Write content to a file with error handling
-fn write_to_file(path: &str, content: &str) -> AppResult<()> {
-    if let Some(parent) = PathBuf::from(path).parent() {
-        fs::create_dir_all(parent).map_err(GrimoireCssError::Io)?;
+fn write_to_file(config: &Config, path: &str, content: &str) -> AppResult<()> {
+    write_with(config, path, |file| {
+        file.write_all(content.as_bytes())
+            .map_err(GrimoireCssError::Io)
+    })
+}
+
+/// Serialize the result straight into the output file
+fn write_transmuted(config: &Config, path: &str, transmuted: &Transmuted) -> AppResult<()> {
+    write_with(config, path, |file| {
+        transmuted.write_as(file, config.format, config.minify)
+    })
+}
+
+/// Create the file and its directories and let `write` fill it, unless dry
+/// run. The file is replaced atomically, see [`write_atomically`]
+fn write_with(
+    config: &Config,
+    path: &str,
+    write: impl FnOnce(&mut BufWriter<File>) -> AppResult<()>,
+) -> AppResult<()> {
+    if config.dry_run {
+        config.detail(format_args!("Dry run, not writing {path}"));
+        return Ok(());
     }
 
-    let mut file = File::create(path).map_err(GrimoireCssError::Io)?;
-    file.write_all(content.as_bytes())
-        .map_err(GrimoireCssError::Io)?;
+    let target = config.resolve(path);
+    write_atomically(&target, write)?;
 
-    eprintln!("Output written to {path}");
+    config.status(format_args!("Output written to {}", target.display()));
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use grimoire_css_transmutator_lib::HtmlExtraction;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_verbosity() {
+        let config = parse_args(args(&["-c", ".a { color: red; }"])).unwrap();
+        assert_eq!(config.verbosity, Verbosity::Normal);
+
+        let config = parse_args(args(&["-c", ".a { color: red; }", "-q"])).unwrap();
+        assert_eq!(config.verbosity, Verbosity::Quiet);
+
+        let config = parse_args(args(&["--verbose", "-p", "a.css"])).unwrap();
+        assert_eq!(config.verbosity, Verbosity::Verbose);
+
+        assert!(matches!(
+            parse_args(args(&["-p", "a.css", "--quiet", "--verbose"])),
+            Err(GrimoireCssError::InvalidInput(msg)) if msg.contains("cannot be combined")
+        ));
+    }
+
+    #[test]
+    fn test_dry_run_writes_nothing() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("out.json");
+        let sourcemap = temp_dir.path().join("map.json");
+        let output_arg = output.to_string_lossy();
+        let sourcemap_arg = sourcemap.to_string_lossy();
+
+        let config = parse_args(args(&[
+            "-c",
+            ".a { color: red; }",
+            "-o",
+            &output_arg,
+            "--emit-sourcemap",
+            &sourcemap_arg,
+            "--dry-run",
+            "-q",
+        ]))
+        .unwrap();
+        process_content_mode(&config).unwrap();
+        assert!(!output.exists());
+        assert!(!sourcemap.exists());
+
+        // Only a rule without declarations, so nothing to transmute
+        let config =
+            parse_args(args(&["-c", ".a {}", "-o", &output_arg, "--dry-run", "-q"])).unwrap();
+        assert!(process_content_mode(&config).is_err());
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_error_json_for_empty_glob() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let pattern = temp_dir.path().join("*.css").to_string_lossy().into_owned();
+
+        let err = run_app(args(&["-p", &pattern, "--error-json"])).unwrap_err();
+        let json: serde_json::Value = serde_json::from_str(&error_to_json(&err)).unwrap();
+
+        assert_eq!(json["error_kind"], "no_files_matched");
+        assert!(json["message"].as_str().unwrap().contains(NO_FILES_MATCHED));
+
+        let err = run_app(args(&["-c", ".a { color: red", "--error-json"])).unwrap_err();
+        assert_eq!(error_kind(&err), "invalid_input");
+        let err = run_app(args(&["-c", ".a { color: red }", "--timeout", "x"])).unwrap_err();
+        assert_eq!(error_kind(&err), "invalid_input");
+    }
+
+    #[test]
+    fn test_output_template() {
+        let cwd = Path::new("/project");
+        let template = "grimoire/{dir}/{name}.scroll.json";
+        assert!(validate_output_template(template).is_ok());
+
+        assert_eq!(
+            expand_output_template(template, cwd, Path::new("/project/src/ui/button.css")),
+            PathBuf::from("grimoire/src/ui/button.scroll.json")
+        );
+        assert_eq!(
+            expand_output_template("out/{name}.{ext}.json", cwd, Path::new("/project/a.css")),
+            PathBuf::from("out/a.css.json")
+        );
+        assert_eq!(
+            expand_output_template(template, cwd, Path::new("/project/a.css")),
+            PathBuf::from("grimoire/a.scroll.json")
+        );
+
+        assert!(validate_output_template("grimoire/{dir}.json").is_err());
+        assert!(validate_output_template("grimoire/{name}-{hash}.json").is_err());
+    }
+
+    #[test]
+    fn test_parse_breakpoints() {
+        assert_eq!(
+            parse_breakpoints("md=print, (max-width: 600px), lg=(width >= 1024px)").unwrap(),
+            vec![
+                ("md".to_string(), "print, (max-width: 600px)".to_string()),
+                ("lg".to_string(), "(width >= 1024px)".to_string()),
+            ]
+        );
+        assert!(parse_breakpoints("(min-width: 768px)").is_err());
+        assert!(parse_breakpoints("=(min-width: 768px)").is_err());
+    }
+
+    #[test]
+    fn test_patterns_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let list = temp_dir.path().join("patterns.txt");
+        fs::write(
+            &list,
+            "# components\nsrc/ui/*.css\n\n  src/legacy/**/*.css  \n",
+        )
+        .unwrap();
+        let input = format!("base.css, @{}", list.to_string_lossy());
+
+        assert_eq!(
+            read_patterns(&input, None).unwrap(),
+            vec!["base.css", "src/ui/*.css", "src/legacy/**/*.css"]
+        );
+        assert!(matches!(
+            read_patterns("@missing-patterns.txt", None),
+            Err(GrimoireCssError::InvalidPath(_))
+        ));
+
+        // HTML input takes pattern lists too
+        fs::write(
+            temp_dir.path().join("index.html"),
+            "<p class=\"lead\" style=\"color: red\">Hi</p>",
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("pages.txt"), "index.html\n").unwrap();
+        let dir = temp_dir.path().to_string_lossy();
+        run_app(args(&[
+            "--cwd",
+            &dir,
+            "-p",
+            "@pages.txt",
+            "--html",
+            "-o",
+            "out.json",
+            "-q",
+        ]))
+        .unwrap();
+        let output = fs::read_to_string(temp_dir.path().join("out.json")).unwrap();
+        let extraction: HtmlExtraction = serde_json::from_str(&output).unwrap();
+        assert_eq!(extraction.classes, vec!["lead"]);
+    }
+
+    #[test]
+    fn test_append_merges_into_existing_output() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let first = temp_dir.path().join("first.css");
+        let second = temp_dir.path().join("second.css");
+        let output = temp_dir.path().join("out.json");
+        fs::write(&first, ".a { color: red } .b { margin: 0 }").unwrap();
+        fs::write(&second, ".a { padding: 0 } .c { top: 0 }").unwrap();
+        let output_arg = output.to_string_lossy();
+
+        for input in [&first, &second] {
+            let input = input.to_string_lossy();
+            run_app(args(&["-p", &input, "-o", &output_arg, "--append", "-q"])).unwrap();
+        }
+
+        let merged =
+            Transmuted::deserialize_as(&fs::read_to_string(&output).unwrap(), OutputFormat::Json)
+                .unwrap();
+        let scrolls: Vec<(&str, &[String])> = merged
+            .scrolls
+            .iter()
+            .map(|scroll| (scroll.name.as_str(), scroll.spells.as_slice()))
+            .collect();
+        assert_eq!(
+            scrolls,
+            vec![
+                ("a", &["color=red".to_string(), "padding=0".to_string()][..]),
+                ("b", &["margin=0".to_string()][..]),
+                ("c", &["top=0".to_string()][..]),
+            ]
+        );
+
+        fs::write(
+            &output,
+            r#"{ "version": 1, "generator": "x", "scrolls": [] }"#,
+        )
+        .unwrap();
+        let input = first.to_string_lossy();
+        assert!(matches!(
+            run_app(args(&["-p", &input, "-o", &output_arg, "--append", "-q"])),
+            Err(GrimoireCssError::InvalidInput(msg)) if msg.contains("schema version 1")
+        ));
+    }
+
+    #[test]
+    fn test_cwd() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().to_string_lossy().into_owned();
+        fs::create_dir(temp_dir.path().join("styles")).unwrap();
+        fs::write(
+            temp_dir.path().join("styles/a.css"),
+            "@import \"b.css\"; .a { color: red; }",
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("styles/b.css"), ".b { margin: 0; }").unwrap();
+        fs::write(temp_dir.path().join("patterns.txt"), "styles/a.css\n").unwrap();
+
+        run_app(args(&[
+            "--cwd",
+            &dir,
+            "-p",
+            "@patterns.txt",
+            "--follow-imports",
+            "-o",
+            "out/transmuted.json",
+            "--cache",
+            "-q",
+        ]))
+        .unwrap();
+        let output = fs::read_to_string(temp_dir.path().join("out/transmuted.json")).unwrap();
+        let transmuted: Transmuted = serde_json::from_str(&output).unwrap();
+        assert_eq!(transmuted.scrolls.len(), 2);
+        assert!(temp_dir.path().join(DEFAULT_CACHE_PATH).is_file());
+
+        run_app(args(&["--cwd", &dir, "-p", "styles/*.css", "-q"])).unwrap();
+        assert!(temp_dir.path().join("grimoire/transmuted.json").is_file());
+
+        // An option value that reads `--cwd` is no directory to resolve against
+        let config = parse_args(args(&["-p", "a.css", "--output-dir", "--cwd"])).unwrap();
+        assert_eq!(config.output_dir.as_deref(), Some("--cwd"));
+        assert!(config.limits.base_dir.is_none());
+        assert!(matches!(
+            parse_args(args(&["-p", "a.css", "--cwd"])),
+            Err(GrimoireCssError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["-p", "a.css", "--cwd", "missing-dir"])),
+            Err(GrimoireCssError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn test_output_dir() {
+        let cwd = env::current_dir().unwrap();
+        let output_file = |extra: &[&str]| {
+            let mut list = vec!["-p", "a.css"];
+            list.extend(extra);
+            output_file(&parse_args(args(&list)).unwrap()).unwrap()
+        };
+
+        assert_eq!(output_file(&[]), cwd.join("grimoire/transmuted.json"));
+        assert_eq!(
+            output_file(&["--output-dir", "grimoire-out"]),
+            cwd.join("grimoire-out/transmuted.json")
+        );
+        assert_eq!(
+            output_file(&["--output-dir", "grimoire-out", "-f", "yaml"]),
+            cwd.join("grimoire-out/transmuted.yaml")
+        );
+        assert_eq!(output_file(&["-o", "out.json"]), PathBuf::from("out.json"));
+        assert_eq!(
+            output_file(&["--output-dir", "grimoire-out", "-o", "out.json"]),
+            PathBuf::from("out.json")
+        );
+
+        assert!(parse_args(args(&["-c", ".a {}", "--output-dir", "out"])).is_err());
+    }
+
+    #[test]
+    fn test_stdout_is_one_json_line() {
+        let config = parse_args(args(&["-c", ".a { color: red; }", "-m"])).unwrap();
+        let transmuted = transmute_content_to_struct(
+            &config.input,
+            config.include_oneliner,
+            config.include_source,
+            &config.parse,
+        )
+        .unwrap();
+
+        let mut stdout = Vec::new();
+        write_stdout(&config, &transmuted, &mut stdout).unwrap();
+        let stdout = String::from_utf8(stdout).unwrap();
+
+        assert!(stdout.ends_with('\n'));
+        assert_eq!(stdout.lines().count(), 1);
+        let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+        assert!(json.is_object());
+        assert_eq!(json["scrolls"][0]["name"], "a");
+    }
+
+    #[test]
+    fn test_failed_write_keeps_existing_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transmuted.json");
+        let path_str = path.to_string_lossy();
+        fs::write(&path, "{\"version\": 1}").unwrap();
+        let config = parse_args(args(&["-c", ".a { color: red; }", "-q"])).unwrap();
+
+        let result = write_with(&config, &path_str, |file| {
+            file.write_all(b"{\"trunc").map_err(GrimoireCssError::Io)?;
+            Err(GrimoireCssError::InvalidInput("interrupted".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"version\": 1}");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        write_to_file(&config, &path_str, "{}").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}